@@ -0,0 +1,225 @@
+//! Orientation from gyro and accelerometer.
+//!
+//! Integrating the gyro alone gives a smooth orientation that slowly drifts away with the gyro
+//! offset. The accelerometer knows where down is, but only on average, every bump and turn
+//! adds to gravity. The filters here integrate the gyro and pull the result towards the
+//! accelerometer's idea of down a little on every sample. Heading has nothing to be pulled
+//! towards, it drifts with the gyro.
+//!
+//! Orientations rotate sensor axes into a world frame with Z pointing up, so at rest with the
+//! sensor's Z up the orientation is [`Quaternion::IDENTITY`].
+
+use core::ops::Mul;
+
+/// Unit quaternion describing a rotation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Quaternion {
+	pub w: f64,
+	pub x: f64,
+	pub y: f64,
+	pub z: f64,
+}
+
+impl Quaternion {
+	pub const IDENTITY: Self = Self { w: 1.0, x: 0.0, y: 0.0, z: 0.0 };
+
+	/// Rotation by `angle` radians around `axis`, which doesn't need to be normalized
+	pub fn from_axis_angle(axis: [f64; 3], angle: f64) -> Self {
+		let [x, y, z] = normalized(axis).unwrap_or([1.0, 0.0, 0.0]);
+		let (sin, cos) = (libm::sin(angle / 2.0), libm::cos(angle / 2.0));
+		Self { w: cos, x: x * sin, y: y * sin, z: z * sin }
+	}
+
+	/// The orientation at rest that reads `accel` as gravity, with an arbitrary heading
+	pub fn from_gravity(accel: [f64; 3]) -> Self {
+		let Some([x, y, z]) = normalized(accel) else { return Self::IDENTITY };
+		// Shortest rotation taking the measured up onto world Z, half-way vector trick
+		match 1.0 + z {
+			w if w < 1e-9 => Self { w: 0.0, x: 1.0, y: 0.0, z: 0.0 },
+			w => Self { w, x: y, y: -x, z: 0.0 }.normalized(),
+		}
+	}
+
+	pub fn conjugate(self) -> Self {
+		Self { w: self.w, x: -self.x, y: -self.y, z: -self.z }
+	}
+
+	pub fn normalized(self) -> Self {
+		let norm = libm::sqrt(self.w * self.w + self.x * self.x + self.y * self.y + self.z * self.z);
+		match norm {
+			0.0 => Self::IDENTITY,
+			norm => Self { w: self.w / norm, x: self.x / norm, y: self.y / norm, z: self.z / norm },
+		}
+	}
+
+	/// `v` turned by this rotation
+	pub fn rotate(self, [x, y, z]: [f64; 3]) -> [f64; 3] {
+		let v = self * Self { w: 0.0, x, y, z } * self.conjugate();
+		[v.x, v.y, v.z]
+	}
+
+	/// Angle of the rotation from `self` to `other` in radians, 0 to π
+	pub fn angle_to(self, other: Self) -> f64 {
+		let dot = self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z;
+		2.0 * libm::acos(libm::fabs(dot).min(1.0))
+	}
+
+	/// Roll, pitch and yaw in radians. The orientation is reached by turning yaw around Z,
+	/// then pitch around the turned Y, then roll around the turned X.
+	pub fn euler(self) -> [f64; 3] {
+		let Self { w, x, y, z } = self;
+		[
+			libm::atan2(2.0 * (w * x + y * z), 1.0 - 2.0 * (x * x + y * y)),
+			libm::asin((2.0 * (w * y - z * x)).clamp(-1.0, 1.0)),
+			libm::atan2(2.0 * (w * z + x * y), 1.0 - 2.0 * (y * y + z * z)),
+		]
+	}
+
+	/// Turns by `gyro` rad/s, in sensor axes, for `dt` seconds
+	fn integrate(self, gyro: [f64; 3], dt: f64) -> Self {
+		let [x, y, z] = gyro;
+		let rate = self * Self { w: 0.0, x, y, z };
+		Self {
+			w: self.w + rate.w * dt / 2.0,
+			x: self.x + rate.x * dt / 2.0,
+			y: self.y + rate.y * dt / 2.0,
+			z: self.z + rate.z * dt / 2.0,
+		}
+		.normalized()
+	}
+
+	/// Where the sensor sees world Z, the direction of the accelerometer reading at rest
+	fn up(self) -> [f64; 3] {
+		self.conjugate().rotate([0.0, 0.0, 1.0])
+	}
+}
+
+impl Mul for Quaternion {
+	type Output = Self;
+
+	fn mul(self, o: Self) -> Self {
+		Self {
+			w: self.w * o.w - self.x * o.x - self.y * o.y - self.z * o.z,
+			x: self.w * o.x + self.x * o.w + self.y * o.z - self.z * o.y,
+			y: self.w * o.y - self.x * o.z + self.y * o.w + self.z * o.x,
+			z: self.w * o.z + self.x * o.y - self.y * o.x + self.z * o.w,
+		}
+	}
+}
+
+fn normalized(v: [f64; 3]) -> Option<[f64; 3]> {
+	let norm = libm::sqrt(v.iter().map(|c| c * c).sum());
+	(norm > 0.0).then(|| v.map(|c| c / norm))
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+	[a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+/// A filter tracking orientation sample by sample
+pub trait Fusion {
+	/// Takes one sample, gyro in rad/s and accel in any unit, `dt` seconds after the last one.
+	/// An all zero accel, as in free fall, leaves the gyro on its own.
+	fn update(&mut self, gyro: [f64; 3], accel: [f64; 3], dt: f64);
+
+	fn orientation(&self) -> Quaternion;
+}
+
+/// Madgwick's gradient descent filter: every sample takes a step of `beta` rad/s towards
+/// the orientation that explains the accelerometer reading
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Madgwick {
+	q: Quaternion,
+	beta: f64,
+}
+
+impl Madgwick {
+	/// Madgwick's suggestion for MEMS gyros, settles within seconds
+	pub const DEFAULT_BETA: f64 = 0.1;
+
+	pub const fn new(beta: f64, start: Quaternion) -> Self {
+		Self { q: start, beta }
+	}
+}
+
+impl Fusion for Madgwick {
+	fn update(&mut self, gyro: [f64; 3], accel: [f64; 3], dt: f64) {
+		let [x, y, z] = gyro;
+		let mut rate = self.q * Quaternion { w: 0.0, x, y, z };
+		rate = Quaternion { w: rate.w / 2.0, x: rate.x / 2.0, y: rate.y / 2.0, z: rate.z / 2.0 };
+
+		if let Some(accel) = normalized(accel) {
+			let Quaternion { w, x, y, z } = self.q;
+			// Gradient of the squared difference between expected and measured down
+			let up = self.q.up();
+			let f = [up[0] - accel[0], up[1] - accel[1], up[2] - accel[2]];
+			let step = Quaternion {
+				w: -2.0 * y * f[0] + 2.0 * x * f[1],
+				x: 2.0 * z * f[0] + 2.0 * w * f[1] - 4.0 * x * f[2],
+				y: -2.0 * w * f[0] + 2.0 * z * f[1] - 4.0 * y * f[2],
+				z: 2.0 * x * f[0] + 2.0 * y * f[1],
+			};
+			if step != (Quaternion { w: 0.0, x: 0.0, y: 0.0, z: 0.0 }) {
+				let step = step.normalized();
+				rate.w -= self.beta * step.w;
+				rate.x -= self.beta * step.x;
+				rate.y -= self.beta * step.y;
+				rate.z -= self.beta * step.z;
+			}
+		}
+
+		self.q = Quaternion {
+			w: self.q.w + rate.w * dt,
+			x: self.q.x + rate.x * dt,
+			y: self.q.y + rate.y * dt,
+			z: self.q.z + rate.z * dt,
+		}
+		.normalized();
+	}
+
+	fn orientation(&self) -> Quaternion {
+		self.q
+	}
+}
+
+/// Mahony's complementary filter: the angle between expected and measured down is fed back
+/// into the gyro rate, proportionally and, to learn the gyro offset, integrated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mahony {
+	q: Quaternion,
+	kp: f64,
+	ki: f64,
+	integral: [f64; 3],
+}
+
+impl Mahony {
+	pub const DEFAULT_KP: f64 = 1.0;
+	pub const DEFAULT_KI: f64 = 0.05;
+
+	pub const fn new(kp: f64, ki: f64, start: Quaternion) -> Self {
+		Self { q: start, kp, ki, integral: [0.0; 3] }
+	}
+
+	/// Gyro offset learned so far, in rad/s, to be subtracted
+	pub fn gyro_bias(&self) -> [f64; 3] {
+		self.integral.map(|i| -i)
+	}
+}
+
+impl Fusion for Mahony {
+	fn update(&mut self, gyro: [f64; 3], accel: [f64; 3], dt: f64) {
+		let mut gyro = gyro;
+		if let Some(accel) = normalized(accel) {
+			let error = cross(accel, self.q.up());
+			for axis in 0..3 {
+				self.integral[axis] += self.ki * error[axis] * dt;
+				gyro[axis] += self.kp * error[axis] + self.integral[axis];
+			}
+		}
+		self.q = self.q.integrate(gyro, dt);
+	}
+
+	fn orientation(&self) -> Quaternion {
+		self.q
+	}
+}
@@ -1,18 +1,829 @@
-use core::num::NonZeroU64;
-use zerocopy::{FromBytes, IntoBytes};
+//! Binary gyro log container.
+//!
+//! A log is a [`BinGyroHeader`] followed by any number of framed sample blocks. Every block
+//! starts with a [`BlockHeader`] and carries `payload_len` bytes of raw IMU data, which is a
+//! sequence of samples as they come out of the IMU FIFO: `gx, gy, gz, ax, ay, az`, each an
+//! `i16`, optionally followed by `mx, my, mz`, the [`TIMESTAMP_LEN`] byte hardware timestamp
+//! data set and the temperature, see [`SampleLayout`].
+//!
+//! With [`Compression::Delta`] in the header every payload is packed with [`compress::pack`]
+//! instead, `sample_count` and the CRC then refer to the packed samples.
+//!
+//! Blocks are numbered consecutively and protected by a CRC-32, so dropped or damaged blocks
+//! show up as a gap in the sequence instead of silently shifting all later samples.
+//!
+//! Long recordings are split over several files. Each segment starts with its own header
+//! carrying the session ID of the recording and its segment number, block numbers and
+//! timestamps run on across segments, so joining them gives one continuous log.
+//!
+//! All multi-byte fields are little endian, so the files read the same on the firmware and on
+//! the host.
 
+use crate::gyro_format::compress;
+use crate::gyro_format::orientation::Orientation;
+use crate::imu::calibration::Calibration;
+use crate::imu::{ImuSample, SampleLayout, lsm6ds3};
+use core::num::{NonZeroU32, NonZeroU64};
+use zerocopy::little_endian::{F32, F64, U16, U32, U64};
+use zerocopy::{FromBytes, Immutable, IntoBytes, KnownLayout, Unaligned};
+
+/// First bytes of every binary log
+pub const MAGIC: [u8; 8] = *b"TRCMGYRO";
+/// Bumped on every incompatible change to the header or block layout
+pub const FORMAT_VERSION: u16 = 11;
+
+/// Start of every block, used to re-synchronise and to catch framing bugs early
+pub const BLOCK_SYNC: [u8; 2] = [0xB1, 0x0C];
+
+/// Raw sample: gyro[3] + accel[3] = 6 * i16 = 12 bytes
+pub const SAMPLE_LEN: usize = ImuSample::LEN;
+/// LSM6DS3 timestamp data set: `TS[15:8], TS[23:16], -, TS[7:0], STEP[7:0], STEP[15:8]`
+pub const TIMESTAMP_LEN: usize = lsm6ds3::TIMESTAMP_LEN;
+
+/// Sensor settings the samples in a log were recorded with
 #[repr(C)]
-#[derive(IntoBytes, FromBytes, Debug, PartialEq)]
+#[derive(IntoBytes, FromBytes, KnownLayout, Immutable, Unaligned, Debug, PartialEq, Clone, Copy)]
+pub struct SensorConfig {
+	odr_millihertz: U32,
+	gyro_range_dps: U16,
+	accel_range_g: U16,
+	gyro_dps_per_lsb: F64,
+	accel_g_per_lsb: F64,
+	hw_timestamp_ns: U32, // LSB of the hardware timestamp stored with every sample, 0 if none
+	temp_c_per_lsb: F64, // Of the temperature stored with every sample, 0 if none
+	temp_zero_c: F64, // Temperature at a raw value of 0
+	mag_ut_per_lsb: F64, // Of the magnetometer stored with every sample, 0 if none
+}
+
+impl SensorConfig {
+	pub const fn new(
+		odr_millihertz: u32,
+		gyro_range_dps: u16,
+		gyro_dps_per_lsb: f64,
+		accel_range_g: u16,
+		accel_g_per_lsb: f64,
+	) -> Self {
+		Self {
+			odr_millihertz: U32::new(odr_millihertz),
+			gyro_range_dps: U16::new(gyro_range_dps),
+			accel_range_g: U16::new(accel_range_g),
+			gyro_dps_per_lsb: F64::new(gyro_dps_per_lsb),
+			accel_g_per_lsb: F64::new(accel_g_per_lsb),
+			hw_timestamp_ns: U32::ZERO,
+			temp_c_per_lsb: F64::ZERO,
+			temp_zero_c: F64::ZERO,
+			mag_ut_per_lsb: F64::ZERO,
+		}
+	}
+
+	/// Every sample is followed by a hardware timestamp counting in `lsb_ns` steps
+	pub const fn with_hw_timestamps(mut self, lsb_ns: u32) -> Self {
+		self.hw_timestamp_ns = U32::new(lsb_ns);
+		self
+	}
+
+	/// Every sample carries the die temperature, `c_per_lsb` 0 for none
+	pub const fn with_temperature(mut self, c_per_lsb: f64, zero_c: f64) -> Self {
+		self.temp_c_per_lsb = F64::new(c_per_lsb);
+		self.temp_zero_c = F64::new(zero_c);
+		self
+	}
+
+	/// Every sample carries a magnetometer reading, `ut_per_lsb` 0 for none
+	pub const fn with_mag(mut self, ut_per_lsb: f64) -> Self {
+		self.mag_ut_per_lsb = F64::new(ut_per_lsb);
+		self
+	}
+
+	/// Output data rate in mHz, so 1.66kHz is 1_660_000
+	pub fn odr_millihertz(&self) -> u32 {
+		self.odr_millihertz.get()
+	}
+
+	pub fn gyro_range_dps(&self) -> u16 {
+		self.gyro_range_dps.get()
+	}
+
+	pub fn accel_range_g(&self) -> u16 {
+		self.accel_range_g.get()
+	}
+
+	/// Gyro sensitivity, raw value times this is °/s
+	pub fn gyro_dps_per_lsb(&self) -> f64 {
+		self.gyro_dps_per_lsb.get()
+	}
+
+	/// Accelerometer sensitivity, raw value times this is g
+	pub fn accel_g_per_lsb(&self) -> f64 {
+		self.accel_g_per_lsb.get()
+	}
+
+	/// Gyro in °/s and accel in g, the units the Gyroflow text header declares
+	pub fn scale(&self, sample: ImuSample) -> [f64; 6] {
+		let gyro = self.gyro_dps_per_lsb();
+		let accel = self.accel_g_per_lsb();
+		let [gx, gy, gz] = sample.gyro.map(|w| w as f64 * gyro);
+		let [ax, ay, az] = sample.accel.map(|w| w as f64 * accel);
+		[gx, gy, gz, ax, ay, az]
+	}
+
+	/// Temperature sensitivity in °C per LSB and the temperature at 0, if it's recorded
+	pub fn temperature_scale(&self) -> Option<(f64, f64)> {
+		let c_per_lsb = self.temp_c_per_lsb.get();
+		(c_per_lsb != 0.0).then(|| (c_per_lsb, self.temp_zero_c.get()))
+	}
+
+	/// Magnetometer sensitivity in µT per LSB, if it's recorded
+	pub fn mag_ut_per_lsb(&self) -> Option<f64> {
+		let ut_per_lsb = self.mag_ut_per_lsb.get();
+		(ut_per_lsb != 0.0).then_some(ut_per_lsb)
+	}
+
+	/// Temperature of a sample in °C
+	pub fn temperature_c(&self, sample: &ImuSample) -> Option<f64> {
+		let (c_per_lsb, zero_c) = self.temperature_scale()?;
+		Some(sample.temperature? as f64 * c_per_lsb + zero_c)
+	}
+
+	/// Magnetometer reading of a sample in µT
+	pub fn mag_ut(&self, sample: &ImuSample) -> Option<[f64; 3]> {
+		let ut_per_lsb = self.mag_ut_per_lsb()?;
+		Some(sample.mag?.map(|w| w as f64 * ut_per_lsb))
+	}
+
+	pub fn hw_timestamp_ns(&self) -> Option<NonZeroU32> {
+		NonZeroU32::new(self.hw_timestamp_ns.get())
+	}
+
+	/// What every sample in the block payload holds
+	pub const fn layout(&self) -> SampleLayout {
+		SampleLayout {
+			mag: self.mag_ut_per_lsb.get() != 0.0,
+			timestamp: self.hw_timestamp_ns.get() != 0,
+			temperature: self.temp_c_per_lsb.get() != 0.0,
+		}
+	}
+
+	/// Bytes per sample in the block payload
+	pub const fn sample_len(&self) -> usize {
+		self.layout().sample_len()
+	}
+}
+
+#[repr(C)]
+#[derive(IntoBytes, FromBytes, KnownLayout, Immutable, Unaligned, Debug, PartialEq, Clone)]
 pub struct BinGyroHeader {
-	gps_start_ts: Option<NonZeroU64>,
-	timescale: u64, // Timestamp resolution in seconds
+	magic: [u8; 8],
+	version: U16,
+	header_len: U16, // Size of the header in bytes, allows appending fields later on
+	sensor: SensorConfig,
+	timescale: U64, // Timestamp ticks per second
+	gps_start_ts: U64, // Unix time in µs at the start of recording, 0 if unknown
+	start_ticks: U64, // Block timestamp that gps_start_ts refers to
+	orientation: [u8; 3], // Gyroflow axis string of how the IMU is mounted in the camera
+	compression: u8, // How block payloads are stored, see Compression
+	gyro_bias: [F32; 3], // Gyro zero-rate offset in LSB, measured while standing still
+	accel_offset: [F32; 3], // Accel offset along gravity in LSB, from the same samples
+	calibration_samples: U32, // Samples both offsets were averaged over, 0 if not calibrated
+	session_id: U32, // Shared by all segments of a recording
+	segment: U16, // Position in the recording, non-zero marks a continuation
+	continues: u8, // 1 if the recording goes on in the next segment
+}
+
+/// How the samples in block payloads are stored
+#[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Compression {
+	/// As they come from the IMU
+	#[default]
+	None = 0,
+	/// Packed with [`compress::pack`]
+	Delta = 1,
+}
+
+impl Compression {
+	pub const fn from_u8(value: u8) -> Option<Self> {
+		match value {
+			0 => Some(Self::None),
+			1 => Some(Self::Delta),
+			_ => None,
+		}
+	}
 }
 
 impl BinGyroHeader {
-	pub const fn new() -> Self {
+	pub const LEN: usize = size_of::<Self>();
+
+	pub const fn new(sensor: SensorConfig) -> Self {
 		Self {
-			gps_start_ts: None,
-			timescale: 1_000_000,
+			magic: MAGIC,
+			version: U16::new(FORMAT_VERSION),
+			header_len: U16::new(Self::LEN as u16),
+			sensor,
+			timescale: U64::new(1_000_000),
+			gps_start_ts: U64::ZERO,
+			start_ticks: U64::ZERO,
+			orientation: *Orientation::IDENTITY.as_bytes(),
+			compression: Compression::None as u8,
+			gyro_bias: [F32::ZERO; 3],
+			accel_offset: [F32::ZERO; 3],
+			calibration_samples: U32::ZERO,
+			session_id: U32::ZERO,
+			segment: U16::ZERO,
+			continues: 0,
 		}
 	}
-}
\ No newline at end of file
+
+	/// How block payloads are stored, has to match how they are framed
+	pub const fn with_compression(mut self, compression: Compression) -> Self {
+		self.compression = compression as u8;
+		self
+	}
+
+	/// How the IMU is mounted relative to the camera
+	pub const fn with_orientation(mut self, orientation: Orientation) -> Self {
+		self.orientation = *orientation.as_bytes();
+		self
+	}
+
+	/// Anchors the log to UTC: at block timestamp `ticks` it was `unix_micros`
+	pub fn with_gps_start(mut self, unix_micros: u64, ticks: u64) -> Self {
+		self.gps_start_ts = U64::new(unix_micros);
+		self.start_ticks = U64::new(ticks);
+		self
+	}
+
+	/// Offsets measured at the start of recording, usually only known once it ended
+	pub fn with_calibration(mut self, calibration: &Calibration) -> Self {
+		self.gyro_bias = calibration.gyro_bias.map(F32::new);
+		self.accel_offset = calibration.accel_offset.map(F32::new);
+		self.calibration_samples = U32::new(calibration.samples);
+		self
+	}
+
+	/// Segment `segment` of the recording `session_id`, the first one is 0
+	pub const fn with_segment(mut self, session_id: u32, segment: u16) -> Self {
+		self.session_id = U32::new(session_id);
+		self.segment = U16::new(segment);
+		self
+	}
+
+	/// Marks that the recording goes on in the next segment, known once this one is closed
+	pub const fn with_next_segment(mut self) -> Self {
+		self.continues = 1;
+		self
+	}
+
+	pub fn version(&self) -> u16 {
+		self.version.get()
+	}
+
+	pub fn sensor(&self) -> &SensorConfig {
+		&self.sensor
+	}
+
+	/// How the IMU is mounted, the reader refuses headers that don't hold a valid one
+	pub fn orientation(&self) -> Orientation {
+		Orientation::parse(&self.orientation).unwrap_or_default()
+	}
+
+	/// How block payloads are stored, the reader refuses headers with an unknown one
+	pub fn compression(&self) -> Compression {
+		Compression::from_u8(self.compression).unwrap_or_default()
+	}
+
+	pub fn calibration(&self) -> Option<Calibration> {
+		(self.calibration_samples.get() > 0).then(|| Calibration {
+			gyro_bias: self.gyro_bias.map(|b| b.get()),
+			accel_offset: self.accel_offset.map(|o| o.get()),
+			samples: self.calibration_samples.get(),
+		})
+	}
+
+	pub fn session_id(&self) -> u32 {
+		self.session_id.get()
+	}
+
+	pub fn segment(&self) -> u16 {
+		self.segment.get()
+	}
+
+	/// Whether this file picks up where an earlier segment left off
+	pub fn is_continuation(&self) -> bool {
+		self.segment() > 0
+	}
+
+	/// Whether the recording goes on in the next segment
+	pub fn continues(&self) -> bool {
+		self.continues != 0
+	}
+
+	/// Whether `other` is a segment of the same recording, which takes the same session ID,
+	/// sensor, clock and start time
+	pub fn same_session(&self, other: &Self) -> bool {
+		self.session_id == other.session_id
+			&& self.sensor == other.sensor
+			&& self.timescale == other.timescale
+			&& self.gps_start_ts == other.gps_start_ts
+			&& self.start_ticks == other.start_ticks
+			&& self.orientation == other.orientation
+			&& self.compression == other.compression
+	}
+
+	pub fn timescale(&self) -> u64 {
+		self.timescale.get()
+	}
+
+	pub fn gps_start_ts(&self) -> Option<NonZeroU64> {
+		NonZeroU64::new(self.gps_start_ts.get())
+	}
+
+	/// Unix time in µs of a block timestamp, if the log start time is known
+	pub fn unix_micros_at(&self, ticks: f64) -> Option<f64> {
+		let start = self.gps_start_ts()?.get() as f64;
+		Some(start + (ticks - self.start_ticks.get() as f64) * 1e6 / self.timescale() as f64)
+	}
+
+	/// Nominal time between two samples in timescale ticks
+	pub fn ticks_per_sample(&self) -> f64 {
+		self.timescale() as f64 * 1000.0 / self.sensor.odr_millihertz() as f64
+	}
+}
+
+#[repr(C)]
+#[derive(IntoBytes, FromBytes, KnownLayout, Immutable, Unaligned, Debug, PartialEq, Clone)]
+pub struct BlockHeader {
+	sync: [u8; 2],
+	sample_count: U16,
+	payload_len: U16,
+	sequence: U32, // Incremented for every block, including ones that got dropped
+	timestamp: U64, // When the batch was taken from the IMU, in timescale ticks
+	lost_samples: U32, // Non-zero marks a FIFO overrun, the IMU dropped about this many samples
+	crc: U32, // CRC-32 over all preceding header fields and the payload
+}
+
+impl BlockHeader {
+	pub const LEN: usize = size_of::<Self>();
+	const CRC_OFFSET: usize = Self::LEN - size_of::<U32>();
+
+	pub fn is_valid(&self, sample_len: usize, compression: Compression) -> bool {
+		let count = self.sample_count() as usize;
+		self.sync == BLOCK_SYNC
+			&& match compression {
+				Compression::None => count == self.payload_len() / sample_len,
+				Compression::Delta => compress::is_plausible(self.payload_len(), sample_len, count),
+			}
+	}
+
+	/// Whether `payload` is what this header was created for
+	pub fn matches(&self, payload: &[u8]) -> bool {
+		payload.len() == self.payload_len() && self.crc.get() == self.compute_crc(payload)
+	}
+
+	pub fn sample_count(&self) -> u16 {
+		self.sample_count.get()
+	}
+
+	pub fn payload_len(&self) -> usize {
+		self.payload_len.get() as usize
+	}
+
+	pub fn sequence(&self) -> u32 {
+		self.sequence.get()
+	}
+
+	pub fn timestamp(&self) -> u64 {
+		self.timestamp.get()
+	}
+
+	/// Estimated samples lost to a FIFO overrun detected at [`Self::timestamp`]
+	pub fn lost_samples(&self) -> u32 {
+		self.lost_samples.get()
+	}
+
+	fn compute_crc(&self, payload: &[u8]) -> u32 {
+		let mut crc = Crc32::new();
+		crc.update(&self.as_bytes()[..Self::CRC_OFFSET]);
+		crc.update(payload);
+		crc.finish()
+	}
+}
+
+/// Frames raw FIFO chunks into blocks, usable without allocation on the firmware.
+///
+/// Write the returned [`BlockHeader`] followed by the chunk itself, or use [`Self::pack`]
+/// to store it compressed.
+pub struct BlockEncoder {
+	sequence: u32,
+	sample_len: usize,
+}
+
+impl BlockEncoder {
+	/// Largest payload a single block can carry
+	pub const MAX_PAYLOAD: usize = u16::MAX as usize;
+
+	pub const fn new(sensor: &SensorConfig) -> Self {
+		Self {
+			sequence: 0,
+			sample_len: sensor.sample_len(),
+		}
+	}
+
+	pub fn frame(&mut self, timestamp: u64, payload: &[u8]) -> BlockHeader {
+		self.block(timestamp, payload.len() / self.sample_len, payload)
+	}
+
+	/// Packs `samples` into `buf` for a log with [`Compression::Delta`].
+	///
+	/// Write the returned header followed by the returned packed payload. `buf` needs room for
+	/// [`compress::max_packed_len`] of the samples.
+	pub fn pack<'a>(&mut self, timestamp: u64, samples: &[u8], buf: &'a mut [u8]) -> (BlockHeader, &'a [u8]) {
+		let len = compress::pack(samples, self.sample_len, buf).expect("Packing buffer too small");
+		(self.block(timestamp, samples.len() / self.sample_len, &buf[..len]), &buf[..len])
+	}
+
+	fn block(&mut self, timestamp: u64, sample_count: usize, payload: &[u8]) -> BlockHeader {
+		assert!(payload.len() <= Self::MAX_PAYLOAD, "Block payload too large");
+		let mut header = BlockHeader {
+			sync: BLOCK_SYNC,
+			sample_count: U16::new(sample_count as u16),
+			payload_len: U16::new(payload.len() as u16),
+			sequence: U32::new(self.sequence),
+			timestamp: U64::new(timestamp),
+			lost_samples: U32::ZERO,
+			crc: U32::ZERO,
+		};
+		header.crc = U32::new(header.compute_crc(payload));
+		self.sequence = self.sequence.wrapping_add(1);
+		header
+	}
+
+	/// An empty block recording that the FIFO overran at `timestamp` and had to be reset.
+	///
+	/// Write it on its own, without payload.
+	pub fn overrun(&mut self, timestamp: u64, lost_samples: u32) -> BlockHeader {
+		let mut header = self.frame(timestamp, &[]);
+		header.lost_samples = U32::new(lost_samples.max(1));
+		header.crc = U32::new(header.compute_crc(&[]));
+		header
+	}
+
+	/// Amount of blocks framed so far
+	pub fn blocks(&self) -> u32 {
+		self.sequence
+	}
+}
+
+/// Data lost between two consecutive blocks that made it into the log
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Gap {
+	pub blocks: u32,
+	/// Estimated from the block timestamps
+	pub samples: u64,
+}
+
+/// Watches the block sequence for blocks that were dropped or skipped as corrupt
+#[derive(Default)]
+pub struct GapTracker {
+	last: Option<(u32, u64)>,
+}
+
+impl GapTracker {
+	pub const fn new() -> Self {
+		Self { last: None }
+	}
+
+	/// Feed blocks in file order, returns what went missing right before `header`
+	pub fn check(&mut self, header: &BlockHeader, ticks_per_sample: f64) -> Option<Gap> {
+		let last = self.last.replace((header.sequence(), header.timestamp()));
+		let (last_seq, last_ts) = last?;
+
+		let blocks = header.sequence().wrapping_sub(last_seq).wrapping_sub(1);
+		if blocks == 0 {
+			return None;
+		}
+		// A block holds the samples captured since the previous one was taken
+		let elapsed = header.timestamp().saturating_sub(last_ts) as f64 / ticks_per_sample;
+		let samples = ((elapsed + 0.5) as u64).saturating_sub(header.sample_count() as u64);
+		Some(Gap { blocks, samples })
+	}
+}
+
+/// CRC-32 (IEEE 802.3, as used by zip and PNG)
+#[derive(Clone)]
+pub struct Crc32(u32);
+
+impl Crc32 {
+	const TABLE: [u32; 256] = {
+		let mut table = [0_u32; 256];
+		let mut i = 0;
+		while i < 256 {
+			let mut c = i as u32;
+			let mut bit = 0;
+			while bit < 8 {
+				c = if c & 1 == 1 { 0xEDB8_8320 ^ (c >> 1) } else { c >> 1 };
+				bit += 1;
+			}
+			table[i] = c;
+			i += 1;
+		}
+		table
+	};
+
+	pub const fn new() -> Self {
+		Self(0xFFFF_FFFF)
+	}
+
+	pub fn update(&mut self, data: &[u8]) {
+		for &b in data {
+			self.0 = Self::TABLE[((self.0 ^ b as u32) & 0xFF) as usize] ^ (self.0 >> 8);
+		}
+	}
+
+	pub fn finish(&self) -> u32 {
+		!self.0
+	}
+}
+
+impl Default for Crc32 {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+/// Whether `data` starts like a binary log
+pub fn is_binary(data: &[u8]) -> bool {
+	data.starts_with(&MAGIC)
+}
+
+#[cfg(feature = "std")]
+pub use reader::{Block, Error, Reader};
+
+#[cfg(feature = "std")]
+mod reader {
+	use super::*;
+	use std::io::{self, Read};
+	use std::fmt;
+	use std::vec::Vec;
+
+	#[derive(Debug)]
+	pub enum Error {
+		Io(io::Error),
+		BadMagic,
+		UnsupportedVersion(u16),
+		BadHeader,
+		/// Damaged block or garbage, `skipped` bytes were dropped until the next block
+		BadBlock { offset: u64, skipped: u64 },
+		Truncated { offset: u64 },
+	}
+
+	impl fmt::Display for Error {
+		fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+			match self {
+				Error::Io(e) => write!(f, "I/O error: {e}"),
+				Error::BadMagic => write!(f, "not a binary gyro log"),
+				Error::UnsupportedVersion(v) => write!(f, "unsupported format version {v}, expected {FORMAT_VERSION}"),
+				Error::BadHeader => write!(f, "malformed log header"),
+				Error::BadBlock { offset, skipped } => write!(f, "corrupt block at byte {offset}, skipped {skipped} bytes"),
+				Error::Truncated { offset } => write!(f, "file truncated at byte {offset}"),
+			}
+		}
+	}
+
+	impl std::error::Error for Error {
+		fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+			match self {
+				Error::Io(e) => Some(e),
+				_ => None,
+			}
+		}
+	}
+
+	impl From<io::Error> for Error {
+		fn from(e: io::Error) -> Self {
+			Error::Io(e)
+		}
+	}
+
+	#[derive(Debug, Clone, PartialEq)]
+	pub struct Block {
+		pub header: BlockHeader,
+		pub payload: Vec<u8>,
+		layout: SampleLayout,
+	}
+
+	impl Block {
+		/// Complete samples in the payload
+		pub fn samples(&self) -> impl Iterator<Item = ImuSample> + '_ {
+			self.payload.chunks_exact(self.layout.sample_len()).map(|s| self.layout.sample(s))
+		}
+
+		/// Raw 24 bit hardware timestamp of every sample, if they were recorded
+		pub fn hw_timestamps(&self) -> Option<impl Iterator<Item = u32> + '_> {
+			self.layout.timestamp.then(|| {
+				self.payload.chunks_exact(self.layout.sample_len()).map(|s| self.layout.timestamp(s).unwrap())
+			})
+		}
+	}
+
+	/// Streaming decoder for binary logs, yields one [`Block`] at a time.
+	///
+	/// Damaged blocks are reported as [`Error::BadBlock`] and skipped, reading continues with
+	/// the next intact block. I/O errors and truncation end the iteration. Nothing but zeros
+	/// after a block is preallocated space the recording didn't fill, the log ends there.
+	pub struct Reader<R> {
+		inner: R,
+		header: BinGyroHeader,
+		buf: Vec<u8>,
+		offset: u64, // File offset of buf[0]
+		done: bool,
+	}
+
+	impl<R: Read> Reader<R> {
+		const READ_CHUNK: usize = 8 * 1024;
+
+		pub fn new(mut inner: R) -> Result<Self, Error> {
+			let mut buf = [0_u8; BinGyroHeader::LEN];
+			let read = read_full(&mut inner, &mut buf)?;
+			if read < MAGIC.len() || buf[..MAGIC.len()] != MAGIC {
+				return Err(Error::BadMagic);
+			}
+			if read < buf.len() {
+				return Err(Error::Truncated { offset: read as u64 });
+			}
+
+			let header = BinGyroHeader::read_from_bytes(&buf).map_err(|_| Error::BadHeader)?;
+			if header.version() != FORMAT_VERSION {
+				return Err(Error::UnsupportedVersion(header.version()));
+			}
+
+			let header_len = header.header_len.get() as u64;
+			if header_len < BinGyroHeader::LEN as u64
+				|| Orientation::parse(&header.orientation).is_none()
+				|| Compression::from_u8(header.compression).is_none()
+			{
+				return Err(Error::BadHeader);
+			}
+			// Skip fields appended by newer writers
+			let extra = header_len - BinGyroHeader::LEN as u64;
+			if io::copy(&mut (&mut inner).take(extra), &mut io::sink())? != extra {
+				return Err(Error::Truncated { offset: BinGyroHeader::LEN as u64 });
+			}
+
+			Ok(Self {
+				inner,
+				header,
+				buf: Vec::new(),
+				offset: header_len,
+				done: false,
+			})
+		}
+
+		pub fn header(&self) -> &BinGyroHeader {
+			&self.header
+		}
+
+		fn next_block(&mut self) -> Result<Option<Block>, Error> {
+			if !self.fill(BlockHeader::LEN)? {
+				return match self.buf.len() {
+					0 => Ok(None),
+					_ if is_zero(&self.buf) => Ok(None),
+					n => Err(Error::Truncated { offset: self.offset + n as u64 }),
+				};
+			}
+
+			let start = self.offset;
+			if is_zero(&self.buf[..BlockHeader::LEN]) {
+				let skipped = self.skip_zeros()?;
+				return match self.buf.is_empty() {
+					true => Ok(None),
+					false => Err(Error::BadBlock { offset: start, skipped }),
+				};
+			}
+			let header = BlockHeader::read_from_bytes(&self.buf[..BlockHeader::LEN]).map_err(|_| Error::BadHeader)?;
+			let layout = self.header.sensor().layout();
+			let compression = self.header.compression();
+			if header.is_valid(layout.sample_len(), compression) {
+				let len = BlockHeader::LEN + header.payload_len();
+				if !self.fill(len)? {
+					return Err(Error::Truncated { offset: self.offset + self.buf.len() as u64 });
+				}
+				let payload = &self.buf[BlockHeader::LEN..len];
+				if header.matches(payload) {
+					let payload = match compression {
+						Compression::None => Ok(payload.to_vec()),
+						Compression::Delta => unpack(payload, &header, layout.sample_len()),
+					};
+					self.consume(len);
+					return match payload {
+						Ok(payload) => Ok(Some(Block { header, payload, layout })),
+						// The CRC matched, so the writer packed it wrong and it won't get better
+						Err(_) => Err(Error::BadBlock { offset: start, skipped: len as u64 }),
+					};
+				}
+			}
+
+			let skipped = self.resync()?;
+			Err(Error::BadBlock { offset: start, skipped })
+		}
+
+		/// Drops bytes until the next block sync, returns how many were dropped
+		fn resync(&mut self) -> Result<u64, Error> {
+			let start = self.offset;
+			self.consume(1);
+			loop {
+				if let Some(pos) = self.buf.windows(BLOCK_SYNC.len()).position(|w| w == BLOCK_SYNC) {
+					self.consume(pos);
+					break;
+				}
+				// The last byte could be the first half of a sync
+				let keep = usize::from(self.buf.last() == Some(&BLOCK_SYNC[0]));
+				self.consume(self.buf.len() - keep);
+				if !self.read_more()? {
+					self.consume(self.buf.len());
+					break;
+				}
+			}
+			Ok(self.offset - start)
+		}
+
+		/// Drops zeros up to the next other byte or the end, returns how many were dropped
+		fn skip_zeros(&mut self) -> Result<u64, Error> {
+			let start = self.offset;
+			loop {
+				if let Some(pos) = self.buf.iter().position(|&b| b != 0) {
+					self.consume(pos);
+					break;
+				}
+				self.consume(self.buf.len());
+				if !self.read_more()? {
+					break;
+				}
+			}
+			Ok(self.offset - start)
+		}
+
+		/// Buffers at least `len` bytes, false if the input ends before that
+		fn fill(&mut self, len: usize) -> io::Result<bool> {
+			while self.buf.len() < len {
+				if !self.read_more()? {
+					return Ok(false);
+				}
+			}
+			Ok(true)
+		}
+
+		fn read_more(&mut self) -> io::Result<bool> {
+			let old_len = self.buf.len();
+			self.buf.resize(old_len + Self::READ_CHUNK, 0);
+			let read = read_full(&mut self.inner, &mut self.buf[old_len..])?;
+			self.buf.truncate(old_len + read);
+			Ok(read > 0)
+		}
+
+		fn consume(&mut self, len: usize) {
+			self.buf.drain(..len);
+			self.offset += len as u64;
+		}
+	}
+
+	impl<R: Read> Iterator for Reader<R> {
+		type Item = Result<Block, Error>;
+
+		fn next(&mut self) -> Option<Self::Item> {
+			if self.done {
+				return None;
+			}
+			let block = self.next_block().transpose();
+			if !matches!(block, Some(Ok(_)) | Some(Err(Error::BadBlock { .. }))) {
+				self.done = true;
+			}
+			block
+		}
+	}
+
+	fn is_zero(data: &[u8]) -> bool {
+		data.iter().all(|&b| b == 0)
+	}
+
+	fn unpack(packed: &[u8], header: &BlockHeader, sample_len: usize) -> Result<Vec<u8>, compress::Error> {
+		let mut payload = std::vec![0; header.sample_count() as usize * sample_len + sample_len - 1];
+		let len = compress::unpack(packed, sample_len, header.sample_count() as usize, &mut payload)?;
+		payload.truncate(len);
+		Ok(payload)
+	}
+
+	/// Like `read_exact`, but reports how far it got instead of failing on EOF
+	fn read_full(r: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+		let mut read = 0;
+		while read < buf.len() {
+			match r.read(&mut buf[read..]) {
+				Ok(0) => break,
+				Ok(n) => read += n,
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+				Err(e) => return Err(e),
+			}
+		}
+		Ok(read)
+	}
+}
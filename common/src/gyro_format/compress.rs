@@ -0,0 +1,167 @@
+//! Lossless packing of block payloads.
+//!
+//! A payload is a run of samples made of 16 bit words. The first sample is stored as it is,
+//! every following word as the difference to the same word in the previous sample, zigzag
+//! encoded so small steps either way stay small. Each word of the sample gets a bit width
+//! that fits all of its differences in the block, the differences are then packed with just
+//! that many bits, least significant bit first. Sensor noise keeps the differences from
+//! getting really small, but they rarely need more than 10 of the 16 bits.
+//!
+//! Packed data is the first sample, one width byte per word, the bits padded to a whole
+//! byte, then the bytes after the last whole sample as they are. Every payload is packed on
+//! its own so a lost block doesn't take the following ones with it.
+
+/// Things that can go wrong packing or unpacking
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+	/// The output buffer can't hold the result
+	BufferTooSmall,
+	/// Not what [`pack`] writes for this sample length and count
+	Malformed,
+}
+
+/// Room [`pack`] needs for `len` bytes of samples in the worst case, when all words jump
+/// around wildly
+pub const fn max_packed_len(len: usize, sample_len: usize) -> usize {
+	len + sample_len / 2
+}
+
+/// Whether `len` packed bytes could hold `count` samples of `sample_len` bytes
+pub const fn is_plausible(len: usize, sample_len: usize, count: usize) -> bool {
+	let min = match count {
+		0 => 0,
+		_ => sample_len + sample_len / 2,
+	};
+	min <= len && len < max_packed_len(count * sample_len, sample_len) + sample_len
+}
+
+/// Packs `samples`, made of samples of `sample_len` bytes, into `out`. Returns the length of
+/// the packed data.
+pub fn pack(samples: &[u8], sample_len: usize, out: &mut [u8]) -> Result<usize, Error> {
+	let words = sample_len / 2;
+	let whole = samples.len() / sample_len * sample_len;
+	let mut len = 0;
+	if let Some((first, rest)) = samples[..whole].split_at_checked(sample_len) {
+		let (head, body) = out.split_at_mut_checked(sample_len + words).ok_or(Error::BufferTooSmall)?;
+		let (raw, widths) = head.split_at_mut(sample_len);
+		raw.copy_from_slice(first);
+		widths.fill(0);
+		for (last, sample) in samples[..whole].chunks_exact(sample_len).zip(rest.chunks_exact(sample_len)) {
+			for (i, width) in widths.iter_mut().enumerate() {
+				*width = (*width).max(16 - zigzag(word(sample, i), word(last, i)).leading_zeros() as u8);
+			}
+		}
+
+		let mut bits = BitWriter { out: body, len: 0, acc: 0, filled: 0 };
+		for (last, sample) in samples[..whole].chunks_exact(sample_len).zip(rest.chunks_exact(sample_len)) {
+			for (i, &width) in widths.iter().enumerate() {
+				bits.write(zigzag(word(sample, i), word(last, i)), width)?;
+			}
+		}
+		len = sample_len + words + bits.finish()?;
+	}
+	let tail = &samples[whole..];
+	out.get_mut(len..len + tail.len()).ok_or(Error::BufferTooSmall)?.copy_from_slice(tail);
+	Ok(len + tail.len())
+}
+
+/// Unpacks `count` samples of `sample_len` bytes, and the bytes after them, into `out`.
+/// Returns the unpacked length.
+pub fn unpack(packed: &[u8], sample_len: usize, count: usize, out: &mut [u8]) -> Result<usize, Error> {
+	let words = sample_len / 2;
+	let mut pos = 0;
+	let mut len = 0;
+	if count > 0 {
+		let raw = packed.get(..sample_len).ok_or(Error::Malformed)?;
+		let widths = packed.get(sample_len..sample_len + words).ok_or(Error::Malformed)?;
+		if widths.iter().any(|&width| width > 16) {
+			return Err(Error::Malformed);
+		}
+		if out.len() < count * sample_len {
+			return Err(Error::BufferTooSmall);
+		}
+		out[..sample_len].copy_from_slice(raw);
+
+		let mut bits = BitReader { data: &packed[sample_len + words..], pos: 0, acc: 0, filled: 0 };
+		for len in (sample_len..count * sample_len).step_by(sample_len) {
+			for (i, &width) in widths.iter().enumerate() {
+				let zigzag = bits.read(width).ok_or(Error::Malformed)?;
+				let delta = (zigzag >> 1) ^ (zigzag & 1).wrapping_neg();
+				let value = word(&out[len - sample_len..], i).wrapping_add(delta);
+				out[len + i * 2..len + i * 2 + 2].copy_from_slice(&value.to_le_bytes());
+			}
+		}
+		pos = sample_len + words + bits.pos;
+		len = count * sample_len;
+	}
+	let tail = &packed[pos..];
+	if tail.len() >= sample_len {
+		return Err(Error::Malformed);
+	}
+	out.get_mut(len..len + tail.len()).ok_or(Error::BufferTooSmall)?.copy_from_slice(tail);
+	Ok(len + tail.len())
+}
+
+fn word(sample: &[u8], i: usize) -> u16 {
+	u16::from_le_bytes([sample[i * 2], sample[i * 2 + 1]])
+}
+
+fn zigzag(word: u16, last: u16) -> u16 {
+	let delta = word.wrapping_sub(last) as i16;
+	((delta << 1) ^ (delta >> 15)) as u16
+}
+
+struct BitWriter<'a> {
+	out: &'a mut [u8],
+	len: usize,
+	acc: u32,
+	filled: u8,
+}
+
+impl BitWriter<'_> {
+	fn write(&mut self, value: u16, width: u8) -> Result<(), Error> {
+		self.acc |= (value as u32) << self.filled;
+		self.filled += width;
+		while self.filled >= 8 {
+			self.push()?;
+		}
+		Ok(())
+	}
+
+	fn push(&mut self) -> Result<(), Error> {
+		*self.out.get_mut(self.len).ok_or(Error::BufferTooSmall)? = self.acc as u8;
+		self.len += 1;
+		self.acc >>= 8;
+		self.filled = self.filled.saturating_sub(8);
+		Ok(())
+	}
+
+	/// Pads the last byte with zeros, returns the amount of bytes written
+	fn finish(mut self) -> Result<usize, Error> {
+		if self.filled > 0 {
+			self.push()?;
+		}
+		Ok(self.len)
+	}
+}
+
+struct BitReader<'a> {
+	data: &'a [u8],
+	pos: usize,
+	acc: u32,
+	filled: u8,
+}
+
+impl BitReader<'_> {
+	fn read(&mut self, width: u8) -> Option<u16> {
+		while self.filled < width {
+			self.acc |= (*self.data.get(self.pos)? as u32) << self.filled;
+			self.pos += 1;
+			self.filled += 8;
+		}
+		let value = self.acc & ((1 << width) - 1);
+		self.acc >>= width;
+		self.filled -= width;
+		Some(value as u16)
+	}
+}
@@ -1,5 +1,13 @@
+pub mod orientation;
+
 #[cfg(feature = "gyro_text")]
 pub mod text;
 
 #[cfg(feature = "gyro_binary")]
-pub mod binary;
\ No newline at end of file
+pub mod binary;
+
+#[cfg(feature = "gyro_binary")]
+pub mod compress;
+
+#[cfg(all(feature = "gyro_binary", feature = "std"))]
+pub mod recover;
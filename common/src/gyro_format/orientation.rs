@@ -0,0 +1,86 @@
+//! How the IMU is mounted relative to the camera.
+//!
+//! Written the way Gyroflow does: for camera X, Y and Z in turn, the IMU axis pointing that
+//! way, lowercase if it points the opposite way. `XYZ` is mounted straight, `yXZ` is turned
+//! 90° about Z. Only the 24 proper rotations exist, a mirrored string can't be a mounting.
+
+use core::fmt;
+use core::ops::Neg;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Orientation([u8; 3]);
+
+impl Orientation {
+	pub const IDENTITY: Self = Self(*b"XYZ");
+
+	/// Parses a Gyroflow orientation string like `XYZ` or `yXZ`
+	pub const fn parse(text: &[u8]) -> Option<Self> {
+		let [a, b, c] = match text {
+			[a, b, c] => [*a, *b, *c],
+			_ => return None,
+		};
+		let (Some((ia, na)), Some((ib, nb)), Some((ic, nc))) = (axis(a), axis(b), axis(c)) else {
+			return None;
+		};
+		if ia == ib || ib == ic || ia == ic {
+			return None;
+		}
+		// Even permutations keep handedness, and so does an even number of sign flips
+		let odd_permutation = (ia > ib) ^ (ia > ic) ^ (ib > ic);
+		if odd_permutation ^ na ^ nb ^ nc {
+			return None;
+		}
+		Some(Self([a, b, c]))
+	}
+
+	/// All 24 mountings, [`Self::IDENTITY`] first
+	pub fn all() -> impl Iterator<Item = Self> {
+		const AXES: [u8; 6] = *b"XYZxyz";
+		AXES.into_iter()
+			.flat_map(|a| AXES.into_iter().map(move |b| (a, b)))
+			.flat_map(|(a, b)| AXES.into_iter().map(move |c| [a, b, c]))
+			.filter_map(|text| Self::parse(&text))
+	}
+
+	pub const fn as_bytes(&self) -> &[u8; 3] {
+		&self.0
+	}
+
+	pub fn as_str(&self) -> &str {
+		// Only ever holds ASCII axis letters
+		core::str::from_utf8(&self.0).unwrap()
+	}
+
+	/// Turns a vector in IMU axes into camera axes
+	pub fn rotate<T: Copy + Neg<Output = T>>(&self, v: [T; 3]) -> [T; 3] {
+		self.0.map(|letter| {
+			let (i, negated) = axis(letter).unwrap();
+			if negated { -v[i] } else { v[i] }
+		})
+	}
+}
+
+impl Default for Orientation {
+	fn default() -> Self {
+		Self::IDENTITY
+	}
+}
+
+impl fmt::Display for Orientation {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+/// Axis index and whether it's negated
+const fn axis(letter: u8) -> Option<(usize, bool)> {
+	match letter {
+		b'X' => Some((0, false)),
+		b'Y' => Some((1, false)),
+		b'Z' => Some((2, false)),
+		b'x' => Some((0, true)),
+		b'y' => Some((1, true)),
+		b'z' => Some((2, true)),
+		_ => None,
+	}
+}
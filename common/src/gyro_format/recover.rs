@@ -0,0 +1,179 @@
+//! Finding logs in a raw image of a card.
+//!
+//! When the power goes mid recording, the directory entry still holds the length of the last
+//! flush. What was written after that sits in clusters that no file covers. [`Scanner`] goes
+//! through an image of the whole card or partition regardless of the filesystem. It picks out
+//! every log header and the blocks that follow it, by their sync bytes and CRC. Writing them
+//! out in the order found gives usable logs again.
+//!
+//! A block is taken for the last header before it on the card, as long as the block numbers
+//! go up and the timestamps follow them. Stale blocks of an older recording left in the same
+//! clusters are dropped that way. Clusters that ended up apart from the rest of their log,
+//! and blocks split over two clusters that aren't next to each other, show up as gaps.
+
+use crate::gyro_format::binary::{BinGyroHeader, BlockEncoder, BlockHeader, Compression, Reader, BLOCK_SYNC, MAGIC};
+use std::io::{self, Read};
+use std::vec::Vec;
+use zerocopy::FromBytes;
+
+/// Most a block timestamp may advance per block number. Blocks span a few ms of samples,
+/// more than that only happens across a stale block from another recording.
+const MAX_BLOCK_SECONDS: u64 = 1;
+
+/// Something that looks like part of a log
+#[derive(Debug, Clone, PartialEq)]
+pub enum Found {
+	/// A log header at `offset` into the image, the blocks found next belong to it
+	Log { offset: u64, header: BinGyroHeader },
+	/// A block of the last log, with the payload as stored, packed if the log is
+	Block { offset: u64, header: BlockHeader, payload: Vec<u8> },
+}
+
+/// The log blocks are taken for
+struct Log {
+	sample_len: usize,
+	compression: Compression,
+	timescale: u64,
+	/// Sequence number and timestamp of the last block taken
+	last: Option<(u32, u64)>,
+}
+
+impl Log {
+	fn follows(&self, header: &BlockHeader) -> bool {
+		let Some((sequence, timestamp)) = self.last else { return true };
+		let blocks = header.sequence().wrapping_sub(sequence) as u64;
+		let elapsed = header.timestamp().wrapping_sub(timestamp);
+		header.sequence() > sequence
+			&& header.timestamp() >= timestamp
+			&& elapsed <= blocks * MAX_BLOCK_SECONDS * self.timescale
+	}
+}
+
+/// Streams through an image and yields the log headers and blocks in it, in image order
+pub struct Scanner<R> {
+	inner: R,
+	buf: Vec<u8>,
+	/// Scan position in `buf`
+	pos: usize,
+	/// Image offset of `buf[0]`
+	offset: u64,
+	eof: bool,
+	log: Option<Log>,
+}
+
+impl<R: Read> Scanner<R> {
+	const READ_CHUNK: usize = 1 << 20;
+	/// Bytes kept ahead of the scan position, enough for the largest block
+	const LOOKAHEAD: usize = BlockHeader::LEN + BlockEncoder::MAX_PAYLOAD;
+
+	pub fn new(inner: R) -> Self {
+		Self { inner, buf: Vec::new(), pos: 0, offset: 0, eof: false, log: None }
+	}
+
+	/// How far into the image the scan got
+	pub fn offset(&self) -> u64 {
+		self.offset + self.pos as u64
+	}
+
+	fn next_found(&mut self) -> io::Result<Option<Found>> {
+		loop {
+			self.fill()?;
+			let rest = &self.buf[self.pos..];
+			if rest.len() < BLOCK_SYNC.len() {
+				return Ok(None);
+			}
+			match rest.windows(2).position(|w| w == BLOCK_SYNC || w == &MAGIC[..2]) {
+				Some(at) => self.pos += at,
+				None => {
+					// The last byte could be the first half of a sync
+					self.pos += rest.len() - 1;
+					continue;
+				}
+			}
+			self.fill()?;
+			if let Some(found) = self.log_header().or_else(|| self.block()) {
+				return Ok(Some(found));
+			}
+			self.pos += 1;
+		}
+	}
+
+	fn log_header(&mut self) -> Option<Found> {
+		let rest = &self.buf[self.pos..];
+		if !rest.starts_with(&MAGIC) {
+			return None;
+		}
+		let header = Reader::new(rest).ok()?.header().clone();
+		self.log = Some(Log {
+			sample_len: header.sensor().sample_len(),
+			compression: header.compression(),
+			timescale: header.timescale(),
+			last: None,
+		});
+		let offset = self.offset();
+		self.pos += BinGyroHeader::LEN;
+		Some(Found::Log { offset, header })
+	}
+
+	fn block(&mut self) -> Option<Found> {
+		let log = self.log.as_mut()?;
+		let rest = &self.buf[self.pos..];
+		let header = BlockHeader::read_from_bytes(rest.get(..BlockHeader::LEN)?).ok()?;
+		if !header.is_valid(log.sample_len, log.compression) {
+			return None;
+		}
+		let payload = rest.get(BlockHeader::LEN..BlockHeader::LEN + header.payload_len())?;
+		if !header.matches(payload) || !log.follows(&header) {
+			return None;
+		}
+		log.last = Some((header.sequence(), header.timestamp()));
+		let payload = payload.to_vec();
+		let offset = self.offset();
+		self.pos += BlockHeader::LEN + payload.len();
+		Some(Found::Block { offset, header, payload })
+	}
+
+	/// Buffers [`Self::LOOKAHEAD`] bytes past the scan position, or up to the end of the image
+	fn fill(&mut self) -> io::Result<()> {
+		if self.buf.len() - self.pos >= Self::LOOKAHEAD || self.eof {
+			return Ok(());
+		}
+		self.buf.drain(..self.pos);
+		self.offset += self.pos as u64;
+		self.pos = 0;
+		// Readers may hand out less than asked for, fill the whole chunk all the same
+		let mut len = self.buf.len();
+		self.buf.resize(len + Self::READ_CHUNK, 0);
+		while len < self.buf.len() && !self.eof {
+			match self.inner.read(&mut self.buf[len..]) {
+				Ok(read) => {
+					len += read;
+					self.eof = read == 0;
+				}
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+				Err(e) => {
+					self.buf.truncate(len);
+					return Err(e);
+				}
+			}
+		}
+		self.buf.truncate(len);
+		Ok(())
+	}
+}
+
+impl<R: Read> Iterator for Scanner<R> {
+	type Item = io::Result<Found>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		match self.next_found() {
+			Ok(found) => found.map(Ok),
+			Err(e) => {
+				self.eof = true;
+				self.buf.clear();
+				self.pos = 0;
+				Some(Err(e))
+			}
+		}
+	}
+}
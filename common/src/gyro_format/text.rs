@@ -1,20 +1,54 @@
+use crate::gyro_format::orientation::Orientation;
+use crate::imu::ImuSample;
 use core::fmt;
 use heapless::String;
 
 const REVISION: &str = "traccam_v1";
-const ORIENTATION: &str = "XYZ"; // TODO: determine
+/// Column of the IMU's die temperature in °C
+pub const TEMPERATURE_COLUMN: &str = "temp";
 
-const HEADER: [[&str; 2]; 8] = [
+/// Declares a scale as number and, spelled exactly the same, as header value
+macro_rules! scale {
+	($(#[$doc:meta])* $name:ident, $text:ident = $value:literal) => {
+		$(#[$doc])*
+		pub const $name: f64 = $value;
+		const $text: &str = stringify!($value);
+	};
+}
+
+scale!(
+	/// Rows count time in µs
+	TSCALE, TSCALE_TEXT = 0.000001
+);
+scale!(
+	/// Rows hold rotation in °/s, this turns it into the rad/s Gyroflow works in
+	GSCALE, GSCALE_TEXT = 0.0174532925
+);
+scale!(
+	/// Rows hold acceleration in g
+	ASCALE, ASCALE_TEXT = 1.0
+);
+scale!(
+	/// Rows hold the magnetic field in µT
+	MSCALE, MSCALE_TEXT = 1.0
+);
+
+const HEADER: [[&str; 2]; 7] = [
 	["GYROFLOW IMU LOG", ""],
 	["version", "1.3"],
-	["id", "REVISION"],
-	["orientation", ORIENTATION],
-	["tscale", "0.000001"],
-	["gscale", "0.0174532925"],
-	["ascale", "1.0"],
-	["t,gx,gy,gz,ax,ay,az", ""],
-
+	["id", REVISION],
+	["orientation", "XYZ"], // Replaced by the mounting, always three letters
+	["tscale", TSCALE_TEXT],
+	["gscale", GSCALE_TEXT],
+	["ascale", ASCALE_TEXT],
 ];
+/// Only there with magnetometer columns
+const MSCALE_FIELD: [&str; 2] = ["mscale", MSCALE_TEXT];
+const COLUMNS: &str = "t,gx,gy,gz,ax,ay,az";
+const MAG_COLUMNS: &str = ",mx,my,mz";
+const QUATERNION_COLUMNS: &str = ",qw,qx,qy,qz";
+
+/// Longest header, the one with all optional columns
 pub const HEADER_LEN: usize = {
 	let mut len = 0;
 	let mut i = 0;
@@ -22,19 +56,479 @@ pub const HEADER_LEN: usize = {
 		len += HEADER[i][0].len() + HEADER[i][1].len() + 2; // + newline and comma
 		i += 1;
 	}
-	len
+	len += MSCALE_FIELD[0].len() + MSCALE_FIELD[1].len() + 2;
+	len + COLUMNS.len() + MAG_COLUMNS.len() + 1 + TEMPERATURE_COLUMN.len() + QUATERNION_COLUMNS.len() + 2
 };
 
-pub fn get_header_string() -> String::<HEADER_LEN> {
+/// Columns written besides time, gyro and accel, in this order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Channels {
+	/// `mx,my,mz`
+	pub mag: bool,
+	/// [`TEMPERATURE_COLUMN`]
+	pub temperature: bool,
+	/// `qw,qx,qy,qz`, the orientation worked out from gyro and accel, see
+	/// [`crate::fusion`]
+	pub quaternion: bool,
+}
+
+impl Channels {
+	pub const NONE: Self = Self { mag: false, temperature: false, quaternion: false };
+}
+
+pub fn get_header_string(orientation: Orientation, channels: Channels) -> String::<HEADER_LEN> {
 	let mut header = String::new();
-	write_header(&mut header).unwrap();
+	write_header(&mut header, orientation, channels).unwrap();
 	header
 }
 
-fn write_header(w: &mut impl fmt::Write) -> Result<(), fmt::Error> {
+fn write_header(w: &mut impl fmt::Write, orientation: Orientation, channels: Channels) -> Result<(), fmt::Error> {
 	for [key, value] in HEADER {
+		let value = if key == "orientation" { orientation.as_str() } else { value };
+		writeln!(w, "{key},{value}")?;
+	}
+	if channels.mag {
+		let [key, value] = MSCALE_FIELD;
 		writeln!(w, "{key},{value}")?;
 	}
-	Ok(())
+	w.write_str(COLUMNS)?;
+	if channels.mag {
+		w.write_str(MAG_COLUMNS)?;
+	}
+	if channels.temperature {
+		write!(w, ",{TEMPERATURE_COLUMN}")?;
+	}
+	if channels.quaternion {
+		w.write_str(QUATERNION_COLUMNS)?;
+	}
+	w.write_str(",\n")
+}
+
+/// Sensitivities in fixed point, n°/s, ng, nµT and n°C per LSB, so rows can be written
+/// without floats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RowScale {
+	gyro: i64,
+	accel: i64,
+	mag: Option<i64>,
+	/// Per LSB and at a raw value of 0
+	temperature: Option<(i64, i64)>,
+}
+
+impl RowScale {
+	pub const fn new(gyro_dps_per_lsb: f64, accel_g_per_lsb: f64) -> Self {
+		Self {
+			gyro: nanos(gyro_dps_per_lsb),
+			accel: nanos(accel_g_per_lsb),
+			mag: None,
+			temperature: None,
+		}
+	}
+
+	/// Adds the magnetometer columns
+	pub const fn with_mag(mut self, ut_per_lsb: f64) -> Self {
+		self.mag = Some(nanos(ut_per_lsb));
+		self
+	}
+
+	/// Adds the temperature column
+	pub const fn with_temperature(mut self, c_per_lsb: f64, zero_c: f64) -> Self {
+		self.temperature = Some((nanos(c_per_lsb), nanos(zero_c)));
+		self
+	}
+
+	/// Columns the rows of this scale are written with
+	pub const fn channels(&self) -> Channels {
+		Channels {
+			mag: self.mag.is_some(),
+			temperature: self.temperature.is_some(),
+			quaternion: false,
+		}
+	}
+
+	/// Gyro in n°/s and accel in ng
+	pub const fn values(&self, sample: &ImuSample) -> [i64; 6] {
+		let [gx, gy, gz] = sample.gyro;
+		let [ax, ay, az] = sample.accel;
+		[
+			gx as i64 * self.gyro,
+			gy as i64 * self.gyro,
+			gz as i64 * self.gyro,
+			ax as i64 * self.accel,
+			ay as i64 * self.accel,
+			az as i64 * self.accel,
+		]
+	}
+
+	pub const fn row(&self, micros: u64, sample: &ImuSample) -> Row {
+		let mag = match (self.mag, sample.mag) {
+			(Some(scale), Some([mx, my, mz])) => Some([mx as i64 * scale, my as i64 * scale, mz as i64 * scale]),
+			_ => None,
+		};
+		let temperature = match (self.temperature, sample.temperature) {
+			(Some((scale, zero)), Some(raw)) => Some(raw as i64 * scale + zero),
+			_ => None,
+		};
+		Row { micros, values: Some(self.values(sample)), mag, temperature, quaternion: None }
+	}
+}
+
+const fn nanos(value: f64) -> i64 {
+	(value * 1e9 + if value < 0.0 { -0.5 } else { 0.5 }) as i64
+}
+
+/// One CSV row in fixed point: µs, then gyro in n°/s and accel in ng, magnetometer in nµT,
+/// temperature in n°C and the orientation quaternion in billionths
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Row {
+	pub micros: u64,
+	/// None for a sample that got lost, written as NaN to keep the timeline intact
+	pub values: Option<[i64; 6]>,
+	/// Written as NaN when missing but the log has the columns
+	pub mag: Option<[i64; 3]>,
+	pub temperature: Option<i64>,
+	pub quaternion: Option<[i64; 4]>,
+}
+
+impl Row {
+	pub const fn lost(micros: u64) -> Self {
+		Self { micros, values: None, mag: None, temperature: None, quaternion: None }
+	}
+}
+
+/// Longest possible row: a 20 digit timestamp, fourteen 19 digit values with sign, point and
+/// separator, and the newline
+pub const ROW_MAX_LEN: usize = 20 + 14 * (2 + 21) + 1;
+
+/// Writes `row` as a line of CSV with the columns of `channels`, values with as many decimals
+/// as they need
+pub fn write_row(w: &mut impl fmt::Write, row: &Row, channels: Channels) -> fmt::Result {
+	write!(w, "{}", row.micros)?;
+	let values = row.values.map_or([None; 6], |values| values.map(Some));
+	let mag = row.mag.map_or([None; 3], |mag| mag.map(Some));
+	let mag = mag.into_iter().take(if channels.mag { 3 } else { 0 });
+	let temperature = channels.temperature.then_some(row.temperature);
+	let quaternion = row.quaternion.map_or([None; 4], |q| q.map(Some));
+	let quaternion = quaternion.into_iter().take(if channels.quaternion { 4 } else { 0 });
+	for (i, value) in values.into_iter().chain(mag).chain(temperature).chain(quaternion).enumerate() {
+		w.write_str(if i == 0 { "," } else { ", " })?;
+		match value {
+			Some(nanos) => write_nanos(w, nanos)?,
+			None => w.write_str("NaN")?,
+		}
+	}
+	w.write_char('\n')
+}
+
+pub fn row_string(row: &Row, channels: Channels) -> String<ROW_MAX_LEN> {
+	let mut line = String::new();
+	write_row(&mut line, row, channels).unwrap();
+	line
+}
+
+/// Billionths as decimal number, trailing zeros dropped
+fn write_nanos(w: &mut impl fmt::Write, nanos: i64) -> fmt::Result {
+	let sign = if nanos < 0 { "-" } else { "" };
+	let int = nanos.unsigned_abs() / 1_000_000_000;
+	let mut frac = nanos.unsigned_abs() % 1_000_000_000;
+	if frac == 0 {
+		return write!(w, "{sign}{int}");
+	}
+	let mut digits = 9;
+	while frac.is_multiple_of(10) {
+		frac /= 10;
+		digits -= 1;
+	}
+	write!(w, "{sign}{int}.{frac:0digits$}")
 }
 
+#[cfg(feature = "std")]
+pub use reader::{Error, Header, Reader, Record};
+
+#[cfg(feature = "std")]
+mod reader {
+	use super::*;
+	use std::io::{self, BufRead};
+	use std::string::{String, ToString};
+	use std::vec::Vec;
+	use std::{fmt, format};
+
+	/// First line of every Gyroflow IMU log, with or without the trailing comma
+	const MAGIC: &str = "GYROFLOW IMU LOG";
+	/// Header lines looked through for the column names before giving up
+	const MAX_HEADER_LINES: usize = 64;
+
+	#[derive(Debug)]
+	pub enum Error {
+		Io(io::Error),
+		BadMagic,
+		BadHeader { line: usize, reason: String },
+		BadRow { line: usize, reason: String },
+		/// The last row was cut short, it starts at byte `offset`
+		Truncated { line: usize, offset: u64 },
+	}
+
+	impl fmt::Display for Error {
+		fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+			match self {
+				Error::Io(e) => write!(f, "I/O error: {e}"),
+				Error::BadMagic => write!(f, "not a Gyroflow IMU log"),
+				Error::BadHeader { line, reason } => write!(f, "malformed header in line {line}: {reason}"),
+				Error::BadRow { line, reason } => write!(f, "line {line}: {reason}"),
+				Error::Truncated { line, offset } => write!(f, "file truncated in line {line} at byte {offset}"),
+			}
+		}
+	}
+
+	impl std::error::Error for Error {
+		fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+			match self {
+				Error::Io(e) => Some(e),
+				_ => None,
+			}
+		}
+	}
+
+	impl From<io::Error> for Error {
+		fn from(e: io::Error) -> Self {
+			Error::Io(e)
+		}
+	}
+
+	/// The `key,value` block and the column names
+	#[derive(Debug, Clone, PartialEq)]
+	pub struct Header {
+		pub version: Option<String>,
+		pub id: Option<String>,
+		pub orientation: Orientation,
+		/// Seconds per unit of `t`
+		pub tscale: f64,
+		/// rad/s per unit of gyro
+		pub gscale: f64,
+		/// g per unit of accel
+		pub ascale: f64,
+		/// µT per unit of magnetometer, there whenever the magnetometer columns are
+		pub mscale: Option<f64>,
+		/// Every `key,value` line in file order, including the ones above
+		pub fields: Vec<(String, String)>,
+		/// As named in the file
+		pub columns: Vec<String>,
+		index: Columns,
+	}
+
+	#[derive(Debug, Clone, PartialEq)]
+	struct Columns {
+		t: usize,
+		gyro: [usize; 3],
+		accel: [usize; 3],
+		mag: Option<[usize; 3]>,
+		temperature: Option<usize>,
+		quaternion: Option<[usize; 4]>,
+	}
+
+	impl Header {
+		pub fn get(&self, key: &str) -> Option<&str> {
+			self.fields.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+		}
+
+		fn from_fields(fields: Vec<(String, String)>, columns: Vec<String>) -> Result<Self, String> {
+			let get = |key: &str| fields.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+			let scale = |key: &str| -> Result<Option<f64>, String> {
+				get(key).map(|v| v.parse().map_err(|_| format!("{key} `{v}` is not a number"))).transpose()
+			};
+			let required = |key: &str| scale(key)?.ok_or_else(|| format!("no {key}"));
+			let column = |name: &str| columns.iter().position(|c| c == name);
+			let required_column = |name: &str| column(name).ok_or_else(|| format!("no {name} column"));
+
+			let orientation = match get("orientation") {
+				None => Orientation::IDENTITY,
+				Some(v) => Orientation::parse(v.as_bytes()).ok_or_else(|| format!("orientation `{v}` is not a rotation"))?,
+			};
+			let mag = match [column("mx"), column("my"), column("mz")] {
+				[Some(x), Some(y), Some(z)] => Some([x, y, z]),
+				[None, None, None] => None,
+				_ => return Err("incomplete magnetometer columns".into()),
+			};
+			let quaternion = match [column("qw"), column("qx"), column("qy"), column("qz")] {
+				[Some(w), Some(x), Some(y), Some(z)] => Some([w, x, y, z]),
+				[None, None, None, None] => None,
+				_ => return Err("incomplete quaternion columns".into()),
+			};
+			let mscale = scale("mscale")?;
+			if mag.is_some() && mscale.is_none() {
+				return Err("magnetometer columns without mscale".into());
+			}
+			let index = Columns {
+				t: required_column("t")?,
+				gyro: [required_column("gx")?, required_column("gy")?, required_column("gz")?],
+				accel: [required_column("ax")?, required_column("ay")?, required_column("az")?],
+				mag,
+				temperature: column(TEMPERATURE_COLUMN),
+				quaternion,
+			};
+			let [tscale, gscale, ascale] = [required("tscale")?, required("gscale")?, required("ascale")?];
+			let version = get("version").map(String::from);
+			let id = get("id").map(String::from);
+
+			Ok(Self { version, id, orientation, tscale, gscale, ascale, mscale, fields, columns, index })
+		}
+
+		fn record(&self, fields: &[&str]) -> Option<Record> {
+			let value = |i: usize| fields.get(i)?.parse::<f64>().ok();
+			let xyz = |[x, y, z]: [usize; 3]| Some([value(x)?, value(y)?, value(z)?]);
+			Some(Record {
+				t: value(self.index.t)?,
+				gyro: xyz(self.index.gyro)?,
+				accel: xyz(self.index.accel)?,
+				mag: match self.index.mag {
+					Some(columns) => Some(xyz(columns)?),
+					None => None,
+				},
+				temperature: match self.index.temperature {
+					Some(column) => Some(value(column)?),
+					None => None,
+				},
+				quaternion: match self.index.quaternion {
+					Some([w, x, y, z]) => Some([value(w)?, value(x)?, value(y)?, value(z)?]),
+					None => None,
+				},
+			})
+		}
+	}
+
+	/// One row, in the units of the file: multiply by the header scales
+	#[derive(Debug, Clone, Copy, PartialEq)]
+	pub struct Record {
+		pub t: f64,
+		pub gyro: [f64; 3],
+		pub accel: [f64; 3],
+		pub mag: Option<[f64; 3]>,
+		/// °C
+		pub temperature: Option<f64>,
+		/// Orientation as `w, x, y, z`
+		pub quaternion: Option<[f64; 4]>,
+	}
+
+	impl Record {
+		/// Lost samples are written as NaN
+		pub fn is_lost(&self) -> bool {
+			self.gyro.iter().chain(&self.accel).any(|v| v.is_nan())
+		}
+	}
+
+	/// Streaming decoder for Gyroflow CSV logs, yields one [`Record`] per row.
+	///
+	/// Malformed rows are reported as [`Error::BadRow`] and skipped. I/O errors and a last row
+	/// that was cut short end the iteration.
+	pub struct Reader<R> {
+		inner: R,
+		header: Header,
+		buf: Vec<u8>,
+		line: usize,
+		offset: u64, // File offset of the next line
+		done: bool,
+	}
+
+	impl<R: BufRead> Reader<R> {
+		pub fn new(mut inner: R) -> Result<Self, Error> {
+			let mut buf = Vec::new();
+			let mut offset = 0;
+			let mut fields = Vec::new();
+			let mut lines = 0;
+			for line in 1..=MAX_HEADER_LINES {
+				let Some(complete) = read_line(&mut inner, &mut buf, &mut offset)? else { break };
+				lines = line;
+				let text = match core::str::from_utf8(&buf) {
+					Ok(text) => text.trim_end(),
+					Err(_) if line == 1 => return Err(Error::BadMagic),
+					Err(_) => return Err(Error::BadHeader { line, reason: "not text".into() }),
+				};
+				if line == 1 {
+					if text.trim_end_matches(',') != MAGIC {
+						return Err(Error::BadMagic);
+					}
+					continue;
+				}
+				if !complete {
+					return Err(Error::Truncated { line, offset: offset - buf.len() as u64 });
+				}
+
+				if text.starts_with("t,") {
+					let columns = text.split(',').map(str::trim).filter(|c| !c.is_empty()).map(String::from).collect();
+					let header = Header::from_fields(fields, columns).map_err(|reason| Error::BadHeader { line, reason })?;
+					return Ok(Self { inner, header, buf, line, offset, done: false });
+				}
+				match text.split_once(',') {
+					Some((key, value)) => fields.push((key.trim().to_string(), value.trim().to_string())),
+					None => return Err(Error::BadHeader { line, reason: "neither `key,value` nor column names".into() }),
+				}
+			}
+			match lines {
+				0 => Err(Error::BadMagic),
+				line => Err(Error::BadHeader { line, reason: "no column names".into() }),
+			}
+		}
+
+		pub fn header(&self) -> &Header {
+			&self.header
+		}
+
+		/// Bytes read so far, right after [`Self::new`] the length of the header
+		pub fn offset(&self) -> u64 {
+			self.offset
+		}
+
+		fn next_record(&mut self) -> Result<Option<Record>, Error> {
+			loop {
+				let Some(complete) = read_line(&mut self.inner, &mut self.buf, &mut self.offset)? else {
+					return Ok(None);
+				};
+				self.line += 1;
+				let line = self.line;
+				let Ok(text) = core::str::from_utf8(&self.buf).map(str::trim) else {
+					return Err(Error::BadRow { line, reason: "not text".into() });
+				};
+				if text.is_empty() {
+					continue;
+				}
+				let fields: Vec<&str> = text.split(',').map(str::trim).collect();
+				return match self.header.record(&fields) {
+					Some(record) => Ok(Some(record)),
+					// A write cut short by pulling the card, the rows before it are fine
+					None if !complete => Err(Error::Truncated { line, offset: self.offset - self.buf.len() as u64 }),
+					None => Err(Error::BadRow { line, reason: format!("expected numbers in columns {}", self.header.columns.join(",")) }),
+				};
+			}
+		}
+	}
+
+	impl<R: BufRead> Iterator for Reader<R> {
+		type Item = Result<Record, Error>;
+
+		fn next(&mut self) -> Option<Self::Item> {
+			if self.done {
+				return None;
+			}
+			let record = self.next_record().transpose();
+			if !matches!(record, Some(Ok(_)) | Some(Err(Error::BadRow { .. }))) {
+				self.done = true;
+			}
+			record
+		}
+	}
+
+	/// Reads the next line into `buf`, without the newline. Whether it had one, None at the end.
+	fn read_line(r: &mut impl BufRead, buf: &mut Vec<u8>, offset: &mut u64) -> io::Result<Option<bool>> {
+		buf.clear();
+		let read = r.read_until(b'\n', buf)?;
+		*offset += read as u64;
+		if read == 0 {
+			return Ok(None);
+		}
+		let complete = buf.last() == Some(&b'\n');
+		if complete {
+			buf.pop();
+		}
+		Ok(Some(complete))
+	}
+}
@@ -0,0 +1,86 @@
+//! Gyro zero-rate offset and accelerometer offset from a period without motion.
+//!
+//! At rest the gyro should read zero and the accelerometer exactly 1 g. Whatever they read
+//! instead stays about the same for a whole recording, the gyro offset mostly shifting with
+//! die temperature, and can be subtracted from every sample.
+
+use crate::imu::{ImuSample, SensorInfo};
+
+/// Largest gyro swing on any axis, peak to peak, that still counts as standing still. The
+/// noise of the supported IMUs stays well below this.
+pub const STILL_GYRO_SPREAD_DPS: f64 = 2.0;
+
+/// Offsets in raw LSB, subtract them from every sample
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Calibration {
+	pub gyro_bias: [f32; 3],
+	/// How far the accelerometer is off along gravity in the pose it was taken in
+	pub accel_offset: [f32; 3],
+	/// Samples averaged
+	pub samples: u32,
+}
+
+/// Averages samples and checks that nothing moved while they were taken, usable without
+/// allocation on the firmware
+#[derive(Debug, Clone)]
+pub struct StillEstimator {
+	gyro_sum: [i64; 3],
+	accel_sum: [i64; 3],
+	gyro_min: [i16; 3],
+	gyro_max: [i16; 3],
+	count: u32,
+	max_spread: i32,
+	lsb_per_g: f64,
+}
+
+impl StillEstimator {
+	pub fn new(info: &SensorInfo) -> Self {
+		Self {
+			gyro_sum: [0; 3],
+			accel_sum: [0; 3],
+			gyro_min: [i16::MAX; 3],
+			gyro_max: [i16::MIN; 3],
+			count: 0,
+			max_spread: (STILL_GYRO_SPREAD_DPS / info.gyro_dps_per_lsb) as i32,
+			lsb_per_g: 1.0 / info.accel_g_per_lsb,
+		}
+	}
+
+	pub fn add(&mut self, sample: &ImuSample) {
+		for axis in 0..3 {
+			self.gyro_sum[axis] += sample.gyro[axis] as i64;
+			self.accel_sum[axis] += sample.accel[axis] as i64;
+			self.gyro_min[axis] = self.gyro_min[axis].min(sample.gyro[axis]);
+			self.gyro_max[axis] = self.gyro_max[axis].max(sample.gyro[axis]);
+		}
+		self.count += 1;
+	}
+
+	/// Samples added so far
+	pub fn count(&self) -> u32 {
+		self.count
+	}
+
+	/// Whether the gyro stayed within [`STILL_GYRO_SPREAD_DPS`] on all axes
+	pub fn is_still(&self) -> bool {
+		(0..3).all(|axis| self.gyro_max[axis] as i32 - self.gyro_min[axis] as i32 <= self.max_spread)
+	}
+
+	/// Offsets from the samples so far, None without samples or if the rig moved
+	pub fn calibration(&self) -> Option<Calibration> {
+		if self.count == 0 || !self.is_still() {
+			return None;
+		}
+		let mean = |sums: [i64; 3]| sums.map(|sum| sum as f64 / self.count as f64);
+		let accel = mean(self.accel_sum);
+		let norm = libm::sqrt(accel.iter().map(|a| a * a).sum());
+		if norm == 0.0 {
+			return None;
+		}
+		Some(Calibration {
+			gyro_bias: mean(self.gyro_sum).map(|g| g as f32),
+			accel_offset: accel.map(|a| (a - a / norm * self.lsb_per_g) as f32),
+			samples: self.count,
+		})
+	}
+}
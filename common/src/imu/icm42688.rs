@@ -0,0 +1,367 @@
+//! ICM-42688-P 6-axis IMU
+//! https://invensense.tdk.com/wp-content/uploads/2020/04/ds-000347_icm-42688-p-datasheet.pdf
+//!
+//! The FIFO is read in 16 byte packets of accel, gyro, temperature and timestamp. Its
+//! timestamp is only 16 bit and not recorded, timing comes from the batch stamps.
+
+use crate::imu::{Error, ImuDriver, ImuSample, SampleBatch, SampleLayout, SensorInfo};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::i2c::I2c;
+use heapless::Vec;
+
+/// Default I2C address, AP_AD0 pulled low
+pub const ADDRESS: u8 = 0x68;
+
+const WHO_AM_I_VALUE: u8 = 0x47;
+
+/// Size of the FIFO buffer on the IMU
+pub const FIFO_BUFSIZE: usize = 2048;
+
+/// FIFO packet 3: header, accel, gyro, temperature and timestamp
+pub const PACKET_LEN: usize = 16;
+
+const DEVICE_CONFIG: u8 = 0x11;
+const INT_CONFIG: u8 = 0x14;
+const FIFO_CONFIG: u8 = 0x16;
+const INT_STATUS: u8 = 0x2D;
+const FIFO_DATA: u8 = 0x30;
+const SIGNAL_PATH_RESET: u8 = 0x4B;
+const INTF_CONFIG0: u8 = 0x4C;
+const PWR_MGMT0: u8 = 0x4E;
+const GYRO_CONFIG0: u8 = 0x4F;
+const ACCEL_CONFIG0: u8 = 0x50;
+const FIFO_CONFIG1: u8 = 0x5F;
+const FIFO_CONFIG2: u8 = 0x60;
+const FIFO_CONFIG3: u8 = 0x61;
+const INT_SOURCE0: u8 = 0x65;
+const WHO_AM_I: u8 = 0x75;
+
+const INT_STATUS_FIFO_FULL: u8 = 0b0000_0010;
+const FIFO_HEADER_EMPTY: u8 = 0b1000_0000;
+const FIFO_HEADER_ACCEL_GYRO: u8 = 0b0110_0000;
+
+/// How often the FIFO should be read out, same as for the LSM6DS3
+const READOUT_INTERVAL_MS: u32 = 25;
+
+/// Sensitivity of the 8 bit FIFO temperature, it reads 0 at 25°C
+const FIFO_TEMP_LSB_PER_C: f64 = 2.07;
+const TEMP_ZERO_C: f64 = 25.0;
+
+/// Output data rate of accelerometer, gyroscope and FIFO
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Odr {
+	Hz12_5,
+	Hz25,
+	Hz50,
+	Hz100,
+	Hz200,
+	Hz500,
+	Hz1000,
+	Hz2000,
+	Hz4000,
+	Hz8000,
+}
+
+impl Odr {
+	/// GYRO_ODR and ACCEL_ODR share the same encoding
+	pub const fn bits(self) -> u8 {
+		match self {
+			Odr::Hz8000 => 0b0011,
+			Odr::Hz4000 => 0b0100,
+			Odr::Hz2000 => 0b0101,
+			Odr::Hz1000 => 0b0110,
+			Odr::Hz200 => 0b0111,
+			Odr::Hz100 => 0b1000,
+			Odr::Hz50 => 0b1001,
+			Odr::Hz25 => 0b1010,
+			Odr::Hz12_5 => 0b1011,
+			Odr::Hz500 => 0b1111,
+		}
+	}
+
+	pub const fn millihertz(self) -> u32 {
+		match self {
+			Odr::Hz12_5 => 12_500,
+			Odr::Hz25 => 25_000,
+			Odr::Hz50 => 50_000,
+			Odr::Hz100 => 100_000,
+			Odr::Hz200 => 200_000,
+			Odr::Hz500 => 500_000,
+			Odr::Hz1000 => 1_000_000,
+			Odr::Hz2000 => 2_000_000,
+			Odr::Hz4000 => 4_000_000,
+			Odr::Hz8000 => 8_000_000,
+		}
+	}
+}
+
+/// Accelerometer full scale
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccelScale {
+	G2,
+	G4,
+	G8,
+	G16,
+}
+
+impl AccelScale {
+	/// ACCEL_FS_SEL
+	pub const fn bits(self) -> u8 {
+		match self {
+			AccelScale::G16 => 0b000,
+			AccelScale::G8 => 0b001,
+			AccelScale::G4 => 0b010,
+			AccelScale::G2 => 0b011,
+		}
+	}
+
+	pub const fn range_g(self) -> u16 {
+		match self {
+			AccelScale::G2 => 2,
+			AccelScale::G4 => 4,
+			AccelScale::G8 => 8,
+			AccelScale::G16 => 16,
+		}
+	}
+
+	/// Linear acceleration sensitivity in g/LSB
+	pub const fn g_per_lsb(self) -> f64 {
+		match self {
+			AccelScale::G2 => 1.0 / 16384.0,
+			AccelScale::G4 => 1.0 / 8192.0,
+			AccelScale::G8 => 1.0 / 4096.0,
+			AccelScale::G16 => 1.0 / 2048.0,
+		}
+	}
+}
+
+/// Gyroscope full scale
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GyroScale {
+	Dps125,
+	Dps250,
+	Dps500,
+	Dps1000,
+	Dps2000,
+}
+
+impl GyroScale {
+	/// GYRO_FS_SEL
+	pub const fn bits(self) -> u8 {
+		match self {
+			GyroScale::Dps2000 => 0b000,
+			GyroScale::Dps1000 => 0b001,
+			GyroScale::Dps500 => 0b010,
+			GyroScale::Dps250 => 0b011,
+			GyroScale::Dps125 => 0b100,
+		}
+	}
+
+	pub const fn range_dps(self) -> u16 {
+		match self {
+			GyroScale::Dps125 => 125,
+			GyroScale::Dps250 => 250,
+			GyroScale::Dps500 => 500,
+			GyroScale::Dps1000 => 1000,
+			GyroScale::Dps2000 => 2000,
+		}
+	}
+
+	/// Angular rate sensitivity in dps/LSB
+	pub const fn dps_per_lsb(self) -> f64 {
+		match self {
+			GyroScale::Dps125 => 1.0 / 262.0,
+			GyroScale::Dps250 => 1.0 / 131.0,
+			GyroScale::Dps500 => 1.0 / 65.5,
+			GyroScale::Dps1000 => 1.0 / 32.8,
+			GyroScale::Dps2000 => 1.0 / 16.4,
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ImuConfig {
+	pub odr: Odr,
+	pub accel: AccelScale,
+	pub gyro: GyroScale,
+	/// Store the die temperature that comes with every FIFO packet
+	pub temperature: bool,
+}
+
+impl Default for ImuConfig {
+	fn default() -> Self {
+		Self::DEFAULT
+	}
+}
+
+impl ImuConfig {
+	pub const DEFAULT: Self = Self {
+		odr: Odr::Hz1000,
+		accel: AccelScale::G2,
+		gyro: GyroScale::Dps250,
+		temperature: false,
+	};
+
+	/// What a sample holds once read out
+	pub const fn layout(&self) -> SampleLayout {
+		SampleLayout {
+			mag: false,
+			timestamp: false,
+			temperature: self.temperature,
+		}
+	}
+
+	pub const fn gyro_config0(&self) -> u8 {
+		self.gyro.bits() << 5 | self.odr.bits()
+	}
+
+	pub const fn accel_config0(&self) -> u8 {
+		self.accel.bits() << 5 | self.odr.bits()
+	}
+
+	/// FIFO threshold in packets
+	pub const fn watermark_packets(&self) -> u16 {
+		let per_readout = self.odr.millihertz() * READOUT_INTERVAL_MS / 1_000_000;
+		let max = (FIFO_BUFSIZE / PACKET_LEN / 4) as u32;
+		let packets = if per_readout > max { max } else { per_readout };
+		(if packets == 0 { 1 } else { packets }) as u16
+	}
+
+	pub const fn info(&self) -> SensorInfo {
+		SensorInfo {
+			model: "ICM-42688-P",
+			odr_millihertz: self.odr.millihertz(),
+			gyro_range_dps: self.gyro.range_dps(),
+			gyro_dps_per_lsb: self.gyro.dps_per_lsb(),
+			accel_range_g: self.accel.range_g(),
+			accel_g_per_lsb: self.accel.g_per_lsb(),
+			hw_timestamp_ns: 0,
+			temp_c_per_lsb: if self.temperature { 1.0 / FIFO_TEMP_LSB_PER_C } else { 0.0 },
+			temp_zero_c: TEMP_ZERO_C,
+			mag_ut_per_lsb: 0.0,
+		}
+	}
+}
+
+/// Register writes that bring a freshly reset IMU into the given config
+pub fn init_sequence(config: &ImuConfig) -> Vec<[u8; 2], 10> {
+	let watermark = config.watermark_packets().to_le_bytes();
+	let mut cmds: Vec<[u8; 2], 10> = Vec::new();
+	let _ = cmds.extend_from_slice(&[
+		[INTF_CONFIG0, 0b0111_0000],        // FIFO count in packets, big endian
+		[INT_CONFIG, 0b0000_0111],          // INT1 latched, push-pull, active high
+		[FIFO_CONFIG1, 0b0000_0111],        // Accel, gyro and temperature, packet 3
+		[FIFO_CONFIG2, watermark[0]],       // Watermark LSB
+		[FIFO_CONFIG3, watermark[1]],       // Watermark MSB
+		[INT_SOURCE0, 0b0000_0110],         // FIFO threshold and full to INT1
+		[FIFO_CONFIG, 0b0100_0000],         // Stream to FIFO
+		[GYRO_CONFIG0, config.gyro_config0()],   // Gyro ODR and full scale
+		[ACCEL_CONFIG0, config.accel_config0()], // Accel ODR and full scale
+		[PWR_MGMT0, 0b0000_1111],           // Gyro and accel in low noise mode
+	]);
+	cmds
+}
+
+/// Driver for an ICM-42688-P on I2C with its FIFO threshold on INT1
+pub struct Icm42688<I2C, INT> {
+	i2c: I2C,
+	int1: INT,
+	config: ImuConfig,
+	fifo_buf: [u8; FIFO_BUFSIZE],
+}
+
+impl<I2C: I2c, INT: Wait> Icm42688<I2C, INT> {
+	pub const fn new(i2c: I2C, int1: INT) -> Self {
+		Self {
+			i2c,
+			int1,
+			config: ImuConfig::DEFAULT,
+			fifo_buf: [0_u8; FIFO_BUFSIZE],
+		}
+	}
+
+	/// Gives back the bus and pin
+	pub fn release(self) -> (I2C, INT) {
+		(self.i2c, self.int1)
+	}
+}
+
+impl<I2C: I2c, INT: Wait> ImuDriver for Icm42688<I2C, INT> {
+	type Config = ImuConfig;
+	type BusError = I2C::Error;
+
+	/// Soft resets the IMU and starts streaming into the FIFO
+	async fn init(&mut self, config: ImuConfig, delay: &mut impl DelayNs) -> Result<(), Error<I2C::Error>> {
+		self.i2c.write(ADDRESS, &[DEVICE_CONFIG, 0b0000_0001]).await?;
+		// Registers are accessible again 1ms after the reset
+		delay.delay_ms(1).await;
+
+		let mut who_am_i = [0];
+		self.i2c.write_read(ADDRESS, &[WHO_AM_I], &mut who_am_i).await?;
+		if who_am_i[0] != WHO_AM_I_VALUE {
+			return Err(Error::WrongDevice(who_am_i[0]));
+		}
+
+		for cmd in init_sequence(&config) {
+			self.i2c.write(ADDRESS, &cmd).await?;
+		}
+		// No register writes for 200µs after switching the sensors on
+		delay.delay_us(200).await;
+		self.config = config;
+		Ok(())
+	}
+
+	/// INT1 is latched until INT_STATUS is read with the next batch
+	async fn wait_data_ready(&mut self) -> Result<(), Error<I2C::Error>> {
+		self.int1.wait_for_high().await.map_err(|_| Error::Pin)
+	}
+
+	/// The FIFO counts whole packets, so a readout can never end up in the middle of one.
+	/// A full FIFO gets flushed and [`Error::Overrun`] returned.
+	async fn read_batch(&mut self) -> Result<SampleBatch<'_>, Error<I2C::Error>> {
+		// INT_STATUS, FIFO_COUNTH and FIFO_COUNTL, reading clears the interrupt
+		let mut status = [0u8; 3];
+		self.i2c.write_read(ADDRESS, &[INT_STATUS], &mut status).await?;
+		if status[0] & INT_STATUS_FIFO_FULL != 0 {
+			self.i2c.write(ADDRESS, &[SIGNAL_PATH_RESET, 0b0000_0010]).await?;
+			return Err(Error::Overrun);
+		}
+
+		let packets = (u16::from_be_bytes([status[1], status[2]]) as usize).min(FIFO_BUFSIZE / PACKET_LEN);
+		let data = &mut self.fifo_buf[..packets * PACKET_LEN];
+		if !data.is_empty() {
+			self.i2c.write_read(ADDRESS, &[FIFO_DATA], data).await?;
+		}
+
+		// Repack into log layout in place, samples are smaller than packets
+		let layout = self.config.layout();
+		let sample_len = layout.sample_len();
+		let mut samples = 0;
+		for i in 0..packets {
+			let packet = &self.fifo_buf[i * PACKET_LEN..(i + 1) * PACKET_LEN];
+			if packet[0] & FIFO_HEADER_EMPTY != 0 || packet[0] & FIFO_HEADER_ACCEL_GYRO != FIFO_HEADER_ACCEL_GYRO {
+				break;
+			}
+			let word = |i: usize| i16::from_be_bytes([packet[i], packet[i + 1]]);
+			let sample = ImuSample {
+				gyro: [word(7), word(9), word(11)],
+				accel: [word(1), word(3), word(5)],
+				mag: None,
+				temperature: Some(packet[13] as i8 as i16),
+			};
+			layout.encode(&sample, &mut self.fifo_buf[samples * sample_len..(samples + 1) * sample_len]);
+			samples += 1;
+		}
+		Ok(SampleBatch::new(&self.fifo_buf[..samples * sample_len], layout))
+	}
+
+	async fn power_off(&mut self) -> Result<(), Error<I2C::Error>> {
+		self.i2c.write(ADDRESS, &[PWR_MGMT0, 0]).await?;
+		Ok(())
+	}
+
+	fn info(&self) -> SensorInfo {
+		self.config.info()
+	}
+}
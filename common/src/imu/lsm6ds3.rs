@@ -0,0 +1,520 @@
+//! LSM6DS3TR-C 6-axis IMU
+//! https://www.st.com/resource/en/datasheet/lsm6ds3tr-c.pdf
+
+#[cfg(feature = "gyro_binary")]
+use crate::gyro_format::binary::SensorConfig;
+use crate::imu::{Error, ImuDriver, ImuSample, SampleBatch, SampleLayout, SensorInfo};
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::i2c::I2c;
+use heapless::Vec;
+
+/// Default I2C address, SDO/SA0 pulled low
+pub const ADDRESS: u8 = 0x6A;
+
+const WHO_AM_I_VALUE: u8 = 0x6A;
+
+/// Size of the FIFO buffer on the IMU
+pub const FIFO_BUFSIZE: usize = 4096;
+
+/// Every FIFO data set is three 16 bit words. With both sensors enabled the FIFO cycles
+/// through gyro (data set 1), accel (data set 2) and, if enabled, the timestamp (data set 4),
+/// which is the order [`SampleLayout`] stores them in.
+pub const DATA_SET_LEN: usize = 6;
+/// Bytes of the timestamp data set following a sample, see [`decode_timestamp`]
+pub const TIMESTAMP_LEN: usize = DATA_SET_LEN;
+
+const FIFO_CTRL1: u8 = 0x06;
+const FIFO_CTRL2: u8 = 0x07;
+const FIFO_CTRL3: u8 = 0x08;
+const FIFO_CTRL4: u8 = 0x09;
+const FIFO_CTRL5: u8 = 0x0A;
+const INT1_CTRL: u8 = 0x0D;
+const WHO_AM_I: u8 = 0x0F;
+const CTRL1_XL: u8 = 0x10;
+const CTRL2_G: u8 = 0x11;
+const CTRL3_C: u8 = 0x12;
+const CTRL10_C: u8 = 0x19;
+const OUT_TEMP_L: u8 = 0x20;
+const FIFO_MODE_MASK: u8 = 0b111;
+const FIFO_STATUS1: u8 = 0x3A;
+const FIFO_DATA_OUT_L: u8 = 0x3E;
+const TIMESTAMP2_REG: u8 = 0x42;
+const WAKE_UP_DUR: u8 = 0x5C;
+
+/// OUT_TEMP sensitivity, it reads 0 at 25°C
+const TEMP_LSB_PER_C: f64 = 256.0;
+const TEMP_ZERO_C: f64 = 25.0;
+
+/// How often the FIFO should be read out. Short enough to leave plenty of headroom before an
+/// overrun, long enough to keep the I2C transactions efficient.
+const READOUT_INTERVAL_MS: u32 = 25;
+
+/// Output data rate of accelerometer, gyroscope and FIFO
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Odr {
+	Hz12_5,
+	Hz26,
+	Hz52,
+	Hz104,
+	Hz208,
+	Hz416,
+	Hz833,
+	Hz1660,
+	Hz3330,
+	Hz6660,
+}
+
+impl Odr {
+	/// ODR_XL, ODR_G and ODR_FIFO share the same encoding
+	pub const fn bits(self) -> u8 {
+		match self {
+			Odr::Hz12_5 => 0b0001,
+			Odr::Hz26 => 0b0010,
+			Odr::Hz52 => 0b0011,
+			Odr::Hz104 => 0b0100,
+			Odr::Hz208 => 0b0101,
+			Odr::Hz416 => 0b0110,
+			Odr::Hz833 => 0b0111,
+			Odr::Hz1660 => 0b1000,
+			Odr::Hz3330 => 0b1001,
+			Odr::Hz6660 => 0b1010,
+		}
+	}
+
+	pub const fn millihertz(self) -> u32 {
+		match self {
+			Odr::Hz12_5 => 12_500,
+			Odr::Hz26 => 26_000,
+			Odr::Hz52 => 52_000,
+			Odr::Hz104 => 104_000,
+			Odr::Hz208 => 208_000,
+			Odr::Hz416 => 416_000,
+			Odr::Hz833 => 833_000,
+			Odr::Hz1660 => 1_660_000,
+			Odr::Hz3330 => 3_330_000,
+			Odr::Hz6660 => 6_660_000,
+		}
+	}
+}
+
+/// Accelerometer full scale
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccelScale {
+	G2,
+	G4,
+	G8,
+	G16,
+}
+
+impl AccelScale {
+	/// FS_XL, note that 16g sits between 2g and 4g
+	pub const fn bits(self) -> u8 {
+		match self {
+			AccelScale::G2 => 0b00,
+			AccelScale::G16 => 0b01,
+			AccelScale::G4 => 0b10,
+			AccelScale::G8 => 0b11,
+		}
+	}
+
+	pub const fn range_g(self) -> u16 {
+		match self {
+			AccelScale::G2 => 2,
+			AccelScale::G4 => 4,
+			AccelScale::G8 => 8,
+			AccelScale::G16 => 16,
+		}
+	}
+
+	/// Linear acceleration sensitivity in g/LSB
+	pub const fn g_per_lsb(self) -> f64 {
+		match self {
+			AccelScale::G2 => 0.061e-3,
+			AccelScale::G4 => 0.122e-3,
+			AccelScale::G8 => 0.244e-3,
+			AccelScale::G16 => 0.488e-3,
+		}
+	}
+}
+
+/// Gyroscope full scale
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GyroScale {
+	Dps125,
+	Dps250,
+	Dps500,
+	Dps1000,
+	Dps2000,
+}
+
+impl GyroScale {
+	/// FS_G[1:0] and FS_125 as they sit in CTRL2_G[3:1]
+	pub const fn bits(self) -> u8 {
+		match self {
+			GyroScale::Dps125 => 0b001,
+			GyroScale::Dps250 => 0b000,
+			GyroScale::Dps500 => 0b010,
+			GyroScale::Dps1000 => 0b100,
+			GyroScale::Dps2000 => 0b110,
+		}
+	}
+
+	pub const fn range_dps(self) -> u16 {
+		match self {
+			GyroScale::Dps125 => 125,
+			GyroScale::Dps250 => 250,
+			GyroScale::Dps500 => 500,
+			GyroScale::Dps1000 => 1000,
+			GyroScale::Dps2000 => 2000,
+		}
+	}
+
+	/// Angular rate sensitivity in dps/LSB
+	pub const fn dps_per_lsb(self) -> f64 {
+		match self {
+			GyroScale::Dps125 => 4.375e-3,
+			GyroScale::Dps250 => 8.75e-3,
+			GyroScale::Dps500 => 17.5e-3,
+			GyroScale::Dps1000 => 35e-3,
+			GyroScale::Dps2000 => 70e-3,
+		}
+	}
+}
+
+/// Optionally puts the IMU's timestamp counter into the FIFO as fourth data set, so every
+/// sample carries the time it was taken
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FifoTimestamps {
+	Off,
+	/// 25µs per LSB, wraps every 419s
+	Fine,
+	/// 6.4ms per LSB, wraps every 29.8h
+	Coarse,
+}
+
+impl FifoTimestamps {
+	pub const fn lsb_ns(self) -> u32 {
+		match self {
+			FifoTimestamps::Off => 0,
+			FifoTimestamps::Fine => 25_000,
+			FifoTimestamps::Coarse => 6_400_000,
+		}
+	}
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ImuConfig {
+	pub odr: Odr,
+	pub accel: AccelScale,
+	pub gyro: GyroScale,
+	pub timestamps: FifoTimestamps,
+	/// Store the die temperature with every sample. It isn't in the FIFO, OUT_TEMP is read
+	/// once per batch.
+	pub temperature: bool,
+}
+
+impl Default for ImuConfig {
+	fn default() -> Self {
+		Self::DEFAULT
+	}
+}
+
+impl ImuConfig {
+	/// What the loggers shipped with before the range was configurable
+	pub const DEFAULT: Self = Self {
+		odr: Odr::Hz1660,
+		accel: AccelScale::G2,
+		gyro: GyroScale::Dps250,
+		timestamps: FifoTimestamps::Off,
+		temperature: false,
+	};
+
+	/// Bytes per sample set in the FIFO: gyro and accel, plus the timestamp data set
+	pub const fn fifo_set_len(&self) -> usize {
+		match self.timestamps {
+			FifoTimestamps::Off => ImuSample::LEN,
+			_ => ImuSample::LEN + TIMESTAMP_LEN,
+		}
+	}
+
+	/// What a sample holds once read out
+	pub const fn layout(&self) -> SampleLayout {
+		SampleLayout {
+			mag: false,
+			timestamp: !matches!(self.timestamps, FifoTimestamps::Off),
+			temperature: self.temperature,
+		}
+	}
+
+	/// Bytes per sample once read out, the FIFO set plus the temperature
+	pub const fn sample_len(&self) -> usize {
+		self.layout().sample_len()
+	}
+
+	pub const fn ctrl1_xl(&self) -> u8 {
+		self.odr.bits() << 4 | self.accel.bits() << 2
+	}
+
+	pub const fn ctrl2_g(&self) -> u8 {
+		self.odr.bits() << 4 | self.gyro.bits() << 1
+	}
+
+	/// FIFO at the sensor ODR in continuous mode
+	pub const fn fifo_ctrl5(&self) -> u8 {
+		self.odr.bits() << 3 | 0b110
+	}
+
+	/// FIFO threshold in 16 bit words, always whole sample sets
+	pub const fn watermark_words(&self) -> u16 {
+		let set_words = (self.fifo_set_len() / 2) as u32;
+		let per_readout = (self.odr.millihertz() * READOUT_INTERVAL_MS / 1_000_000) * set_words;
+		let max = (FIFO_BUFSIZE / 2 / 4) as u32;
+		let words = if per_readout > max { max } else { per_readout };
+		let sets = words / set_words;
+		(if sets == 0 { 1 } else { sets } * set_words) as u16
+	}
+
+	/// Watermark LSB
+	pub const fn fifo_ctrl1(&self) -> u8 {
+		self.watermark_words() as u8
+	}
+
+	/// Watermark MSB, timestamp as fourth data set
+	pub const fn fifo_ctrl2(&self) -> u8 {
+		let timer_pedo_fifo_en = match self.timestamps {
+			FifoTimestamps::Off => 0,
+			_ => 0b1000_0000,
+		};
+		timer_pedo_fifo_en | (self.watermark_words() >> 8) as u8 & 0b111
+	}
+
+	/// TIMER_HR, resolution of the timestamp counter
+	pub const fn wake_up_dur(&self) -> u8 {
+		match self.timestamps {
+			FifoTimestamps::Fine => 0b0001_0000,
+			_ => 0,
+		}
+	}
+
+	/// Nominal time between two samples
+	pub const fn sample_interval_micros(&self) -> f32 {
+		1e9 / self.odr.millihertz() as f32
+	}
+
+	pub const fn info(&self) -> SensorInfo {
+		SensorInfo {
+			model: "LSM6DS3TR-C",
+			odr_millihertz: self.odr.millihertz(),
+			gyro_range_dps: self.gyro.range_dps(),
+			gyro_dps_per_lsb: self.gyro.dps_per_lsb(),
+			accel_range_g: self.accel.range_g(),
+			accel_g_per_lsb: self.accel.g_per_lsb(),
+			hw_timestamp_ns: self.timestamps.lsb_ns(),
+			temp_c_per_lsb: if self.temperature { 1.0 / TEMP_LSB_PER_C } else { 0.0 },
+			temp_zero_c: TEMP_ZERO_C,
+			mag_ut_per_lsb: 0.0,
+		}
+	}
+
+	/// How this config is described in the log header
+	#[cfg(feature = "gyro_binary")]
+	pub const fn sensor_config(&self) -> SensorConfig {
+		self.info().sensor_config()
+	}
+}
+
+/// Driver for the IMU behind a switchable power rail, with the FIFO threshold on INT1
+pub struct Lsm6ds3<I2C, INT, PWR> {
+	i2c: I2C,
+	int1: INT,
+	power: PWR,
+	config: ImuConfig,
+	fifo_buf: [u8; FIFO_BUFSIZE],
+}
+
+impl<I2C: I2c, INT: Wait, PWR: OutputPin> Lsm6ds3<I2C, INT, PWR> {
+	/// Takes over the bus and pins, the IMU stays as it is until [`ImuDriver::init`]
+	pub const fn new(i2c: I2C, int1: INT, power: PWR) -> Self {
+		Self {
+			i2c,
+			int1,
+			power,
+			config: ImuConfig::DEFAULT,
+			fifo_buf: [0_u8; FIFO_BUFSIZE],
+		}
+	}
+
+	pub async fn fifo_status(&mut self) -> Result<FifoStatus, Error<I2C::Error>> {
+		let mut status = [0u8; 4];
+		self.i2c.write_read(ADDRESS, &[FIFO_STATUS1], &mut status).await?;
+		Ok(FifoStatus::from_registers(status))
+	}
+
+	/// Drops the FIFO contents and clears the overrun flag by going through bypass mode
+	pub async fn reset_fifo(&mut self) -> Result<(), Error<I2C::Error>> {
+		let continuous = self.config.fifo_ctrl5();
+		self.i2c.write(ADDRESS, &[FIFO_CTRL5, continuous & !FIFO_MODE_MASK]).await?;
+		self.i2c.write(ADDRESS, &[FIFO_CTRL5, continuous]).await?;
+		Ok(())
+	}
+
+	/// Gives back the bus and pins
+	pub fn release(self) -> (I2C, INT, PWR) {
+		(self.i2c, self.int1, self.power)
+	}
+}
+
+impl<I2C: I2c, INT: Wait, PWR: OutputPin> ImuDriver for Lsm6ds3<I2C, INT, PWR> {
+	type Config = ImuConfig;
+	type BusError = I2C::Error;
+
+	/// Power cycles the IMU and starts streaming into the FIFO
+	async fn init(&mut self, config: ImuConfig, delay: &mut impl DelayNs) -> Result<(), Error<I2C::Error>> {
+		// Ensure full reset and power discharge
+		self.power.set_low().map_err(|_| Error::Pin)?;
+		delay.delay_ms(200).await;
+		self.power.set_high().map_err(|_| Error::Pin)?;
+
+		// Let it boot, manual says 3ms, but our power Rail takes up to 300ms to rise (minimum)
+		delay.delay_ms(500).await;
+
+		let mut who_am_i = [0];
+		self.i2c.write_read(ADDRESS, &[WHO_AM_I], &mut who_am_i).await?;
+		if who_am_i[0] != WHO_AM_I_VALUE {
+			return Err(Error::WrongDevice(who_am_i[0]));
+		}
+
+		for cmd in init_sequence(&config) {
+			self.i2c.write(ADDRESS, &cmd).await?;
+		}
+		self.config = config;
+		Ok(())
+	}
+
+	/// INT1 stays high while the FIFO is above the watermark
+	async fn wait_data_ready(&mut self) -> Result<(), Error<I2C::Error>> {
+		self.int1.wait_for_high().await.map_err(|_| Error::Pin)
+	}
+
+	/// Words in front of the first whole set, according to the FIFO pattern, are dropped. A set
+	/// the IMU is still writing stays in the FIFO for the next readout.
+	///
+	/// After an overrun the FIFO no longer holds a continuous stream, it gets reset and
+	/// [`Error::Overrun`] returned instead.
+	///
+	/// With the temperature enabled, OUT_TEMP is read right after the FIFO and stored with
+	/// every sample of the batch. Only as many sets are read as fit the buffer once expanded.
+	async fn read_batch(&mut self) -> Result<SampleBatch<'_>, Error<I2C::Error>> {
+		let fifo_status = self.fifo_status().await?;
+		if fifo_status.overrun {
+			self.reset_fifo().await?;
+			return Err(Error::Overrun);
+		}
+
+		let set_len = self.config.fifo_set_len();
+		let sample_len = self.config.sample_len();
+		let set_words = set_len / 2;
+		let unread = fifo_status.unread_bytes() / 2;
+		let skip = (set_words - fifo_status.pattern as usize % set_words) % set_words;
+		let sets = (unread.saturating_sub(skip) / set_words).min((FIFO_BUFSIZE - skip * 2) / sample_len);
+		let words = if sets == 0 { 0 } else { skip + sets * set_words };
+
+		let data = &mut self.fifo_buf[..words * 2];
+		if !data.is_empty() {
+			// Important to read only exactly as much as needed, otherwise the FIFO goes haywire
+			self.i2c.write_read(ADDRESS, &[FIFO_DATA_OUT_L], data).await?;
+		}
+		let mut samples = skip.min(words) * 2..words * 2;
+		if self.config.temperature {
+			let mut temperature = [0u8; SampleLayout::TEMPERATURE_LEN];
+			if sets > 0 {
+				self.i2c.write_read(ADDRESS, &[OUT_TEMP_L], &mut temperature).await?;
+			}
+			// Spread the sets out back to front, a set never lands on one that hasn't moved yet
+			self.fifo_buf.copy_within(samples, 0);
+			for i in (0..sets).rev() {
+				self.fifo_buf.copy_within(i * set_len..(i + 1) * set_len, i * sample_len);
+				self.fifo_buf[i * sample_len + set_len..(i + 1) * sample_len].copy_from_slice(&temperature);
+			}
+			samples = 0..sets * sample_len;
+		}
+		let mut batch = SampleBatch::new(&self.fifo_buf[samples], self.config.layout());
+		batch.skipped_words = if sets == 0 { 0 } else { skip as u16 };
+		Ok(batch)
+	}
+
+	async fn power_off(&mut self) -> Result<(), Error<I2C::Error>> {
+		self.power.set_low().map_err(|_| Error::Pin)
+	}
+
+	fn info(&self) -> SensorInfo {
+		self.config.info()
+	}
+}
+
+/// Register writes that bring a freshly booted IMU into the given config
+pub fn init_sequence(config: &ImuConfig) -> Vec<[u8; 2], 12> {
+	let mut cmds: Vec<[u8; 2], 12> = Vec::new();
+	let _ = cmds.extend_from_slice(&[
+		[CTRL3_C, 0b01000100],              //         BDU=1, IF_INC=1
+		[FIFO_CTRL1, config.fifo_ctrl1()],  // Watermark LSB
+		[FIFO_CTRL2, config.fifo_ctrl2()],  // Watermark MSB, timestamp as 4th data set
+	]);
+	if config.timestamps != FifoTimestamps::Off {
+		let _ = cmds.extend_from_slice(&[
+			[FIFO_CTRL4, 0b00_001_000],     //    4th data set without decimation
+			[WAKE_UP_DUR, config.wake_up_dur()], // Timestamp resolution 25µs or 6.4ms
+			[CTRL10_C, 0b0010_0100],        //       TIMER_EN, FUNC_EN
+			[TIMESTAMP2_REG, 0xAA],         //        Reset timestamp counter
+		]);
+	}
+	let _ = cmds.extend_from_slice(&[
+		[FIFO_CTRL3, 0b00001001],           //      No decimation
+		[FIFO_CTRL5, config.fifo_ctrl5()],  //      ODR, Continuous mode
+		[INT1_CTRL, 0b00011000],            //       Route FIFO threshold  and overrun to INT1
+		[CTRL1_XL, config.ctrl1_xl()],      //        Accel ODR and full scale
+		[CTRL2_G, config.ctrl2_g()],        //         Gyro ODR and full scale
+	]);
+	cmds
+}
+
+/// The 24 bit counter from the timestamp data set: TS[15:8], TS[23:16], unused, TS[7:0] and
+/// the step counter
+pub fn decode_timestamp(set: &[u8]) -> u32 {
+	u32::from_le_bytes([set[3], set[0], set[1], 0])
+}
+
+/// FIFO_STATUS1 to FIFO_STATUS4
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct FifoStatus {
+	/// WaterM cleared, fewer words than the threshold
+	pub below_watermark: bool,
+	/// OVER_RUN, old data got overwritten
+	pub overrun: bool,
+	/// FIFO_FULL_SMART, the next ODR tick fills the FIFO
+	pub fifo_full_smart: bool,
+	/// FIFO_EMPTY
+	pub fifo_empty: bool,
+	/// FIFO_PATTERN, position of the next word to be read within a sample set
+	pub pattern: u16,
+	unread_bytes: u16,
+}
+
+impl FifoStatus {
+	pub fn from_registers(status: [u8; 4]) -> Self {
+		FifoStatus {
+			below_watermark: (status[1] & 0b_1000_0000) == 0,
+			overrun: (status[1] & 0b_0100_0000) != 0,
+			fifo_full_smart: (status[1] & 0b_0010_0000) != 0,
+			fifo_empty: (status[1] & 0b_0001_0000) != 0,
+			pattern: status[2] as u16 | ((status[3] & 0b11) as u16) << 8,
+			unread_bytes: ((status[0] as u16) | (((status[1] & 0x0F) as u16) << 8)) * 2, // It returns amount of words, each word is 16 bit
+		}
+	}
+
+	pub fn unread_bytes(&self) -> usize {
+		self.unread_bytes as usize
+	}
+}
@@ -0,0 +1,243 @@
+//! IMU chips the loggers can record from.
+//!
+//! Every chip gets a driver implementing [`ImuDriver`], so the recording loop doesn't care
+//! which one is fitted. Samples come out in the layout the binary log stores them in.
+
+#[cfg(feature = "gyro_binary")]
+use crate::gyro_format::binary::SensorConfig;
+use embedded_hal_async::delay::DelayNs;
+
+pub mod calibration;
+pub mod icm42688;
+pub mod lsm6ds3;
+
+/// Things that can go wrong talking to an IMU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error<E> {
+	I2c(E),
+	/// Power or interrupt pin failed
+	Pin,
+	/// Something else answered on the IMU's address, holds its WHO_AM_I
+	WrongDevice(u8),
+	/// The FIFO overflowed and was reset, everything since the last readout is gone. Sampling
+	/// continues, the next readout starts with a whole sample again.
+	Overrun,
+}
+
+impl<E> From<E> for Error<E> {
+	fn from(e: E) -> Self {
+		Error::I2c(e)
+	}
+}
+
+/// A FIFO based IMU that streams gyro and accel at a fixed rate
+#[allow(async_fn_in_trait)]
+pub trait ImuDriver {
+	/// Rate and full scales, in whatever the chip supports
+	type Config;
+	type BusError;
+
+	/// (Re)starts the IMU from scratch and begins sampling into the FIFO
+	async fn init(&mut self, config: Self::Config, delay: &mut impl DelayNs) -> Result<(), Error<Self::BusError>>;
+
+	/// Waits until the FIFO holds a batch worth reading
+	async fn wait_data_ready(&mut self) -> Result<(), Error<Self::BusError>>;
+
+	/// Takes all complete samples from the FIFO. The FIFO level is latched first thing, so the
+	/// newest sample is at most one sample interval older than the call.
+	async fn read_batch(&mut self) -> Result<SampleBatch<'_>, Error<Self::BusError>>;
+
+	async fn power_off(&mut self) -> Result<(), Error<Self::BusError>>;
+
+	/// What the current config records
+	fn info(&self) -> SensorInfo;
+}
+
+/// Sensor metadata for the log header and for converting raw values
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SensorInfo {
+	pub model: &'static str,
+	pub odr_millihertz: u32,
+	pub gyro_range_dps: u16,
+	pub gyro_dps_per_lsb: f64,
+	pub accel_range_g: u16,
+	pub accel_g_per_lsb: f64,
+	/// Length of one tick of the 24 bit timestamp counter stored with every sample, 0 if there
+	/// is none
+	pub hw_timestamp_ns: u32,
+	/// Die temperature stored with every sample in °C per LSB, 0 if there is none
+	pub temp_c_per_lsb: f64,
+	/// Temperature at a raw value of 0
+	pub temp_zero_c: f64,
+	/// Magnetometer stored with every sample in µT per LSB, 0 if there is none
+	pub mag_ut_per_lsb: f64,
+}
+
+impl SensorInfo {
+	/// About how many samples are taken in `micros`
+	pub const fn samples_in(&self, micros: u64) -> u32 {
+		((micros * self.odr_millihertz as u64 + 500_000_000) / 1_000_000_000) as u32
+	}
+
+	/// What every stored sample holds
+	pub const fn layout(&self) -> SampleLayout {
+		SampleLayout {
+			mag: self.mag_ut_per_lsb != 0.0,
+			timestamp: self.hw_timestamp_ns != 0,
+			temperature: self.temp_c_per_lsb != 0.0,
+		}
+	}
+
+	/// How this is described in the log header
+	#[cfg(feature = "gyro_binary")]
+	pub const fn sensor_config(&self) -> SensorConfig {
+		SensorConfig::new(
+			self.odr_millihertz,
+			self.gyro_range_dps,
+			self.gyro_dps_per_lsb,
+			self.accel_range_g,
+			self.accel_g_per_lsb,
+		)
+		.with_hw_timestamps(self.hw_timestamp_ns)
+		.with_temperature(self.temp_c_per_lsb, self.temp_zero_c)
+		.with_mag(self.mag_ut_per_lsb)
+	}
+}
+
+/// Data sets of a stored sample: gyro and accel, then whichever of these are recorded, in
+/// this order. It's the order of the LSM6DS3 FIFO with the sensor hub as third and the
+/// timestamp as fourth data set, the temperature is appended by the driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SampleLayout {
+	/// Magnetometer XYZ, three `i16`
+	pub mag: bool,
+	/// See [`lsm6ds3::decode_timestamp`]
+	pub timestamp: bool,
+	/// One `i16`
+	pub temperature: bool,
+}
+
+impl SampleLayout {
+	pub const MAG_LEN: usize = lsm6ds3::DATA_SET_LEN;
+	pub const TEMPERATURE_LEN: usize = 2;
+
+	/// Bytes per stored sample
+	pub const fn sample_len(&self) -> usize {
+		self.temperature_offset() + if self.temperature { Self::TEMPERATURE_LEN } else { 0 }
+	}
+
+	const fn timestamp_offset(&self) -> usize {
+		ImuSample::LEN + if self.mag { Self::MAG_LEN } else { 0 }
+	}
+
+	const fn temperature_offset(&self) -> usize {
+		self.timestamp_offset() + if self.timestamp { lsm6ds3::TIMESTAMP_LEN } else { 0 }
+	}
+
+	/// Decodes a stored sample, `set` is [`Self::sample_len`] bytes
+	pub fn sample(&self, set: &[u8]) -> ImuSample {
+		let word = |i: usize| i16::from_le_bytes([set[i], set[i + 1]]);
+		ImuSample {
+			mag: self.mag.then(|| [0, 2, 4].map(|i| word(ImuSample::LEN + i))),
+			temperature: self.temperature.then(|| word(self.temperature_offset())),
+			..ImuSample::from_le_bytes(set[..ImuSample::LEN].try_into().unwrap())
+		}
+	}
+
+	/// Raw timestamp counter of a stored sample, if there is one
+	pub fn timestamp(&self, set: &[u8]) -> Option<u32> {
+		self.timestamp.then(|| lsm6ds3::decode_timestamp(&set[self.timestamp_offset()..]))
+	}
+
+	/// Stores `sample` into `set`, which is [`Self::sample_len`] bytes. Channels the sample lacks are
+	/// stored as 0, the timestamp is left as it is.
+	pub fn encode(&self, sample: &ImuSample, set: &mut [u8]) {
+		set[..ImuSample::LEN].copy_from_slice(&sample.to_le_bytes());
+		if self.mag {
+			for (i, word) in sample.mag.unwrap_or_default().iter().enumerate() {
+				set[ImuSample::LEN + i * 2..ImuSample::LEN + i * 2 + 2].copy_from_slice(&word.to_le_bytes());
+			}
+		}
+		if self.temperature {
+			let offset = self.temperature_offset();
+			set[offset..offset + Self::TEMPERATURE_LEN].copy_from_slice(&sample.temperature.unwrap_or_default().to_le_bytes());
+		}
+	}
+}
+
+/// Whole samples from one FIFO readout, in log layout as described by a [`SampleLayout`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SampleBatch<'a> {
+	data: &'a [u8],
+	layout: SampleLayout,
+	/// Words of a partial sample that were thrown away to get back in step with the FIFO
+	pub skipped_words: u16,
+}
+
+impl<'a> SampleBatch<'a> {
+	pub fn new(data: &'a [u8], layout: SampleLayout) -> Self {
+		Self {
+			data,
+			layout,
+			skipped_words: 0,
+		}
+	}
+
+	/// The samples as they are written to the log
+	pub fn as_bytes(&self) -> &'a [u8] {
+		self.data
+	}
+
+	pub fn len(&self) -> usize {
+		self.data.len() / self.layout.sample_len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.data.is_empty()
+	}
+
+	pub fn samples(&self) -> impl Iterator<Item = ImuSample> + 'a {
+		let layout = self.layout;
+		self.data.chunks_exact(layout.sample_len()).map(move |set| layout.sample(set))
+	}
+
+	/// Timestamp counter of every sample, if they are recorded
+	pub fn timestamps(&self) -> Option<impl Iterator<Item = u32> + 'a> {
+		let layout = self.layout;
+		layout.timestamp.then(|| self.data.chunks_exact(layout.sample_len()).map(move |set| layout.timestamp(set).unwrap()))
+	}
+}
+
+/// One reading of gyroscope and accelerometer, raw LSB as the sensor reports them, with
+/// magnetometer and die temperature on setups that record them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ImuSample {
+	pub gyro: [i16; 3],
+	pub accel: [i16; 3],
+	pub mag: Option<[i16; 3]>,
+	pub temperature: Option<i16>,
+}
+
+impl ImuSample {
+	/// Size of the gyro and accel data sets that start every stored sample
+	pub const LEN: usize = 2 * lsm6ds3::DATA_SET_LEN;
+
+	/// Gyro XYZ followed by accel XYZ, little endian as in the log and the LSM6DS3 FIFO
+	pub fn from_le_bytes(bytes: &[u8; Self::LEN]) -> Self {
+		let word = |i: usize| i16::from_le_bytes([bytes[i * 2], bytes[i * 2 + 1]]);
+		Self {
+			gyro: [word(0), word(1), word(2)],
+			accel: [word(3), word(4), word(5)],
+			mag: None,
+			temperature: None,
+		}
+	}
+
+	pub fn to_le_bytes(&self) -> [u8; Self::LEN] {
+		let mut bytes = [0; Self::LEN];
+		for (i, word) in self.gyro.iter().chain(&self.accel).enumerate() {
+			bytes[i * 2..i * 2 + 2].copy_from_slice(&word.to_le_bytes());
+		}
+		bytes
+	}
+}
@@ -3,9 +3,13 @@
 use chrono::{DateTime, FixedOffset, NaiveDate, NaiveTime};
 
 pub mod display;
+pub mod fusion;
 pub mod time;
 pub mod sd_storage;
 pub mod gyro_format;
+pub mod imu;
+pub mod timing;
+pub mod utc;
 
 #[derive(Clone, Default)]
 pub struct DisplayState {
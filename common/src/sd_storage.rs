@@ -1 +1,593 @@
+//! Log files on the SD card.
+//!
+//! [`Storage`] brings up the card, opens the first FAT volume and hands out [`LogFile`]s. It
+//! is generic over `embedded_sdmmc`'s [`BlockDevice`], so the same code runs against a card on
+//! SPI and, in tests, against a [`MemoryDevice`].
+//!
+//! Logs are numbered across the whole card, `LOG00001.BIN` and up, and go into a `YYYYMMDD`
+//! folder for their UTC date when that is known. Nothing is ever truncated, a name that turns
+//! out to be taken is skipped.
+//!
+//! Recordings that outgrow a [`Rollover`] limit go on in the next log. FAT32 can't hold files
+//! of 4 GiB or more, and a card that dies mid recording takes less with it.
+//!
+//! A log file gathers writes up to the next block boundary before passing them on. The card
+//! then mostly sees whole 512 byte blocks, every partial one costs `embedded_sdmmc` a read of
+//! the block before it can be written.
+//!
+//! What reached the card but not the directory entry is still in the clusters the FAT chains
+//! to the file when power goes, it just isn't part of the file. A [`FlushPolicy`] bounds how
+//! much that is, [`LogFile::preallocate`] avoids it altogether.
 
+use chrono::{Datelike, NaiveDate};
+use core::fmt::{self, Debug, Display, Write};
+use embedded_hal_async::delay::DelayNs;
+use embedded_sdmmc::{
+	Block, BlockDevice, DirEntry, Mode, RawDirectory, RawFile, RawVolume, ShortFileName, TimeSource, VolumeIdx, VolumeManager,
+};
+use heapless::String;
+
+/// Size of a card block
+pub const BLOCK_LEN: usize = Block::LEN;
+/// Tries at reaching the card before giving up, some need a moment after power up
+pub const MOUNT_ATTEMPTS: u32 = 5;
+const MOUNT_RETRY_MS: u32 = 200;
+/// Highest log number, names have room for five digits
+pub const MAX_LOG_INDEX: u32 = 99_999;
+
+/// Things that can go wrong with the card
+#[derive(Debug, Clone)]
+pub enum Error<E: Debug> {
+	/// The card didn't answer in [`MOUNT_ATTEMPTS`] tries, holds the last error
+	NoCard(E),
+	/// A read or write failed
+	Device(E),
+	/// The first partition isn't FAT16 or FAT32
+	NoFilesystem,
+	/// A file by that name exists already, it is left alone. For logs: all numbers are taken.
+	Exists,
+	/// No free clusters left
+	Full,
+	/// Anything else `embedded_sdmmc` refused
+	Filesystem(embedded_sdmmc::Error<E>),
+}
+
+impl<E: Debug> From<embedded_sdmmc::Error<E>> for Error<E> {
+	fn from(e: embedded_sdmmc::Error<E>) -> Self {
+		match e {
+			embedded_sdmmc::Error::DeviceError(e) => Error::Device(e),
+			embedded_sdmmc::Error::FileAlreadyExists => Error::Exists,
+			embedded_sdmmc::Error::DiskFull | embedded_sdmmc::Error::NotEnoughSpace => Error::Full,
+			e => Error::Filesystem(e),
+		}
+	}
+}
+
+/// When the directory entry of a log file is brought up to date. Until then the data is on
+/// the card, but the entry still holds the old length, so after a power cut the file ends
+/// there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlushPolicy {
+	/// Only on closing, the fewest writes
+	OnClose,
+	/// Whenever this many bytes went to the card since the last update
+	EveryBytes(u32),
+	/// Whenever this many µs passed since the last update, going by [`LogFile::tick`]
+	EveryMicros(u64),
+}
+
+/// When a recording moves on to a new log, checked before every write that must not be split
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rollover {
+	max_len: u32,
+	max_micros: Option<u64>,
+}
+
+impl Rollover {
+	/// Only when the next write wouldn't fit into a FAT32 file anymore
+	pub const FAT32_LIMIT: Self = Self::new(embedded_sdmmc::MAX_FILE_SIZE);
+
+	/// Logs are kept to `max_len` bytes
+	pub const fn new(max_len: u32) -> Self {
+		Self { max_len, max_micros: None }
+	}
+
+	/// Logs also end after `micros` of recording
+	pub const fn with_max_duration(mut self, micros: u64) -> Self {
+		self.max_micros = Some(micros);
+		self
+	}
+
+	/// Whether a log that holds `len` bytes and was started `elapsed_micros` ago should be
+	/// closed before writing `next` more bytes. An empty log takes anything.
+	pub fn is_due(&self, len: u32, next: usize, elapsed_micros: u64) -> bool {
+		len > 0
+			&& (len as u64 + next as u64 > self.max_len as u64
+				|| self.max_micros.is_some_and(|max| elapsed_micros >= max))
+	}
+}
+
+/// Where a log went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogName {
+	/// UTC date the log was started on, names its folder
+	pub date: Option<NaiveDate>,
+	pub index: u32,
+}
+
+impl LogName {
+	pub fn file_name(&self) -> String<12> {
+		let mut name = String::new();
+		write!(name, "LOG{:05}.BIN", self.index).unwrap();
+		name
+	}
+
+	pub fn dir_name(&self) -> Option<String<8>> {
+		self.date.map(|date| {
+			let mut name = String::new();
+			write!(name, "{:04}{:02}{:02}", date.year(), date.month(), date.day()).unwrap();
+			name
+		})
+	}
+}
+
+impl Display for LogName {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		if let Some(dir) = self.dir_name() {
+			write!(f, "{dir}/")?;
+		}
+		f.write_str(&self.file_name())
+	}
+}
+
+/// A log just created and where it went
+pub type NewLog<'a, D, T> = (LogName, LogFile<'a, D, T>);
+
+/// Number of a `LOGnnnnn.BIN` file
+fn log_index(entry: &DirEntry) -> Option<u32> {
+	let digits = entry.name.base_name().strip_prefix(b"LOG")?;
+	let is_log = !entry.attributes.is_directory() && entry.name.extension() == b"BIN" && is_number(digits, 5);
+	is_log.then(|| digits.iter().fold(0, |index, digit| index * 10 + (digit - b'0') as u32))
+}
+
+/// Name of a `YYYYMMDD` folder
+fn date_dir(entry: &DirEntry) -> Option<[u8; 8]> {
+	let name = entry.name.base_name();
+	let is_date = entry.attributes.is_directory() && entry.name.extension().is_empty() && is_number(name, 8);
+	is_date.then(|| name.try_into().unwrap())
+}
+
+fn is_number(digits: &[u8], len: usize) -> bool {
+	digits.len() == len && digits.iter().all(u8::is_ascii_digit)
+}
+
+/// The first volume of a card, with its root directory open
+pub struct Storage<D: BlockDevice, T: TimeSource> {
+	volume_mgr: VolumeManager<D, T>,
+	volume: RawVolume,
+	root: RawDirectory,
+	blocks: u32,
+}
+
+impl<D: BlockDevice, T: TimeSource> Storage<D, T> {
+	/// Brings up the card and opens the first volume. `embedded_sdmmc` initializes the card on
+	/// first access and again on the next one after a failure, so a card that isn't ready yet
+	/// is simply asked again a little later.
+	pub async fn mount(device: D, time_source: T, delay: &mut impl DelayNs) -> Result<Self, Error<D::Error>> {
+		let mut attempt = 1;
+		let blocks = loop {
+			match device.num_blocks() {
+				Ok(blocks) => break blocks.0,
+				Err(e) if attempt == MOUNT_ATTEMPTS => return Err(Error::NoCard(e)),
+				Err(_) => {
+					attempt += 1;
+					delay.delay_ms(MOUNT_RETRY_MS).await;
+				}
+			}
+		};
+
+		let volume_mgr = VolumeManager::new(device, time_source);
+		let volume = volume_mgr.open_raw_volume(VolumeIdx(0)).map_err(|e| match e {
+			embedded_sdmmc::Error::FormatError(_) | embedded_sdmmc::Error::NoSuchVolume => Error::NoFilesystem,
+			e => e.into(),
+		})?;
+		let root = volume_mgr.open_root_dir(volume)?;
+		Ok(Self { volume_mgr, volume, root, blocks })
+	}
+
+	/// Size of the whole card in bytes
+	pub fn card_size(&self) -> u64 {
+		self.blocks as u64 * BLOCK_LEN as u64
+	}
+
+	/// Creates a file to write a log to, in directory `dir` if given, which is created below
+	/// the root when missing. Existing files are never truncated, that's [`Error::Exists`].
+	pub fn create(&self, dir: Option<&str>, name: &str, flush: FlushPolicy) -> Result<LogFile<'_, D, T>, Error<D::Error>> {
+		let file = match dir {
+			Some(dir) => {
+				let dir = self.open_dir(dir)?;
+				let file = self.volume_mgr.open_file_in_dir(dir, name, Mode::ReadWriteCreate);
+				self.volume_mgr.close_dir(dir)?;
+				file?
+			}
+			None => self.volume_mgr.open_file_in_dir(self.root, name, Mode::ReadWriteCreate)?,
+		};
+		Ok(LogFile {
+			storage: self,
+			file,
+			closed: false,
+			buffer: [0; BLOCK_LEN],
+			buffered: 0,
+			written: 0,
+			unflushed: 0,
+			flush,
+			flushed_at: None,
+		})
+	}
+
+	/// Creates the next log, numbered one past the highest `LOGnnnnn.BIN` anywhere on the card,
+	/// in the folder for `date` if given
+	pub fn create_log(&self, date: Option<NaiveDate>, flush: FlushPolicy) -> Result<NewLog<'_, D, T>, Error<D::Error>> {
+		let mut name = LogName { date, index: self.highest_log_index()? + 1 };
+		while name.index <= MAX_LOG_INDEX {
+			match self.create(name.dir_name().as_deref(), &name.file_name(), flush) {
+				// Something that isn't a log took the name
+				Err(Error::Exists) => name.index += 1,
+				result => return result.map(|file| (name, file)),
+			}
+		}
+		Err(Error::Exists)
+	}
+
+	/// Highest log number in the root and in the date folders, 0 without logs
+	fn highest_log_index(&self) -> Result<u32, Error<D::Error>> {
+		let mut highest = self.highest_log_index_in(self.root)?;
+		// A folder can't be opened while iterating, so each round finds the next one by name
+		let mut last = None;
+		loop {
+			let mut next = None;
+			self.volume_mgr.iterate_dir(self.root, |entry| {
+				if let Some(date) = date_dir(entry)
+					&& last.is_none_or(|last| date > last)
+					&& next.is_none_or(|next| date < next)
+				{
+					next = Some(date);
+				}
+			})?;
+			let Some(date) = next else { return Ok(highest) };
+			let name = ShortFileName::create_from_str(core::str::from_utf8(&date).unwrap()).unwrap();
+			let dir = self.volume_mgr.open_dir(self.root, &name)?;
+			let found = self.highest_log_index_in(dir);
+			self.volume_mgr.close_dir(dir)?;
+			highest = highest.max(found?);
+			last = next;
+		}
+	}
+
+	fn highest_log_index_in(&self, dir: RawDirectory) -> Result<u32, Error<D::Error>> {
+		let mut highest = 0;
+		self.volume_mgr.iterate_dir(dir, |entry| {
+			highest = highest.max(log_index(entry).unwrap_or(0));
+		})?;
+		Ok(highest)
+	}
+
+	/// Opens a directory below the root, creating it if missing
+	fn open_dir(&self, name: &str) -> Result<RawDirectory, Error<D::Error>> {
+		match self.volume_mgr.make_dir_in_dir(self.root, name) {
+			Ok(()) | Err(embedded_sdmmc::Error::DirAlreadyExists) => {}
+			Err(e) => return Err(e.into()),
+		}
+		Ok(self.volume_mgr.open_dir(self.root, name)?)
+	}
+
+	/// Closes the volume and hands back the card
+	pub fn unmount(self) -> Result<D, Error<D::Error>> {
+		self.volume_mgr.close_dir(self.root)?;
+		self.volume_mgr.close_volume(self.volume)?;
+		Ok(self.volume_mgr.free().0)
+	}
+}
+
+/// A log being written. Close it with [`LogFile::close`], dropping it closes it as well but
+/// loses any error.
+pub struct LogFile<'a, D: BlockDevice, T: TimeSource> {
+	storage: &'a Storage<D, T>,
+	file: RawFile,
+	closed: bool,
+	/// Bytes up to the next block boundary
+	buffer: [u8; BLOCK_LEN],
+	buffered: usize,
+	/// Bytes passed on to the card
+	written: u32,
+	/// Bytes passed on since the directory entry was last updated
+	unflushed: u32,
+	flush: FlushPolicy,
+	/// Time of the last update, as given to [`LogFile::tick`]
+	flushed_at: Option<u64>,
+}
+
+impl<D: BlockDevice, T: TimeSource> LogFile<'_, D, T> {
+	/// Bytes written so far, buffered ones included
+	pub fn len(&self) -> u32 {
+		self.written + self.buffered as u32
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	pub fn write(&mut self, mut data: &[u8]) -> Result<(), Error<D::Error>> {
+		while !data.is_empty() {
+			let room = BLOCK_LEN - self.written as usize % BLOCK_LEN - self.buffered;
+			if room == BLOCK_LEN && data.len() >= BLOCK_LEN {
+				// Whole blocks go straight through
+				let whole = data.len() - data.len() % BLOCK_LEN;
+				self.pass_on(&data[..whole])?;
+				data = &data[whole..];
+				continue;
+			}
+			let taken = room.min(data.len());
+			self.buffer[self.buffered..self.buffered + taken].copy_from_slice(&data[..taken]);
+			self.buffered += taken;
+			data = &data[taken..];
+			if taken == room {
+				self.write_buffer()?;
+			}
+		}
+		match self.flush {
+			FlushPolicy::EveryBytes(bytes) if self.unflushed >= bytes => self.flush(),
+			_ => Ok(()),
+		}
+	}
+
+	/// Tells the file the time in µs, to update the directory entry by
+	/// [`FlushPolicy::EveryMicros`]. The first call starts the clock.
+	pub fn tick(&mut self, now_micros: u64) -> Result<(), Error<D::Error>> {
+		let FlushPolicy::EveryMicros(micros) = self.flush else { return Ok(()) };
+		let flushed_at = *self.flushed_at.get_or_insert(now_micros);
+		if now_micros.saturating_sub(flushed_at) < micros {
+			return Ok(());
+		}
+		self.flushed_at = Some(now_micros);
+		self.flush()
+	}
+
+	/// Makes the file `len` bytes of zeros up front, for an empty file only. The data written
+	/// later takes their place, so it is part of the file as soon as it reaches the card, flushed
+	/// or not, and the FAT isn't touched until the file outgrows them. Clusters are taken in
+	/// order from the next free one, on a card that isn't fragmented they are contiguous.
+	///
+	/// This takes as long as writing `len` bytes. The file keeps its length if less gets
+	/// written, readers have to take trailing zeros as the end.
+	pub fn preallocate(&mut self, len: u32) -> Result<(), Error<D::Error>> {
+		if !self.is_empty() {
+			return Err(Error::Filesystem(embedded_sdmmc::Error::InvalidOffset));
+		}
+		let volume_mgr = &self.storage.volume_mgr;
+		let zeros = [0; BLOCK_LEN];
+		let mut left = len as usize;
+		while left > 0 {
+			let chunk = left.min(BLOCK_LEN);
+			volume_mgr.write(self.file, &zeros[..chunk])?;
+			left -= chunk;
+		}
+		volume_mgr.flush_file(self.file)?;
+		volume_mgr.file_seek_from_start(self.file, 0)?;
+		Ok(())
+	}
+
+	/// Puts buffered bytes on the card and updates the directory entry, everything written so
+	/// far survives a power cut after this
+	pub fn flush(&mut self) -> Result<(), Error<D::Error>> {
+		self.write_buffer()?;
+		self.storage.volume_mgr.flush_file(self.file)?;
+		self.unflushed = 0;
+		Ok(())
+	}
+
+	/// Replaces bytes written before, to fill in a header once more is known. The bytes must
+	/// not reach past what was written so far.
+	pub fn overwrite(&mut self, offset: u32, data: &[u8]) -> Result<(), Error<D::Error>> {
+		if offset as usize + data.len() > self.len() as usize {
+			return Err(Error::Filesystem(embedded_sdmmc::Error::InvalidOffset));
+		}
+		self.write_buffer()?;
+		let volume_mgr = &self.storage.volume_mgr;
+		volume_mgr.file_seek_from_start(self.file, offset)?;
+		volume_mgr.write(self.file, data)?;
+		// Not the end of the file if it was preallocated
+		volume_mgr.file_seek_from_start(self.file, self.written)?;
+		Ok(())
+	}
+
+	/// Writes out what is left and closes the file
+	pub fn close(mut self) -> Result<(), Error<D::Error>> {
+		self.finish()
+	}
+
+	fn finish(&mut self) -> Result<(), Error<D::Error>> {
+		if self.closed {
+			return Ok(());
+		}
+		self.closed = true;
+		let written = self.write_buffer();
+		// Closing updates the directory entry, worth doing even if the last write failed
+		let closed = self.storage.volume_mgr.close_file(self.file);
+		written.and(closed.map_err(Error::from))
+	}
+
+	fn write_buffer(&mut self) -> Result<(), Error<D::Error>> {
+		if self.buffered > 0 {
+			let buffered = core::mem::take(&mut self.buffered);
+			let buffer = self.buffer;
+			self.pass_on(&buffer[..buffered])?;
+		}
+		Ok(())
+	}
+
+	fn pass_on(&mut self, data: &[u8]) -> Result<(), Error<D::Error>> {
+		self.storage.volume_mgr.write(self.file, data)?;
+		self.written += data.len() as u32;
+		self.unflushed += data.len() as u32;
+		Ok(())
+	}
+}
+
+impl<D: BlockDevice, T: TimeSource> Drop for LogFile<'_, D, T> {
+	fn drop(&mut self) {
+		let _ = self.finish();
+	}
+}
+
+#[cfg(feature = "std")]
+pub use memory::{MemoryDevice, OutOfRange};
+
+#[cfg(feature = "std")]
+mod memory {
+	use super::BLOCK_LEN;
+	use core::cell::RefCell;
+	use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+	use std::collections::BTreeMap;
+
+	/// Partition start, cards come with their first partition aligned to 4 MiB
+	const PARTITION_START: u32 = 8192;
+	const RESERVED_BLOCKS: u32 = 32;
+	const FSINFO_BLOCK: u32 = 1;
+	const BACKUP_BOOT_BLOCK: u32 = 6;
+	/// FAT32 needs at least 65525 clusters
+	const MIN_CLUSTERS: u32 = 65525;
+
+	/// A card in RAM. Blocks never written read as zeros and take no memory, so it can be as
+	/// large as a real card.
+	#[derive(Debug, Clone)]
+	pub struct MemoryDevice {
+		blocks: RefCell<BTreeMap<u32, [u8; BLOCK_LEN]>>,
+		num_blocks: u32,
+	}
+
+	/// Access past the end of a [`MemoryDevice`], holds the block index
+	#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+	pub struct OutOfRange(pub u32);
+
+	impl MemoryDevice {
+		/// An all zero card of `bytes`, without partition table
+		pub fn new(bytes: u64) -> Self {
+			Self { blocks: RefCell::default(), num_blocks: (bytes / BLOCK_LEN as u64) as u32 }
+		}
+
+		/// A card of `bytes` formatted as it comes from the shop: an MBR with one FAT32
+		/// partition, clusters as large as the size allows up to 32 KiB
+		pub fn fat32(bytes: u64) -> Self {
+			let card = Self::new(bytes);
+			let total = card.num_blocks - PARTITION_START;
+			let mut cluster_blocks = 64;
+			while cluster_blocks > 1 && total / cluster_blocks < MIN_CLUSTERS + 1024 {
+				cluster_blocks /= 2;
+			}
+			let fat_blocks = ((total - RESERVED_BLOCKS) / cluster_blocks + 2).div_ceil(BLOCK_LEN as u32 / 4);
+			let clusters = (total - RESERVED_BLOCKS - 2 * fat_blocks) / cluster_blocks;
+			assert!(clusters >= MIN_CLUSTERS, "{bytes} bytes are too small for FAT32");
+
+			let mut mbr = [0; BLOCK_LEN];
+			let partition = &mut mbr[446..462];
+			// Type FAT32 with LBA
+			partition[4] = 0x0C;
+			partition[8..12].copy_from_slice(&PARTITION_START.to_le_bytes());
+			partition[12..16].copy_from_slice(&total.to_le_bytes());
+			mbr[510..].copy_from_slice(&[0x55, 0xAA]);
+			card.write_block(0, &mbr);
+
+			let mut boot = [0; BLOCK_LEN];
+			boot[..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+			boot[3..11].copy_from_slice(b"TRACCAM ");
+			boot[11..13].copy_from_slice(&(BLOCK_LEN as u16).to_le_bytes());
+			boot[13] = cluster_blocks as u8;
+			boot[14..16].copy_from_slice(&(RESERVED_BLOCKS as u16).to_le_bytes());
+			// Two FATs, no fixed root directory, no 16 bit size
+			boot[16] = 2;
+			boot[21] = 0xF8;
+			boot[24..26].copy_from_slice(&63_u16.to_le_bytes());
+			boot[26..28].copy_from_slice(&255_u16.to_le_bytes());
+			boot[28..32].copy_from_slice(&PARTITION_START.to_le_bytes());
+			boot[32..36].copy_from_slice(&total.to_le_bytes());
+			boot[36..40].copy_from_slice(&fat_blocks.to_le_bytes());
+			// Root directory in cluster 2
+			boot[44..48].copy_from_slice(&2_u32.to_le_bytes());
+			boot[48..50].copy_from_slice(&(FSINFO_BLOCK as u16).to_le_bytes());
+			boot[50..52].copy_from_slice(&(BACKUP_BOOT_BLOCK as u16).to_le_bytes());
+			boot[64] = 0x80;
+			boot[66] = 0x29;
+			boot[67..71].copy_from_slice(&0x7CA0_CA11_u32.to_le_bytes());
+			boot[71..82].copy_from_slice(b"NO NAME    ");
+			boot[82..90].copy_from_slice(b"FAT32   ");
+			boot[510..].copy_from_slice(&[0x55, 0xAA]);
+
+			let mut info = [0; BLOCK_LEN];
+			info[..4].copy_from_slice(&0x4161_5252_u32.to_le_bytes());
+			info[484..488].copy_from_slice(&0x6141_7272_u32.to_le_bytes());
+			// The root directory took the first cluster
+			info[488..492].copy_from_slice(&(clusters - 1).to_le_bytes());
+			info[492..496].copy_from_slice(&3_u32.to_le_bytes());
+			info[508..].copy_from_slice(&0xAA55_0000_u32.to_le_bytes());
+
+			for copy in [0, BACKUP_BOOT_BLOCK] {
+				card.write_block(PARTITION_START + copy, &boot);
+				card.write_block(PARTITION_START + copy + FSINFO_BLOCK, &info);
+			}
+
+			// Media descriptor, reserved entry and the root directory's end of chain
+			let mut fat = [0; BLOCK_LEN];
+			fat[..4].copy_from_slice(&0x0FFF_FFF8_u32.to_le_bytes());
+			fat[4..8].copy_from_slice(&0x0FFF_FFFF_u32.to_le_bytes());
+			fat[8..12].copy_from_slice(&0x0FFF_FFFF_u32.to_le_bytes());
+			for first in [RESERVED_BLOCKS, RESERVED_BLOCKS + fat_blocks] {
+				card.write_block(PARTITION_START + first, &fat);
+			}
+			card
+		}
+
+		pub fn read_block(&self, index: u32) -> [u8; BLOCK_LEN] {
+			self.blocks.borrow().get(&index).copied().unwrap_or([0; BLOCK_LEN])
+		}
+
+		pub fn write_block(&self, index: u32, data: &[u8; BLOCK_LEN]) {
+			let mut blocks = self.blocks.borrow_mut();
+			match data.iter().all(|&b| b == 0) {
+				true => blocks.remove(&index),
+				false => blocks.insert(index, *data),
+			};
+		}
+
+		fn check(&self, start: BlockIdx, count: usize) -> Result<(), OutOfRange> {
+			match start.0 as u64 + count as u64 <= self.num_blocks as u64 {
+				true => Ok(()),
+				false => Err(OutOfRange(start.0)),
+			}
+		}
+	}
+
+	impl BlockDevice for MemoryDevice {
+		type Error = OutOfRange;
+
+		fn read(&self, blocks: &mut [Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+			self.check(start_block_idx, blocks.len())?;
+			for (i, block) in blocks.iter_mut().enumerate() {
+				block.contents = self.read_block(start_block_idx.0 + i as u32);
+			}
+			Ok(())
+		}
+
+		fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), Self::Error> {
+			self.check(start_block_idx, blocks.len())?;
+			for (i, block) in blocks.iter().enumerate() {
+				self.write_block(start_block_idx.0 + i as u32, &block.contents);
+			}
+			Ok(())
+		}
+
+		fn num_blocks(&self) -> Result<BlockCount, Self::Error> {
+			Ok(BlockCount(self.num_blocks))
+		}
+	}
+}
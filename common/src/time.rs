@@ -12,6 +12,11 @@ impl DisplayState {
 		self.time = t;
 	}
 
+	/// GPS UTC date and time, None until the receiver has reported a date
+	pub fn utc(&self) -> Option<NaiveDateTime> {
+		(self.date != NaiveDate::default()).then(|| NaiveDateTime::new(self.date, self.time))
+	}
+
 	pub fn now_utc(&self) -> DateTime<FixedOffset> {
 		DateTime::<Utc>::from_naive_utc_and_offset(NaiveDateTime::new(self.date, self.time), Utc).with_timezone(&FixedOffset::east_opt(0).expect("Infallible. UTC."))
 	}
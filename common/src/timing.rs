@@ -0,0 +1,182 @@
+//! Reconstruction of real sample times from batch timestamps.
+//!
+//! The IMU's output data rate is only nominal, it can be off by a few percent and drifts with
+//! temperature. Every FIFO batch is stamped with the MCU clock when it is read out, so fitting
+//! those stamps against the running sample count recovers the actual sample period.
+//!
+//! The same fit works for the IMU's own timestamp counter, to calibrate its ticks against the
+//! crystal of the MCU.
+
+/// Time base of a run of consecutive samples, in timestamp ticks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampleClock {
+	/// Time of sample 0
+	pub origin: f64,
+	/// Time between two samples
+	pub period: f64,
+}
+
+impl SampleClock {
+	pub fn time_of(&self, index: u64) -> f64 {
+		self.origin + self.period * index as f64
+	}
+}
+
+/// Least squares fit of batch timestamps against the index of the newest sample in the batch
+#[derive(Debug, Clone, Default)]
+pub struct ClockFit {
+	// Sums are relative to the first point to keep f64 precision with large uptimes
+	first: Option<(u64, u64)>,
+	n: f64,
+	sum_x: f64,
+	sum_y: f64,
+	sum_xx: f64,
+	sum_xy: f64,
+}
+
+impl ClockFit {
+	pub const fn new() -> Self {
+		Self {
+			first: None,
+			n: 0.0,
+			sum_x: 0.0,
+			sum_y: 0.0,
+			sum_xx: 0.0,
+			sum_xy: 0.0,
+		}
+	}
+
+	/// Add a batch whose newest sample, number `last_index`, was captured at `timestamp`
+	pub fn add(&mut self, last_index: u64, timestamp: u64) {
+		let (x0, y0) = *self.first.get_or_insert((last_index, timestamp));
+		let x = last_index as f64 - x0 as f64;
+		let y = timestamp as f64 - y0 as f64;
+		self.n += 1.0;
+		self.sum_x += x;
+		self.sum_y += y;
+		self.sum_xx += x * x;
+		self.sum_xy += x * y;
+	}
+
+	/// Take back a batch that was added before, for sliding windows
+	pub fn remove(&mut self, last_index: u64, timestamp: u64) {
+		let Some((x0, y0)) = self.first else { return };
+		let x = last_index as f64 - x0 as f64;
+		let y = timestamp as f64 - y0 as f64;
+		self.n -= 1.0;
+		self.sum_x -= x;
+		self.sum_y -= y;
+		self.sum_xx -= x * x;
+		self.sum_xy -= x * y;
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.n == 0.0
+	}
+
+	/// Solve for the sample clock, falls back to `nominal_period` if there are too few batches
+	/// to measure it
+	pub fn solve(&self, nominal_period: f64) -> Option<SampleClock> {
+		let (x0, y0) = self.first?;
+		if self.is_empty() {
+			return None;
+		}
+		let mean_x = self.sum_x / self.n;
+		let mean_y = self.sum_y / self.n;
+		let var_x = self.sum_xx - self.sum_x * mean_x;
+
+		let period = if var_x > 0.0 {
+			(self.sum_xy - self.sum_x * mean_y) / var_x
+		} else {
+			nominal_period
+		};
+
+		let origin = y0 as f64 + mean_y - period * (x0 as f64 + mean_x);
+		Some(SampleClock { origin, period })
+	}
+}
+
+/// Fits a sample clock for every batch over its `radius` neighbours on either side, so slow
+/// drift of the rate (warm-up, ambient temperature) is followed instead of averaged out.
+///
+/// `batches` holds `(last index, timestamp)` like [`ClockFit::add`].
+pub struct WindowedFit<'a> {
+	batches: &'a [(u64, u64)],
+	radius: usize,
+	nominal_period: f64,
+	fit: ClockFit,
+	next: usize,
+	window: (usize, usize),
+}
+
+impl<'a> WindowedFit<'a> {
+	pub fn new(batches: &'a [(u64, u64)], radius: usize, nominal_period: f64) -> Self {
+		Self {
+			batches,
+			radius,
+			nominal_period,
+			fit: ClockFit::new(),
+			next: 0,
+			window: (0, 0),
+		}
+	}
+}
+
+impl Iterator for WindowedFit<'_> {
+	type Item = SampleClock;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.next >= self.batches.len() {
+			return None;
+		}
+		let lo = self.next.saturating_sub(self.radius);
+		let hi = (self.next + self.radius + 1).min(self.batches.len());
+		while self.window.1 < hi {
+			let (index, ts) = self.batches[self.window.1];
+			self.fit.add(index, ts);
+			self.window.1 += 1;
+		}
+		while self.window.0 < lo {
+			let (index, ts) = self.batches[self.window.0];
+			self.fit.remove(index, ts);
+			self.window.0 += 1;
+		}
+		self.next += 1;
+		self.fit.solve(self.nominal_period)
+	}
+}
+
+/// Extends a wrapping hardware counter into a monotonic count
+pub struct CounterUnwrapper {
+	modulus: u64,
+	last: Option<(u64, u64)>,
+}
+
+impl CounterUnwrapper {
+	pub const fn new(bits: u32) -> Self {
+		Self {
+			modulus: 1 << bits,
+			last: None,
+		}
+	}
+
+	/// Next reading, less than one full wrap after the previous one
+	pub fn next(&mut self, raw: u32) -> u64 {
+		self.resume(raw, 0)
+	}
+
+	/// Next reading after a gap of roughly `elapsed` counts, which may span several wraps
+	pub fn resume(&mut self, raw: u32, elapsed: u64) -> u64 {
+		let raw = raw as u64 % self.modulus;
+		let value = match self.last {
+			None => raw,
+			Some((last_raw, last)) => {
+				let delta = (raw + self.modulus - last_raw) % self.modulus;
+				let wraps = (elapsed.saturating_sub(delta) + self.modulus / 2) / self.modulus;
+				last + delta + wraps * self.modulus
+			}
+		};
+		self.last = Some((raw, value));
+		value
+	}
+}
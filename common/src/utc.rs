@@ -0,0 +1,151 @@
+//! Wall clock time for devices without a GPS of their own.
+//!
+//! The IMU logger listens for NMEA `ZDA` or `RMC` sentences on a serial line, which can come
+//! straight from a GPS module or from a host that types its clock into a terminal. The last
+//! received time is kept as a [`UtcAnchor`] against the monotonic clock.
+//!
+//! [`UtcClock`] turns the anchor into timestamps for the files on the SD card.
+
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, NaiveTime, Timelike};
+use embedded_sdmmc::{TimeSource, Timestamp};
+
+/// File timestamp used while no UTC time is known: 1980-01-01 00:00:00, the earliest date FAT can
+/// store, so such files stand out in any listing.
+pub const UNKNOWN_TIME: Timestamp = Timestamp {
+	year_since_1970: 10,
+	zero_indexed_month: 0,
+	zero_indexed_day: 0,
+	hours: 0,
+	minutes: 0,
+	seconds: 0,
+};
+
+/// A known UTC time together with the monotonic clock reading (µs) it was valid at
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UtcAnchor {
+	unix_micros: i64,
+	ticks: u64,
+}
+
+impl UtcAnchor {
+	pub fn new(utc: NaiveDateTime, ticks: u64) -> Self {
+		Self {
+			unix_micros: utc.and_utc().timestamp_micros(),
+			ticks,
+		}
+	}
+
+	/// Unix time in µs at the monotonic clock reading `ticks`
+	pub fn unix_micros_at(&self, ticks: u64) -> i64 {
+		self.unix_micros + (ticks as i64 - self.ticks as i64)
+	}
+
+	/// UTC at the monotonic clock reading `ticks`, None outside chrono's range
+	pub fn utc_at(&self, ticks: u64) -> Option<NaiveDateTime> {
+		DateTime::from_timestamp_micros(self.unix_micros_at(ticks)).map(|t| t.naive_utc())
+	}
+}
+
+/// An [`embedded_sdmmc::TimeSource`] that runs the monotonic clock from the last known UTC time.
+///
+/// `anchor` returns the latest [`UtcAnchor`], `ticks` reads the monotonic clock (µs) the anchors
+/// are taken against.
+pub struct UtcClock<A, M> {
+	anchor: A,
+	ticks: M,
+}
+
+impl<A: Fn() -> Option<UtcAnchor>, M: Fn() -> u64> UtcClock<A, M> {
+	pub fn new(anchor: A, ticks: M) -> Self {
+		Self { anchor, ticks }
+	}
+
+	/// Current UTC, None before the first time was received
+	pub fn now(&self) -> Option<NaiveDateTime> {
+		(self.anchor)()?.utc_at((self.ticks)())
+	}
+}
+
+impl<A: Fn() -> Option<UtcAnchor>, M: Fn() -> u64> TimeSource for UtcClock<A, M> {
+	fn get_timestamp(&self) -> Timestamp {
+		self.now().and_then(fat_timestamp).unwrap_or(UNKNOWN_TIME)
+	}
+}
+
+/// None outside the years FAT can store
+fn fat_timestamp(utc: NaiveDateTime) -> Option<Timestamp> {
+	if !(1980..=2107).contains(&utc.year()) {
+		return None;
+	}
+	Timestamp::from_calendar(
+		utc.year() as u16,
+		utc.month() as u8,
+		utc.day() as u8,
+		utc.hour() as u8,
+		utc.minute() as u8,
+		utc.second() as u8,
+	)
+	.ok()
+}
+
+/// Extracts the UTC date and time from a `ZDA` or `RMC` sentence of any talker.
+///
+/// The checksum is verified if present. Sentences without a valid fix are rejected.
+pub fn parse_nmea_utc(line: &str) -> Option<NaiveDateTime> {
+	let line = line.trim_end().strip_prefix('$')?;
+	let body = match line.split_once('*') {
+		Some((body, checksum)) => {
+			let expected = u8::from_str_radix(checksum, 16).ok()?;
+			if body.bytes().fold(0, |acc, b| acc ^ b) != expected {
+				return None;
+			}
+			body
+		}
+		None => line,
+	};
+
+	let mut fields = body.split(',');
+	let kind = fields.next()?;
+	match kind.get(2..)? {
+		"ZDA" => {
+			// hhmmss.ss,dd,mm,yyyy,zone hours,zone minutes
+			let time = parse_time(fields.next()?)?;
+			let day = fields.next()?.parse().ok()?;
+			let month = fields.next()?.parse().ok()?;
+			let year = fields.next()?.parse().ok()?;
+			Some(NaiveDate::from_ymd_opt(year, month, day)?.and_time(time))
+		}
+		"RMC" => {
+			// hhmmss.ss,status,lat,N/S,lon,E/W,speed,course,ddmmyy
+			let time = parse_time(fields.next()?)?;
+			if fields.next()? != "A" {
+				return None;
+			}
+			let date = fields.nth(6)?;
+			let day = date.get(0..2)?.parse().ok()?;
+			let month = date.get(2..4)?.parse().ok()?;
+			let year: i32 = date.get(4..6)?.parse().ok()?;
+			Some(NaiveDate::from_ymd_opt(2000 + year, month, day)?.and_time(time))
+		}
+		_ => None,
+	}
+}
+
+fn parse_time(field: &str) -> Option<NaiveTime> {
+	let (hms, frac) = field.split_once('.').unwrap_or((field, ""));
+	if hms.len() != 6 {
+		return None;
+	}
+	let hour = hms.get(0..2)?.parse().ok()?;
+	let minute = hms.get(2..4)?.parse().ok()?;
+	let second = hms.get(4..6)?.parse().ok()?;
+
+	let mut micros = 0;
+	for (i, digit) in frac.bytes().take(6).enumerate() {
+		if !digit.is_ascii_digit() {
+			return None;
+		}
+		micros += (digit - b'0') as u32 * 10_u32.pow(5 - i as u32);
+	}
+	NaiveTime::from_hms_micro_opt(hour, minute, second, micros)
+}
@@ -0,0 +1,349 @@
+use traccam_common::gyro_format::orientation::Orientation;
+use traccam_common::gyro_format::binary::{
+	BinGyroHeader, BlockEncoder, BlockHeader, Crc32, Error, Gap, GapTracker, Reader, SensorConfig,
+	SAMPLE_LEN, TIMESTAMP_LEN,
+};
+use traccam_common::imu::calibration::Calibration;
+use traccam_common::imu::{ImuSample, SampleLayout};
+use traccam_common::imu::lsm6ds3::ImuConfig;
+use zerocopy::IntoBytes;
+
+const SENSOR: SensorConfig = ImuConfig::DEFAULT.sensor_config();
+
+fn sample_bytes(samples: &[[i16; 6]]) -> Vec<u8> {
+	samples.iter().flatten().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+fn words(sample: ImuSample) -> [i16; 6] {
+	let [gx, gy, gz] = sample.gyro;
+	let [ax, ay, az] = sample.accel;
+	[gx, gy, gz, ax, ay, az]
+}
+
+fn encode(chunks: &[Vec<u8>]) -> Vec<u8> {
+	let mut out = BinGyroHeader::new(SENSOR).as_bytes().to_vec();
+	let mut encoder = BlockEncoder::new(&SENSOR);
+	for (i, chunk) in chunks.iter().enumerate() {
+		out.extend_from_slice(encoder.frame(i as u64 * 1000, chunk).as_bytes());
+		out.extend_from_slice(chunk);
+	}
+	out
+}
+
+#[test]
+fn header_round_trip() {
+	let data = encode(&[]);
+	assert_eq!(data.len(), BinGyroHeader::LEN);
+
+	let reader = Reader::new(data.as_slice()).unwrap();
+	assert_eq!(reader.header(), &BinGyroHeader::new(SENSOR));
+	assert_eq!(reader.header().sensor().odr_millihertz(), 1_660_000);
+	assert_eq!(reader.header().sensor().gyro_range_dps(), 250);
+	assert_eq!(reader.header().sensor().accel_range_g(), 2);
+	assert_eq!(reader.header().sensor().gyro_dps_per_lsb(), 8.75e-3);
+	assert_eq!(reader.header().sensor().accel_g_per_lsb(), 0.061e-3);
+	assert_eq!(reader.header().timescale(), 1_000_000);
+	assert_eq!(reader.header().gps_start_ts(), None);
+	assert_eq!(reader.header().orientation(), Orientation::IDENTITY);
+	assert_eq!(reader.count(), 0);
+}
+
+#[test]
+fn blocks_round_trip() {
+	let batches: Vec<Vec<[i16; 6]>> = (0..5)
+		.map(|b| (0..b * 7 + 1).map(|i| [i, -i, b, i16::MAX, i16::MIN, 42]).collect())
+		.collect();
+	let chunks: Vec<Vec<u8>> = batches.iter().map(|b| sample_bytes(b)).collect();
+
+	let blocks: Vec<_> = Reader::new(encode(&chunks).as_slice())
+		.unwrap()
+		.collect::<Result<_, _>>()
+		.unwrap();
+
+	assert_eq!(blocks.len(), batches.len());
+	for (i, (block, batch)) in blocks.iter().zip(&batches).enumerate() {
+		assert_eq!(block.header.sequence(), i as u32);
+		assert_eq!(block.header.timestamp(), i as u64 * 1000);
+		assert_eq!(block.header.sample_count() as usize, batch.len());
+		assert_eq!(block.samples().map(words).collect::<Vec<_>>(), *batch);
+	}
+}
+
+#[test]
+fn gps_start_round_trip() {
+	let header = BinGyroHeader::new(SENSOR).with_gps_start(1_792_238_400_000_000, 5_000_000);
+	let reader = Reader::new(header.as_bytes()).unwrap();
+	assert_eq!(reader.header().gps_start_ts().unwrap().get(), 1_792_238_400_000_000);
+	assert_eq!(reader.header().unix_micros_at(5_250_000.0), Some(1_792_238_400_250_000.0));
+	assert_eq!(BinGyroHeader::new(SENSOR).unix_micros_at(5_250_000.0), None);
+}
+
+#[test]
+fn calibration_round_trip() {
+	assert_eq!(BinGyroHeader::new(SENSOR).calibration(), None);
+	let calibration = Calibration { gyro_bias: [1.5, -20.25, 3.0], accel_offset: [0.0, -12.5, 80.0], samples: 833 };
+	let header = BinGyroHeader::new(SENSOR).with_calibration(&calibration);
+	let reader = Reader::new(header.as_bytes()).unwrap();
+	assert_eq!(reader.header().calibration(), Some(calibration));
+}
+
+#[test]
+fn segment_round_trip() {
+	let header = BinGyroHeader::new(SENSOR).with_gps_start(1_792_238_400_000_000, 5_000_000);
+	let first = Reader::new(header.clone().with_segment(421, 0).as_bytes()).unwrap().header().clone();
+	assert_eq!((first.session_id(), first.segment()), (421, 0));
+	assert!(!first.is_continuation());
+	assert!(!first.continues());
+
+	let closed = header.clone().with_segment(421, 0).with_next_segment();
+	assert!(Reader::new(closed.as_bytes()).unwrap().header().continues());
+	let second = Reader::new(header.clone().with_segment(421, 1).as_bytes()).unwrap().header().clone();
+	assert_eq!(second.segment(), 1);
+	assert!(second.is_continuation());
+
+	assert!(first.same_session(&second));
+	assert!(closed.same_session(&second));
+	assert!(!first.same_session(&header.clone().with_segment(422, 1)));
+	assert!(!first.same_session(&BinGyroHeader::new(SENSOR).with_segment(421, 1)));
+	let faster = SensorConfig::new(3_330_000, 250, 8.75e-3, 2, 0.061e-3);
+	assert!(!first.same_session(&BinGyroHeader::new(faster).with_gps_start(1_792_238_400_000_000, 5_000_000).with_segment(421, 1)));
+}
+
+#[test]
+fn orientation_round_trip() {
+	let mounted = Orientation::parse(b"yXZ").unwrap();
+	let header = BinGyroHeader::new(SENSOR).with_orientation(mounted);
+	let reader = Reader::new(header.as_bytes()).unwrap();
+	assert_eq!(reader.header().orientation(), mounted);
+
+	// A mirrored or garbled mounting makes the header unusable
+	let at = header.as_bytes().windows(3).position(|w| w == b"yXZ").unwrap();
+	for bad in [b"XYz", b"XXZ", b"ABC"] {
+		let mut data = header.as_bytes().to_vec();
+		data[at..at + 3].copy_from_slice(bad);
+		assert!(matches!(Reader::new(data.as_slice()), Err(Error::BadHeader)));
+	}
+}
+
+#[test]
+fn hw_timestamps_round_trip() {
+	let sensor = SENSOR.with_hw_timestamps(25_000);
+	assert_eq!(sensor.sample_len(), SAMPLE_LEN + TIMESTAMP_LEN);
+
+	let ticks = [0x00_0000, 0x12_3456, 0xFF_FFFF];
+	let mut chunk = vec![];
+	for (i, ts) in ticks.iter().enumerate() {
+		chunk.extend(sample_bytes(&[[i as i16; 6]]));
+		// As the FIFO outputs the fourth data set, including a step count of 0x0102
+		let [b0, b1, b2, _] = u32::to_le_bytes(*ts);
+		chunk.extend_from_slice(&[b1, b2, 0xEE, b0, 0x02, 0x01]);
+	}
+
+	let mut data = BinGyroHeader::new(sensor).as_bytes().to_vec();
+	data.extend_from_slice(BlockEncoder::new(&sensor).frame(0, &chunk).as_bytes());
+	data.extend_from_slice(&chunk);
+
+	let reader = Reader::new(data.as_slice()).unwrap();
+	assert_eq!(reader.header().sensor().hw_timestamp_ns().unwrap().get(), 25_000);
+	let block = reader.into_iter().next().unwrap().unwrap();
+	assert_eq!(block.header.sample_count(), 3);
+	assert_eq!(block.samples().map(words).collect::<Vec<_>>(), [[0; 6], [1; 6], [2; 6]]);
+	assert_eq!(block.hw_timestamps().unwrap().collect::<Vec<_>>(), ticks);
+
+	let plain = Reader::new(encode(&[sample_bytes(&[[1; 6]])]).as_slice()).unwrap().next().unwrap().unwrap();
+	assert!(plain.hw_timestamps().is_none());
+}
+
+#[test]
+fn mag_and_temperature_round_trip() {
+	let sensor = SENSOR.with_hw_timestamps(25_000).with_temperature(1.0 / 256.0, 25.0).with_mag(0.15);
+	let layout = sensor.layout();
+	assert_eq!(layout, SampleLayout { mag: true, timestamp: true, temperature: true });
+	assert_eq!(sensor.sample_len(), SAMPLE_LEN + 6 + TIMESTAMP_LEN + 2);
+
+	let samples: Vec<ImuSample> = (0..4)
+		.map(|i| ImuSample { gyro: [i; 3], accel: [-i; 3], mag: Some([i, 100 * i, -300]), temperature: Some(256 * i) })
+		.collect();
+	let mut chunk = vec![0; samples.len() * layout.sample_len()];
+	for (sample, set) in samples.iter().zip(chunk.chunks_exact_mut(layout.sample_len())) {
+		// The timestamp sits between magnetometer and temperature, as in the LSM6DS3 FIFO
+		set[SAMPLE_LEN + 6..][..TIMESTAMP_LEN].copy_from_slice(&[0, 0, 0, sample.gyro[0] as u8, 0, 0]);
+		layout.encode(sample, set);
+	}
+
+	let mut data = BinGyroHeader::new(sensor).as_bytes().to_vec();
+	data.extend_from_slice(BlockEncoder::new(&sensor).frame(0, &chunk).as_bytes());
+	data.extend_from_slice(&chunk);
+
+	let reader = Reader::new(data.as_slice()).unwrap();
+	let header = *reader.header().sensor();
+	assert_eq!(header.temperature_scale(), Some((1.0 / 256.0, 25.0)));
+	assert_eq!(header.mag_ut_per_lsb(), Some(0.15));
+	let block = reader.into_iter().next().unwrap().unwrap();
+	assert_eq!(block.samples().collect::<Vec<_>>(), samples);
+	assert_eq!(block.hw_timestamps().unwrap().collect::<Vec<_>>(), [0, 1, 2, 3]);
+	assert_eq!(header.temperature_c(&samples[2]), Some(27.0));
+	assert_eq!(header.mag_ut(&samples[1]), Some([0.15, 15.0, -45.0]));
+
+	// Logs without the channels leave them out of the samples
+	assert_eq!(SENSOR.temperature_scale(), None);
+	assert_eq!(SENSOR.temperature_c(&samples[2]), None);
+	assert_eq!(SENSOR.mag_ut(&samples[1]), None);
+}
+
+#[test]
+fn partial_samples_are_kept_in_payload() {
+	let mut chunk = sample_bytes(&[[1, 2, 3, 4, 5, 6]]);
+	chunk.extend_from_slice(&[7, 0, 8, 0]);
+
+	let block = Reader::new(encode(&[chunk.clone()]).as_slice()).unwrap().next().unwrap().unwrap();
+	assert_eq!(block.header.sample_count(), 1);
+	assert_eq!(block.payload, chunk);
+	assert_eq!(block.samples().count(), 1);
+}
+
+#[test]
+fn rejects_foreign_files() {
+	assert!(matches!(Reader::new(&b"GYROFLOW IMU LOG,\n"[..]), Err(Error::BadMagic)));
+	assert!(matches!(Reader::new(&[][..]), Err(Error::BadMagic)));
+
+	let mut data = encode(&[]);
+	data[8] = 0xFF;
+	assert!(matches!(Reader::new(data.as_slice()), Err(Error::UnsupportedVersion(0x00FF))));
+}
+
+#[test]
+fn reports_truncation() {
+	let data = encode(&[]);
+	assert!(matches!(Reader::new(&data[..BinGyroHeader::LEN - 1]), Err(Error::Truncated { .. })));
+
+	let data = encode(&[sample_bytes(&[[1; 6]; 4])]);
+	for cut in [BinGyroHeader::LEN + 1, BinGyroHeader::LEN + BlockHeader::LEN + SAMPLE_LEN] {
+		let mut reader = Reader::new(&data[..cut]).unwrap();
+		assert!(matches!(reader.next(), Some(Err(Error::Truncated { .. }))));
+		assert!(reader.next().is_none());
+	}
+}
+
+#[test]
+fn stops_at_preallocated_zeros() {
+	let chunks = [sample_bytes(&[[1; 6]; 4]), sample_bytes(&[[2; 6]; 3])];
+	let data = encode(&chunks);
+	for zeros in [1, BlockHeader::LEN - 1, BlockHeader::LEN, 10_000] {
+		let mut padded = data.clone();
+		padded.resize(data.len() + zeros, 0);
+		let blocks: Vec<_> = Reader::new(padded.as_slice()).unwrap().collect::<Result<_, _>>().unwrap();
+		assert_eq!(blocks.len(), 2);
+	}
+
+	// The power went halfway through the last block
+	let mut cut = data[..data.len() - 10].to_vec();
+	cut.resize(data.len() + 1000, 0);
+	let results: Vec<_> = Reader::new(cut.as_slice()).unwrap().collect();
+	assert_eq!(results.len(), 2);
+	assert!(results[0].is_ok());
+	assert!(matches!(results[1], Err(Error::BadBlock { .. })));
+
+	// Zeros followed by more data aren't the end
+	let mut gap = data.clone();
+	gap.extend_from_slice(&[0; 100]);
+	gap.extend_from_slice(&data[BinGyroHeader::LEN..]);
+	let results: Vec<_> = Reader::new(gap.as_slice()).unwrap().collect();
+	assert_eq!(results.len(), 5);
+	assert!(matches!(results[2], Err(Error::BadBlock { skipped: 100, .. })));
+}
+
+#[test]
+fn crc32_check_value() {
+	let mut crc = Crc32::new();
+	crc.update(b"1234");
+	crc.update(b"56789");
+	assert_eq!(crc.finish(), 0xCBF4_3926);
+}
+
+#[test]
+fn skips_corrupt_blocks() {
+	let chunks = vec![sample_bytes(&[[1; 6]; 3]); 3];
+	let block_len = BlockHeader::LEN + chunks[0].len();
+	let second = (BinGyroHeader::LEN + block_len) as u64;
+
+	for corrupt in [second as usize, second as usize + 7, second as usize + BlockHeader::LEN + 5] {
+		let mut data = encode(&chunks);
+		data[corrupt] ^= 0x40;
+
+		let results: Vec<_> = Reader::new(data.as_slice()).unwrap().collect();
+		assert_eq!(results.len(), 3);
+		assert_eq!(results[0].as_ref().unwrap().header.sequence(), 0);
+		assert!(matches!(
+			results[1],
+			Err(Error::BadBlock { offset, skipped }) if offset == second && skipped == block_len as u64
+		));
+		assert_eq!(results[2].as_ref().unwrap().header.sequence(), 2);
+	}
+}
+
+#[test]
+fn skips_garbage_between_blocks() {
+	let chunk = sample_bytes(&[[3; 6]; 2]);
+	let mut data = encode(std::slice::from_ref(&chunk));
+	data.extend_from_slice(&[0xB1, 0xB1, 0x00, 0x0C]);
+	let mut encoder = BlockEncoder::new(&SENSOR);
+	encoder.frame(0, &chunk);
+	data.extend_from_slice(encoder.frame(1000, &chunk).as_bytes());
+	data.extend_from_slice(&chunk);
+
+	let results: Vec<_> = Reader::new(data.as_slice()).unwrap().collect();
+	assert_eq!(results.len(), 3);
+	assert!(matches!(results[1], Err(Error::BadBlock { skipped: 4, .. })));
+	assert_eq!(results[2].as_ref().unwrap().header.sequence(), 1);
+}
+
+#[test]
+fn gap_tracker_finds_dropped_blocks() {
+	let header = BinGyroHeader::new(SENSOR);
+	let period = header.ticks_per_sample();
+	assert!((period - 602.409).abs() < 0.001);
+
+	let chunk = sample_bytes(&[[0; 6]; 250]);
+	let block_ticks = (250.0 * period) as u64;
+	let mut encoder = BlockEncoder::new(&SENSOR);
+	let blocks: Vec<_> = (0..6).map(|i| encoder.frame(i * block_ticks, &chunk)).collect();
+
+	let mut tracker = GapTracker::new();
+	assert_eq!(tracker.check(&blocks[0], period), None);
+	assert_eq!(tracker.check(&blocks[1], period), None);
+	// Blocks 2 and 3 never made it to the card
+	assert_eq!(tracker.check(&blocks[4], period), Some(Gap { blocks: 2, samples: 500 }));
+	assert_eq!(tracker.check(&blocks[5], period), None);
+}
+
+#[test]
+fn overrun_event_round_trip() {
+	let chunk = sample_bytes(&[[1; 6]; 3]);
+	let mut encoder = BlockEncoder::new(&SENSOR);
+	let mut data = BinGyroHeader::new(SENSOR).as_bytes().to_vec();
+	for header in [encoder.frame(1000, &chunk), encoder.overrun(2500, 1400)] {
+		data.extend_from_slice(header.as_bytes());
+		data.extend_from_slice(&chunk[..header.payload_len()]);
+	}
+	data.extend_from_slice(encoder.frame(3000, &chunk).as_bytes());
+	data.extend_from_slice(&chunk);
+
+	let blocks: Vec<_> = Reader::new(data.as_slice()).unwrap().collect::<Result<_, _>>().unwrap();
+	assert_eq!(blocks.len(), 3);
+	assert_eq!(blocks[0].header.lost_samples(), 0);
+
+	let event = &blocks[1].header;
+	assert_eq!(event.sequence(), 1);
+	assert_eq!(event.timestamp(), 2500);
+	assert_eq!(event.lost_samples(), 1400);
+	assert_eq!(event.sample_count(), 0);
+	assert_eq!(blocks[1].samples().count(), 0);
+
+	// An event is no gap in the sequence
+	let mut tracker = GapTracker::new();
+	assert!(blocks.iter().all(|b| tracker.check(&b.header, 602.0).is_none()));
+
+	// Even a vanishingly small loss is an event
+	assert_eq!(encoder.overrun(4000, 0).lost_samples(), 1);
+}
@@ -0,0 +1,45 @@
+use traccam_common::imu::calibration::{StillEstimator, STILL_GYRO_SPREAD_DPS};
+use traccam_common::imu::lsm6ds3::ImuConfig;
+use traccam_common::imu::ImuSample;
+
+fn sample(gyro: [i16; 3], accel: [i16; 3]) -> ImuSample {
+	ImuSample { gyro, accel, ..Default::default() }
+}
+
+#[test]
+fn offsets_of_a_still_rig() {
+	let info = ImuConfig::DEFAULT.info();
+	let mut still = StillEstimator::new(&info);
+	assert_eq!(still.calibration(), None);
+	// Noise around a fixed offset, lying flat and reading a bit over 1 g
+	for i in 0..1000 {
+		let noise = [-3, 0, 3, 1][i % 4];
+		still.add(&sample([12 + noise, -40 - noise, 7], [noise, 0, 16500 + noise]));
+	}
+	assert!(still.is_still());
+	let calibration = still.calibration().unwrap();
+	assert_eq!(calibration.samples, 1000);
+	assert_eq!(calibration.gyro_bias, [12.25, -40.25, 7.0]);
+	let one_g = 1.0 / info.accel_g_per_lsb;
+	assert!((calibration.accel_offset[2] as f64 - (16500.25 - one_g)).abs() < 0.01, "{calibration:?}");
+	assert!(calibration.accel_offset[0].abs() < 0.01);
+}
+
+#[test]
+fn motion_spoils_calibration() {
+	let info = ImuConfig::DEFAULT.info();
+	let spread = (STILL_GYRO_SPREAD_DPS / info.gyro_dps_per_lsb) as i16;
+	let mut still = StillEstimator::new(&info);
+	still.add(&sample([0, 100, 0], [0, 0, 16393]));
+	still.add(&sample([0, 100 + spread, 0], [0, 0, 16393]));
+	assert!(still.is_still());
+	still.add(&sample([0, 99, 0], [0, 0, 16393]));
+	assert!(!still.is_still());
+	assert_eq!(still.calibration(), None);
+	assert_eq!(still.count(), 3);
+
+	// Free fall, no gravity to tell the offset from
+	let mut falling = StillEstimator::new(&info);
+	falling.add(&sample([0; 3], [0; 3]));
+	assert_eq!(falling.calibration(), None);
+}
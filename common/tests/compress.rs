@@ -0,0 +1,113 @@
+use traccam_common::gyro_format::binary::{BinGyroHeader, BlockEncoder, Compression, Error, Reader};
+use traccam_common::gyro_format::compress::{self, max_packed_len, pack, unpack};
+use traccam_common::imu::lsm6ds3::ImuConfig;
+use zerocopy::IntoBytes;
+
+/// Raw 12 byte samples recorded at 1.66kHz, after the legacy text header
+fn recorded() -> &'static [u8] {
+	let log = include_bytes!("../../detrac/LOG.CSV");
+	let columns = b"t,gx,gy,gz,ax,ay,az,\n";
+	let start = log.windows(columns.len()).position(|w| w == columns).unwrap() + columns.len();
+	&log[start..]
+}
+
+fn round_trip(samples: &[u8], sample_len: usize) -> usize {
+	let mut packed = vec![0; max_packed_len(samples.len(), sample_len)];
+	let len = pack(samples, sample_len, &mut packed).unwrap();
+	let mut unpacked = vec![0; samples.len()];
+	assert_eq!(unpack(&packed[..len], sample_len, samples.len() / sample_len, &mut unpacked), Ok(samples.len()));
+	assert_eq!(unpacked, samples);
+	len
+}
+
+#[test]
+fn recorded_samples_round_trip() {
+	let samples = recorded();
+	// Blocks the size of what the firmware reads at the FIFO watermark
+	let block_len = 41 * 12;
+	let packed: usize = samples.chunks(block_len).map(|block| round_trip(block, 12)).sum();
+	// About 40% off on a handheld recording
+	let ratio = packed as f64 / samples.len() as f64;
+	assert!(ratio < 0.62, "{ratio}");
+}
+
+#[test]
+fn extremes_fit_worst_case() {
+	// Every step is i16::MIN, which takes all 16 bits
+	let mut samples = Vec::new();
+	for i in 0..20 {
+		let word: i16 = if i % 2 == 0 { 0 } else { i16::MIN };
+		for _ in 0..6 {
+			samples.extend_from_slice(&word.to_le_bytes());
+		}
+	}
+	samples.extend_from_slice(&[1, 2, 3]);
+	assert_eq!(round_trip(&samples, 12), max_packed_len(samples.len() - 3, 12) + 3);
+	assert_eq!(round_trip(&[], 12), 0);
+	assert_eq!(round_trip(&[1, 2, 3], 12), 3);
+	// Nothing changes, nothing but the first sample and the widths
+	assert_eq!(round_trip(&[7; 12 * 5], 12), 12 + 6);
+}
+
+#[test]
+fn refuses_bad_data_and_short_buffers() {
+	let samples = &recorded()[..12 * 10];
+	let mut packed = [0; 256];
+	assert_eq!(pack(samples, 12, &mut packed[..8]), Err(compress::Error::BufferTooSmall));
+	let len = pack(samples, 12, &mut packed).unwrap();
+	let packed = &packed[..len];
+
+	let mut out = [0; 12 * 10];
+	assert_eq!(unpack(packed, 12, 10, &mut out[..12 * 9]), Err(compress::Error::BufferTooSmall));
+	// Cut short, or more left over than a sample
+	assert_eq!(unpack(&packed[..len - 1], 12, 10, &mut out), Err(compress::Error::Malformed));
+	assert_eq!(unpack(packed, 12, 2, &mut out), Err(compress::Error::Malformed));
+	assert!(compress::is_plausible(len, 12, 10));
+	assert!(!compress::is_plausible(12 + 5, 12, 10));
+	assert!(!compress::is_plausible(max_packed_len(12 * 10, 12) + 12, 12, 10));
+	// A word can't take more than 16 bits
+	assert_eq!(unpack(&[1, 0, 17, 0, 0, 0], 2, 2, &mut out), Err(compress::Error::Malformed));
+	assert_eq!(unpack(&[1, 0, 16, 1, 0], 2, 2, &mut out), Ok(4));
+	assert_eq!(out[..4], [1, 0, 0, 0]);
+}
+
+#[test]
+fn packed_log_reads_like_raw_one() {
+	let config = ImuConfig::DEFAULT;
+	let sensor = config.sensor_config();
+	let samples = &recorded()[..12 * 41 * 8];
+	let write = |compression| {
+		let mut log = BinGyroHeader::new(sensor).with_compression(compression).as_bytes().to_vec();
+		let mut encoder = BlockEncoder::new(&sensor);
+		let mut buf = vec![0; max_packed_len(12 * 41, 12)];
+		for (i, block) in samples.chunks(12 * 41).enumerate() {
+			let (header, payload) = match compression {
+				Compression::None => (encoder.frame(i as u64 * 24_700, block), block),
+				Compression::Delta => encoder.pack(i as u64 * 24_700, block, &mut buf),
+			};
+			log.extend_from_slice(header.as_bytes());
+			log.extend_from_slice(payload);
+		}
+		log
+	};
+	let raw = write(Compression::None);
+	let packed = write(Compression::Delta);
+	assert!(packed.len() < raw.len() * 3 / 4);
+
+	let reader = Reader::new(&packed[..]).unwrap();
+	assert_eq!(reader.header().compression(), Compression::Delta);
+	let blocks: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+	let raw_blocks: Vec<_> = Reader::new(&raw[..]).unwrap().collect::<Result<_, _>>().unwrap();
+	assert_eq!(blocks.len(), 8);
+	for (block, raw) in blocks.iter().zip(&raw_blocks) {
+		assert_eq!(block.payload, raw.payload);
+		assert_eq!(block.header.sample_count(), 41);
+		assert_eq!(block.samples().collect::<Vec<_>>(), raw.samples().collect::<Vec<_>>());
+	}
+
+	// Unknown compression
+	let at = raw.iter().zip(&packed).position(|(raw, packed)| raw != packed).unwrap();
+	let mut bad = packed.clone();
+	bad[at] = 7;
+	assert!(matches!(Reader::new(&bad[..]), Err(Error::BadHeader)));
+}
@@ -0,0 +1,108 @@
+use std::f64::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+use traccam_common::fusion::{Fusion, Madgwick, Mahony, Quaternion};
+
+const RATE: f64 = 1660.0;
+
+fn filters(start: Quaternion) -> [Box<dyn Fusion>; 2] {
+	[
+		Box::new(Madgwick::new(Madgwick::DEFAULT_BETA, start)),
+		Box::new(Mahony::new(Mahony::DEFAULT_KP, Mahony::DEFAULT_KI, start)),
+	]
+}
+
+/// Feeds what an ideal IMU reads while turning around a fixed `axis` at `dps`, returns the
+/// true orientation at the end
+fn turn(filter: &mut dyn Fusion, start: Quaternion, axis: [f64; 3], dps: f64, seconds: f64, gyro_bias: [f64; 3]) -> Quaternion {
+	let rate = dps.to_radians();
+	let norm = axis.iter().map(|a| a * a).sum::<f64>().sqrt();
+	let gyro = [0, 1, 2].map(|i| axis[i] / norm * rate + gyro_bias[i]);
+	let mut truth = start;
+	for i in 1..=(seconds * RATE) as usize {
+		truth = start * Quaternion::from_axis_angle(axis, rate * i as f64 / RATE);
+		let accel = truth.conjugate().rotate([0.0, 0.0, 1.0]);
+		filter.update(gyro, accel, 1.0 / RATE);
+	}
+	truth
+}
+
+#[test]
+fn quaternion_basics() {
+	let yaw = Quaternion::from_axis_angle([0.0, 0.0, 2.0], FRAC_PI_2);
+	let turned = yaw.rotate([1.0, 0.0, 0.0]);
+	assert!((turned[0]).abs() < 1e-12 && (turned[1] - 1.0).abs() < 1e-12, "{turned:?}");
+	assert!((yaw.euler()[2] - FRAC_PI_2).abs() < 1e-12);
+	assert!((yaw.angle_to(Quaternion::IDENTITY) - FRAC_PI_2).abs() < 1e-12);
+
+	// Yaw, then pitch, then roll
+	let q = Quaternion::from_axis_angle([0.0, 0.0, 1.0], 0.3)
+		* Quaternion::from_axis_angle([0.0, 1.0, 0.0], -0.2)
+		* Quaternion::from_axis_angle([1.0, 0.0, 0.0], 0.1);
+	let [roll, pitch, yaw] = q.euler();
+	assert!((roll - 0.1).abs() < 1e-12 && (pitch + 0.2).abs() < 1e-12 && (yaw - 0.3).abs() < 1e-12);
+}
+
+#[test]
+fn start_from_gravity() {
+	for accel in [[0.0, 0.0, 1.0], [0.0, 0.0, -3.0], [1.0, 0.0, 0.0], [0.3, -0.5, 0.8]] {
+		let q = Quaternion::from_gravity(accel);
+		let up = q.conjugate().rotate([0.0, 0.0, 1.0]);
+		let norm = accel.iter().map(|a| a * a).sum::<f64>().sqrt();
+		for axis in 0..3 {
+			assert!((up[axis] - accel[axis] / norm).abs() < 1e-9, "{accel:?} {up:?}");
+		}
+		assert!(q.angle_to(Quaternion::IDENTITY) <= PI);
+	}
+	assert_eq!(Quaternion::from_gravity([0.0; 3]), Quaternion::IDENTITY);
+}
+
+#[test]
+fn follow_rotations() {
+	let tilted = Quaternion::from_axis_angle([1.0, 1.0, 0.0], FRAC_PI_4);
+	let cases = [
+		// Pan a full turn, tilt and roll a quarter
+		(Quaternion::IDENTITY, [0.0, 0.0, 1.0], 180.0, 2.0),
+		(Quaternion::IDENTITY, [0.0, 1.0, 0.0], 90.0, 1.0),
+		(Quaternion::IDENTITY, [1.0, 0.0, 0.0], -45.0, 2.0),
+		(tilted, [0.3, -1.0, 0.5], 120.0, 1.5),
+	];
+	for (start, axis, dps, seconds) in cases {
+		for filter in &mut filters(start) {
+			let truth = turn(filter.as_mut(), start, axis, dps, seconds, [0.0; 3]);
+			let error = filter.orientation().angle_to(truth).to_degrees();
+			assert!(error < 0.5, "{axis:?} at {dps}°/s off by {error}°");
+		}
+	}
+}
+
+#[test]
+fn accel_keeps_gyro_offset_from_tilting() {
+	// 1°/s of offset on X, 30 s at rest would integrate to 30° of roll
+	let bias = [1.0_f64.to_radians(), 0.0, 0.0];
+	for filter in &mut filters(Quaternion::IDENTITY) {
+		let truth = turn(filter.as_mut(), Quaternion::IDENTITY, [0.0, 0.0, 1.0], 0.0, 30.0, bias);
+		let error = filter.orientation().angle_to(truth).to_degrees();
+		assert!(error < 2.0, "off by {error}°");
+	}
+
+	// Mahony's integral learns the offset and takes the tilt out completely
+	let mut mahony = Mahony::new(Mahony::DEFAULT_KP, Mahony::DEFAULT_KI, Quaternion::IDENTITY);
+	let truth = turn(&mut mahony, Quaternion::IDENTITY, [0.0, 0.0, 1.0], 0.0, 120.0, bias);
+	assert!(mahony.orientation().angle_to(truth).to_degrees() < 0.1);
+	assert!((mahony.gyro_bias()[0] - bias[0]).abs() < bias[0] / 10.0, "{:?}", mahony.gyro_bias());
+}
+
+#[test]
+fn converges_from_wrong_start() {
+	// Mahony's integral first winds up on the 30° error and takes a while to unwind
+	let truth = Quaternion::from_axis_angle([1.0, 0.0, 0.0], 0.5);
+	for filter in &mut filters(Quaternion::IDENTITY) {
+		let accel = truth.conjugate().rotate([0.0, 0.0, 1.0]);
+		for _ in 0..(60.0 * RATE) as usize {
+			filter.update([0.0; 3], accel, 1.0 / RATE);
+		}
+		// Only down can be found this way, heading stays wherever the filter ended up
+		let up = filter.orientation().conjugate().rotate([0.0, 0.0, 1.0]);
+		let error = up.iter().zip(accel).map(|(u, a)| u * a).sum::<f64>().min(1.0).acos().to_degrees();
+		assert!(error < 0.1, "off by {error}°");
+	}
+}
@@ -0,0 +1,363 @@
+//! The ICM-42688-P driver against a simulated IMU, modelled on the registers it uses
+
+use embassy_futures::block_on;
+use embedded_hal::digital;
+use embedded_hal::i2c::{ErrorKind, NoAcknowledgeSource, Operation};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::i2c::{self, I2c};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::rc::Rc;
+use traccam_common::imu::icm42688::{self, AccelScale, GyroScale, Icm42688, ImuConfig, Odr};
+use traccam_common::imu::{Error, ImuDriver, ImuSample};
+
+const DEVICE_CONFIG: u8 = 0x11;
+const FIFO_CONFIG: u8 = 0x16;
+const INT_STATUS: u8 = 0x2D;
+const FIFO_COUNTH: u8 = 0x2E;
+const FIFO_COUNTL: u8 = 0x2F;
+const FIFO_DATA: u8 = 0x30;
+const SIGNAL_PATH_RESET: u8 = 0x4B;
+const INTF_CONFIG0: u8 = 0x4C;
+const PWR_MGMT0: u8 = 0x4E;
+const GYRO_CONFIG0: u8 = 0x4F;
+const ACCEL_CONFIG0: u8 = 0x50;
+const FIFO_CONFIG1: u8 = 0x5F;
+const FIFO_CONFIG2: u8 = 0x60;
+const FIFO_CONFIG3: u8 = 0x61;
+const INT_SOURCE0: u8 = 0x65;
+const WHO_AM_I: u8 = 0x75;
+
+/// Register file and FIFO of a simulated IMU
+#[derive(Default)]
+struct Model {
+	/// Simulated time in µs, advanced by the delay
+	now_us: u32,
+	/// Registers can't be accessed before this after a soft reset
+	busy_until: u32,
+	regs: Vec<u8>,
+	who_am_i: u8,
+	writes: Vec<(u8, u8)>,
+	fifo: VecDeque<u8>,
+	fifo_full: bool,
+	/// Samples taken since the last reset
+	samples: u16,
+}
+
+impl Model {
+	fn new() -> Rc<RefCell<Self>> {
+		let mut model = Self {
+			who_am_i: 0x47,
+			..Default::default()
+		};
+		model.reset();
+		Rc::new(RefCell::new(model))
+	}
+
+	fn reset(&mut self) {
+		self.regs = vec![0; 0x80];
+		self.regs[INTF_CONFIG0 as usize] = 0x30;
+		self.regs[INT_SOURCE0 as usize] = 0x10;
+		self.fifo.clear();
+		self.fifo_full = false;
+		self.samples = 0;
+	}
+
+	fn watermark(&self) -> usize {
+		u16::from_le_bytes([self.regs[FIFO_CONFIG2 as usize], self.regs[FIFO_CONFIG3 as usize]]) as usize
+	}
+
+	fn streaming(&self) -> bool {
+		self.regs[FIFO_CONFIG as usize] >> 6 == 0b01 && self.regs[PWR_MGMT0 as usize] & 0b1111 == 0b1111
+	}
+
+	/// Packet 3, big endian as the chip does by default
+	fn push_sample(&mut self) {
+		if !self.streaming() {
+			return;
+		}
+		assert_eq!(self.regs[FIFO_CONFIG1 as usize] & 0b111, 0b111, "only packet 3 is modelled");
+		let n = self.samples as i16;
+		let mut packet = vec![0b0110_1000];
+		for word in [-n, -n - 1, -n - 2, n, n + 1, n + 2] {
+			packet.extend(word.to_be_bytes());
+		}
+		packet.push(25); // Temperature
+		packet.extend((self.samples.wrapping_mul(16)).to_be_bytes());
+
+		if self.fifo.len() + packet.len() > icm42688::FIFO_BUFSIZE {
+			self.fifo_full = true;
+			self.fifo.drain(..packet.len());
+		}
+		self.fifo.extend(packet);
+		self.samples += 1;
+	}
+
+	fn count(&self) -> u16 {
+		match self.regs[INTF_CONFIG0 as usize] & 0b0100_0000 {
+			0 => self.fifo.len() as u16,
+			_ => (self.fifo.len() / icm42688::PACKET_LEN) as u16,
+		}
+	}
+
+	fn read_reg(&mut self, reg: u8) -> u8 {
+		match reg {
+			INT_STATUS => {
+				let ths = (self.fifo.len() / icm42688::PACKET_LEN >= self.watermark()) as u8;
+				let full = std::mem::take(&mut self.fifo_full) as u8;
+				ths << 2 | full << 1
+			}
+			FIFO_COUNTH => (self.count() >> 8) as u8,
+			FIFO_COUNTL => self.count() as u8,
+			FIFO_DATA => self.fifo.pop_front().unwrap_or(0xFF),
+			WHO_AM_I => self.who_am_i,
+			reg => self.regs[reg as usize],
+		}
+	}
+
+	fn write_reg(&mut self, reg: u8, value: u8) {
+		self.writes.push((reg, value));
+		match reg {
+			DEVICE_CONFIG if value & 1 != 0 => {
+				self.reset();
+				self.busy_until = self.now_us + 1000;
+			}
+			SIGNAL_PATH_RESET if value & 0b10 != 0 => self.fifo.clear(),
+			reg => self.regs[reg as usize] = value,
+		}
+	}
+}
+
+struct Bus(Rc<RefCell<Model>>);
+
+impl i2c::ErrorType for Bus {
+	type Error = ErrorKind;
+}
+
+impl I2c for Bus {
+	async fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+		let mut model = self.0.borrow_mut();
+		if address != icm42688::ADDRESS || model.now_us < model.busy_until {
+			return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+		}
+		let mut pointer = None;
+		for op in operations {
+			match op {
+				Operation::Write(bytes) => {
+					let (&reg, values) = bytes.split_first().unwrap();
+					for (i, &value) in values.iter().enumerate() {
+						model.write_reg(reg + i as u8, value);
+					}
+					pointer = Some(reg);
+				}
+				Operation::Read(buf) => {
+					let mut reg = pointer.expect("read without register address");
+					for byte in buf.iter_mut() {
+						*byte = model.read_reg(reg);
+						// FIFO_DATA keeps its address, everything else moves on
+						if reg != FIFO_DATA {
+							reg += 1;
+						}
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// INT1 latched on the FIFO threshold, waiting lets the sensor produce samples
+struct Int1(Rc<RefCell<Model>>);
+
+impl digital::ErrorType for Int1 {
+	type Error = Infallible;
+}
+
+impl Wait for Int1 {
+	async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+		let mut model = self.0.borrow_mut();
+		assert!(model.streaming(), "INT1 would never rise");
+		assert_eq!(model.regs[INT_SOURCE0 as usize] & 0b100, 0b100, "FIFO threshold not routed to INT1");
+		while model.fifo.len() / icm42688::PACKET_LEN < model.watermark() {
+			model.push_sample();
+		}
+		Ok(())
+	}
+
+	async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+		unimplemented!()
+	}
+
+	async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+		unimplemented!()
+	}
+
+	async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+		unimplemented!()
+	}
+
+	async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+		unimplemented!()
+	}
+}
+
+struct Delay(Rc<RefCell<Model>>);
+
+impl DelayNs for Delay {
+	async fn delay_ns(&mut self, ns: u32) {
+		self.0.borrow_mut().now_us += ns.div_ceil(1000);
+	}
+}
+
+fn simulated() -> (Rc<RefCell<Model>>, Icm42688<Bus, Int1>, Delay) {
+	let model = Model::new();
+	let imu = Icm42688::new(Bus(model.clone()), Int1(model.clone()));
+	(model.clone(), imu, Delay(model))
+}
+
+#[test]
+fn config_registers() {
+	let config = ImuConfig::DEFAULT;
+	assert_eq!(config.gyro_config0(), 0b0110_0110);
+	assert_eq!(config.accel_config0(), 0b0110_0110);
+
+	let config = ImuConfig {
+		odr: Odr::Hz8000,
+		accel: AccelScale::G16,
+		gyro: GyroScale::Dps2000,
+		temperature: false,
+	};
+	assert_eq!(config.gyro_config0(), 0b0000_0011);
+	assert_eq!(config.accel_config0(), 0b0000_0011);
+	assert_eq!(ImuConfig { odr: Odr::Hz500, ..config }.gyro_config0(), 0b0000_1111);
+
+	let info = config.info();
+	assert_eq!(info.model, "ICM-42688-P");
+	assert_eq!(info.odr_millihertz, 8_000_000);
+	assert_eq!(info.gyro_range_dps, 2000);
+	assert_eq!(info.accel_g_per_lsb, 1.0 / 2048.0);
+	assert_eq!(info.hw_timestamp_ns, 0);
+}
+
+#[test]
+fn watermark_is_about_25ms() {
+	assert_eq!(ImuConfig::DEFAULT.watermark_packets(), 25);
+	assert_eq!(ImuConfig { odr: Odr::Hz12_5, ..ImuConfig::DEFAULT }.watermark_packets(), 1);
+	// Capped to a quarter of the FIFO
+	assert_eq!(ImuConfig { odr: Odr::Hz8000, ..ImuConfig::DEFAULT }.watermark_packets(), 32);
+}
+
+#[test]
+fn init_resets_then_configures() {
+	let (model, mut imu, mut delay) = simulated();
+	model.borrow_mut().regs[FIFO_CONFIG as usize] = 0xFF;
+	let config = ImuConfig {
+		odr: Odr::Hz2000,
+		..ImuConfig::DEFAULT
+	};
+	block_on(imu.init(config, &mut delay)).unwrap();
+
+	let model = model.borrow();
+	assert_eq!(model.writes[0], (DEVICE_CONFIG, 1));
+	let expected: Vec<(u8, u8)> = icm42688::init_sequence(&config).iter().map(|&[reg, value]| (reg, value)).collect();
+	assert_eq!(model.writes[1..], expected);
+	assert_eq!(model.regs[GYRO_CONFIG0 as usize], config.gyro_config0());
+	assert_eq!(model.regs[ACCEL_CONFIG0 as usize], config.accel_config0());
+	assert_eq!(model.watermark(), 32);
+	assert!(model.streaming());
+	assert_eq!(imu.info(), config.info());
+}
+
+#[test]
+fn refuses_other_chips() {
+	let (model, mut imu, mut delay) = simulated();
+	// ICM-42605
+	model.borrow_mut().who_am_i = 0x42;
+	assert_eq!(block_on(imu.init(ImuConfig::DEFAULT, &mut delay)), Err(Error::WrongDevice(0x42)));
+	assert!(!model.borrow().streaming());
+}
+
+#[test]
+fn batches_come_out_in_log_layout() {
+	let (model, mut imu, mut delay) = simulated();
+	block_on(imu.init(ImuConfig::DEFAULT, &mut delay)).unwrap();
+
+	for batch in 0..4 {
+		block_on(imu.wait_data_ready()).unwrap();
+		let samples = block_on(imu.read_batch()).unwrap();
+		assert_eq!(samples.len(), 25);
+		assert_eq!(samples.as_bytes().len(), 25 * ImuSample::LEN);
+		assert!(samples.timestamps().is_none());
+		for (i, sample) in samples.samples().enumerate() {
+			let n = (batch * 25 + i) as i16;
+			// Gyro first, unlike in the FIFO packets
+			assert_eq!(sample, ImuSample { gyro: [n, n + 1, n + 2], accel: [-n, -n - 1, -n - 2], ..Default::default() });
+		}
+	}
+	assert!(model.borrow().fifo.is_empty());
+}
+
+#[test]
+fn temperature_comes_with_every_packet() {
+	let (_, mut imu, mut delay) = simulated();
+	let config = ImuConfig { temperature: true, ..ImuConfig::DEFAULT };
+	block_on(imu.init(config, &mut delay)).unwrap();
+	let info = imu.info();
+	assert_eq!(info.layout().sample_len(), ImuSample::LEN + 2);
+
+	block_on(imu.wait_data_ready()).unwrap();
+	let samples = block_on(imu.read_batch()).unwrap();
+	assert_eq!(samples.as_bytes().len(), 25 * info.layout().sample_len());
+	for (i, sample) in samples.samples().enumerate() {
+		let n = i as i16;
+		assert_eq!((sample.gyro, sample.accel), ([n, n + 1, n + 2], [-n, -n - 1, -n - 2]));
+		// The model always reports 25 LSB, 2.07 LSB per °C above 25°C
+		assert_eq!(sample.temperature, Some(25));
+		let celsius = sample.temperature.unwrap() as f64 * info.temp_c_per_lsb + info.temp_zero_c;
+		assert!((celsius - (25.0 + 25.0 / 2.07)).abs() < 1e-9, "{celsius}");
+	}
+}
+
+#[test]
+fn late_readout_takes_everything() {
+	let (model, mut imu, mut delay) = simulated();
+	block_on(imu.init(ImuConfig::DEFAULT, &mut delay)).unwrap();
+
+	for _ in 0..100 {
+		model.borrow_mut().push_sample();
+	}
+	let samples = block_on(imu.read_batch()).unwrap();
+	assert_eq!(samples.len(), 100);
+	assert_eq!(samples.samples().last().unwrap().gyro[0], 99);
+	assert!(block_on(imu.read_batch()).unwrap().is_empty());
+}
+
+#[test]
+fn full_fifo_is_flushed() {
+	let (model, mut imu, mut delay) = simulated();
+	block_on(imu.init(ImuConfig::DEFAULT, &mut delay)).unwrap();
+
+	for _ in 0..200 {
+		model.borrow_mut().push_sample();
+	}
+	assert_eq!(block_on(imu.read_batch()), Err(Error::Overrun));
+	assert!(model.borrow().fifo.is_empty());
+
+	// Back to normal with the next batch
+	block_on(imu.wait_data_ready()).unwrap();
+	let samples = block_on(imu.read_batch()).unwrap();
+	assert_eq!(samples.len(), 25);
+	assert_eq!(samples.samples().next().unwrap().gyro[0], 200);
+}
+
+#[test]
+fn power_off_stops_sampling() {
+	let (model, mut imu, mut delay) = simulated();
+	block_on(imu.init(ImuConfig::DEFAULT, &mut delay)).unwrap();
+	block_on(imu.power_off()).unwrap();
+	assert_eq!(model.borrow().regs[PWR_MGMT0 as usize], 0);
+	model.borrow_mut().push_sample();
+	assert!(block_on(imu.read_batch()).unwrap().is_empty());
+}
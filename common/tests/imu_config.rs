@@ -0,0 +1,89 @@
+use traccam_common::imu::lsm6ds3::{AccelScale, FifoTimestamps, GyroScale, ImuConfig, Odr};
+
+#[test]
+fn default_matches_original_registers() {
+	let config = ImuConfig::DEFAULT;
+	assert_eq!(config.ctrl1_xl(), 0b1000_0000);
+	assert_eq!(config.ctrl2_g(), 0b1000_0000);
+	assert_eq!(config.fifo_ctrl5(), 0b0100_0110);
+	assert_eq!(config.fifo_ctrl2(), 0);
+	assert_eq!(config.wake_up_dur(), 0);
+	assert_eq!(config.sample_len(), 12);
+	assert_eq!(config.sample_interval_micros(), 1e6 / 1660.0);
+	assert_eq!(config.info().samples_in(1_000_000), 1660);
+	assert_eq!(config.info().samples_in(25_000), 42);
+	assert_eq!(config.info().samples_in(0), 0);
+}
+
+#[test]
+fn full_scale_registers() {
+	let config = |accel, gyro| ImuConfig { accel, gyro, ..ImuConfig::DEFAULT };
+	assert_eq!(config(AccelScale::G16, GyroScale::Dps125).ctrl1_xl(), 0b1000_0100);
+	assert_eq!(config(AccelScale::G16, GyroScale::Dps125).ctrl2_g(), 0b1000_0010);
+	assert_eq!(config(AccelScale::G4, GyroScale::Dps500).ctrl1_xl(), 0b1000_1000);
+	assert_eq!(config(AccelScale::G4, GyroScale::Dps500).ctrl2_g(), 0b1000_0100);
+	assert_eq!(config(AccelScale::G8, GyroScale::Dps2000).ctrl1_xl(), 0b1000_1100);
+	assert_eq!(config(AccelScale::G8, GyroScale::Dps2000).ctrl2_g(), 0b1000_1100);
+	assert_eq!(config(AccelScale::G8, GyroScale::Dps1000).ctrl2_g(), 0b1000_1000);
+
+	// Sensitivity doubles with the range
+	assert_eq!(AccelScale::G16.g_per_lsb(), 0.488e-3);
+	assert_eq!(GyroScale::Dps2000.dps_per_lsb(), 70e-3);
+}
+
+#[test]
+fn odr_registers() {
+	let config = |odr| ImuConfig { odr, ..ImuConfig::DEFAULT };
+	assert_eq!(config(Odr::Hz12_5).ctrl1_xl(), 0b0001_0000);
+	assert_eq!(config(Odr::Hz6660).ctrl2_g(), 0b1010_0000);
+	assert_eq!(config(Odr::Hz6660).fifo_ctrl5(), 0b0101_0110);
+	assert_eq!(config(Odr::Hz104).fifo_ctrl5(), 0b0010_0110);
+}
+
+#[test]
+fn watermark_is_whole_sample_sets() {
+	let timed = ImuConfig { timestamps: FifoTimestamps::Fine, ..ImuConfig::DEFAULT };
+	for odr in [Odr::Hz12_5, Odr::Hz26, Odr::Hz104, Odr::Hz1660, Odr::Hz3330, Odr::Hz6660] {
+		for config in [ImuConfig { odr, ..ImuConfig::DEFAULT }, ImuConfig { odr, ..timed }] {
+			let words = config.watermark_words() as usize;
+			assert_eq!(words % (config.sample_len() / 2), 0, "{config:?}");
+			assert!(words > 0);
+			// Leaves most of the FIFO as headroom for late readouts
+			assert!(words * 2 <= 4096 / 4, "{config:?}");
+			assert_eq!(config.fifo_ctrl1(), words as u8);
+			assert_eq!(config.fifo_ctrl2() & 0b111, (words >> 8) as u8);
+		}
+	}
+
+	// About 25ms worth at the default rate
+	assert_eq!(ImuConfig::DEFAULT.watermark_words(), 41 * 6);
+	assert_eq!(timed.watermark_words(), 41 * 9);
+	assert_eq!(timed.fifo_ctrl2(), 0b1000_0001);
+	assert_eq!(timed.wake_up_dur(), 0b0001_0000);
+	assert_eq!(ImuConfig { odr: Odr::Hz104, ..timed }.fifo_ctrl2(), 0b1000_0000);
+}
+
+#[test]
+fn header_describes_config() {
+	let config = ImuConfig {
+		odr: Odr::Hz833,
+		accel: AccelScale::G8,
+		gyro: GyroScale::Dps1000,
+		timestamps: FifoTimestamps::Coarse,
+		temperature: true,
+	};
+	let sensor = config.sensor_config();
+	assert_eq!(sensor.odr_millihertz(), 833_000);
+	assert_eq!(sensor.accel_range_g(), 8);
+	assert_eq!(sensor.accel_g_per_lsb(), 0.244e-3);
+	assert_eq!(sensor.gyro_range_dps(), 1000);
+	assert_eq!(sensor.gyro_dps_per_lsb(), 35e-3);
+	assert_eq!(sensor.hw_timestamp_ns().unwrap().get(), 6_400_000);
+	assert_eq!(sensor.temperature_scale(), Some((1.0 / 256.0, 25.0)));
+	assert_eq!(sensor.mag_ut_per_lsb(), None);
+	// Gyro, accel, timestamp and temperature, only the first three come from the FIFO
+	assert_eq!(sensor.sample_len(), config.sample_len());
+	assert_eq!(config.sample_len(), 20);
+	assert_eq!(config.fifo_set_len(), 18);
+	assert_eq!(ImuConfig::DEFAULT.sensor_config().temperature_scale(), None);
+}
@@ -0,0 +1,586 @@
+//! The LSM6DS3 driver against a simulated IMU, modelled on the registers it uses
+
+use embassy_futures::block_on;
+use embedded_hal::digital::{self, OutputPin};
+use embedded_hal::i2c::{ErrorKind, NoAcknowledgeSource, Operation};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::i2c::{self, I2c};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::rc::Rc;
+use traccam_common::imu::lsm6ds3::{self, FifoStatus, FifoTimestamps, ImuConfig, Lsm6ds3, Odr};
+use traccam_common::imu::{Error, ImuDriver, ImuSample};
+
+const FIFO_CTRL1: u8 = 0x06;
+const FIFO_CTRL2: u8 = 0x07;
+const FIFO_CTRL5: u8 = 0x0A;
+const WHO_AM_I: u8 = 0x0F;
+const CTRL1_XL: u8 = 0x10;
+const CTRL2_G: u8 = 0x11;
+const CTRL3_C: u8 = 0x12;
+const OUT_TEMP_L: u8 = 0x20;
+const FIFO_STATUS1: u8 = 0x3A;
+const FIFO_STATUS2: u8 = 0x3B;
+const FIFO_STATUS3: u8 = 0x3C;
+const FIFO_STATUS4: u8 = 0x3D;
+const FIFO_DATA_OUT_L: u8 = 0x3E;
+const FIFO_DATA_OUT_H: u8 = 0x3F;
+
+/// Register file and FIFO of a simulated IMU
+#[derive(Default)]
+struct Model {
+	powered: bool,
+	/// Simulated time in ms, advanced by the delay
+	now_ms: u32,
+	/// When the power was last switched on
+	powered_at: Option<u32>,
+	regs: Vec<u8>,
+	who_am_i: u8,
+	writes: Vec<(u8, u8)>,
+	fifo: VecDeque<u8>,
+	/// Bytes that left the FIFO since it was last reset, read or overwritten
+	consumed: usize,
+	overrun: bool,
+	/// Sample sets pushed since power on
+	sets: u16,
+}
+
+impl Model {
+	fn new() -> Rc<RefCell<Self>> {
+		Rc::new(RefCell::new(Self {
+			regs: vec![0; 0x80],
+			who_am_i: 0x6A,
+			..Default::default()
+		}))
+	}
+
+	fn watermark_words(&self) -> usize {
+		self.regs[FIFO_CTRL1 as usize] as usize | (self.regs[FIFO_CTRL2 as usize] as usize & 0b111) << 8
+	}
+
+	fn timestamps(&self) -> bool {
+		self.regs[FIFO_CTRL2 as usize] & 0b1000_0000 != 0
+	}
+
+	fn fifo_running(&self) -> bool {
+		self.regs[FIFO_CTRL5 as usize] & 0b111 == 0b110 && self.regs[CTRL2_G as usize] >> 4 != 0
+	}
+
+	fn set_words(&self) -> usize {
+		if self.timestamps() { 9 } else { 6 }
+	}
+
+	/// One ODR tick: gyro, accel and optionally the timestamp data set
+	fn push_set(&mut self) {
+		let words = self.set_words();
+		self.push_words(words);
+	}
+
+	/// The IMU writes a set word by word, a readout can catch it halfway
+	fn push_words(&mut self, count: usize) {
+		if !self.fifo_running() {
+			return;
+		}
+		let n = self.sets as i16;
+		let ts = self.sets as u32 * 3;
+		let mut bytes: Vec<u8> = [n, n + 1, n + 2, -n, -n - 1, -n - 2].iter().flat_map(|w| w.to_le_bytes()).collect();
+		bytes.extend([(ts >> 8) as u8, (ts >> 16) as u8, 0, ts as u8, 0, 0]);
+
+		let set_words = self.set_words();
+		for _ in 0..count {
+			let word = (self.consumed / 2 + self.fifo.len() / 2) % set_words;
+			if self.fifo.len() + 2 > lsm6ds3::FIFO_BUFSIZE {
+				self.overrun = true;
+				self.fifo.drain(..2);
+				self.consumed += 2;
+			}
+			self.fifo.extend(&bytes[word * 2..word * 2 + 2]);
+			if word == set_words - 1 {
+				self.sets += 1;
+			}
+		}
+	}
+
+	/// Where the next word to be read sits within a set
+	fn pattern(&self) -> u16 {
+		(self.consumed / 2 % self.set_words()) as u16
+	}
+
+	fn read_reg(&mut self, reg: u8) -> u8 {
+		let words = self.fifo.len() / 2;
+		match reg {
+			FIFO_STATUS1 => words as u8,
+			FIFO_STATUS2 => {
+				let wtm = (words >= self.watermark_words()) as u8;
+				let empty = self.fifo.is_empty() as u8;
+				wtm << 7 | (self.overrun as u8) << 6 | empty << 4 | (words >> 8) as u8 & 0x0F
+			}
+			WHO_AM_I => self.who_am_i,
+			FIFO_STATUS3 => self.pattern() as u8,
+			FIFO_STATUS4 => (self.pattern() >> 8) as u8,
+			FIFO_DATA_OUT_L | FIFO_DATA_OUT_H => {
+				// Reading past the end returns garbage on the real thing
+				self.consumed += 1;
+				self.fifo.pop_front().expect("read beyond the FIFO contents")
+			}
+			reg => self.regs[reg as usize],
+		}
+	}
+}
+
+struct Bus(Rc<RefCell<Model>>);
+
+impl i2c::ErrorType for Bus {
+	type Error = ErrorKind;
+}
+
+impl I2c for Bus {
+	async fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+		let mut model = self.0.borrow_mut();
+		if address != lsm6ds3::ADDRESS || !model.powered {
+			return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+		}
+		let mut pointer = None;
+		for op in operations {
+			let auto_increment = model.regs[CTRL3_C as usize] & 0b100 != 0;
+			match op {
+				Operation::Write(bytes) => {
+					let (&reg, values) = bytes.split_first().unwrap();
+					for (i, &value) in values.iter().enumerate() {
+						let reg = reg + i as u8;
+						model.writes.push((reg, value));
+						model.regs[reg as usize] = value;
+						// Bypass mode empties the FIFO and clears its flags
+						if reg == FIFO_CTRL5 && value & 0b111 == 0 {
+							model.fifo.clear();
+							model.consumed = 0;
+							model.overrun = false;
+						}
+					}
+					pointer = Some(reg);
+				}
+				Operation::Read(buf) => {
+					let mut reg = pointer.expect("read without register address");
+					for byte in buf.iter_mut() {
+						*byte = model.read_reg(reg);
+						// The FIFO output keeps its address, everything else moves on
+						if auto_increment && reg != FIFO_DATA_OUT_L && reg != FIFO_DATA_OUT_H {
+							reg += 1;
+						}
+					}
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+/// INT1 with FIFO threshold routed to it, waiting lets the sensor produce samples
+struct Int1(Rc<RefCell<Model>>);
+
+impl digital::ErrorType for Int1 {
+	type Error = Infallible;
+}
+
+impl Wait for Int1 {
+	async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+		let mut model = self.0.borrow_mut();
+		assert!(model.fifo_running(), "INT1 would never rise");
+		while model.fifo.len() / 2 < model.watermark_words() {
+			model.push_set();
+		}
+		Ok(())
+	}
+
+	async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+		unimplemented!()
+	}
+
+	async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+		unimplemented!()
+	}
+
+	async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+		unimplemented!()
+	}
+
+	async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+		unimplemented!()
+	}
+}
+
+struct Power(Rc<RefCell<Model>>);
+
+impl digital::ErrorType for Power {
+	type Error = Infallible;
+}
+
+impl OutputPin for Power {
+	fn set_low(&mut self) -> Result<(), Self::Error> {
+		let mut model = self.0.borrow_mut();
+		let now = model.now_ms;
+		*model = Model {
+			regs: vec![0; 0x80],
+			who_am_i: model.who_am_i,
+			writes: std::mem::take(&mut model.writes),
+			now_ms: now,
+			..Default::default()
+		};
+		Ok(())
+	}
+
+	fn set_high(&mut self) -> Result<(), Self::Error> {
+		let mut model = self.0.borrow_mut();
+		if !model.powered {
+			model.powered = true;
+			model.powered_at = Some(model.now_ms);
+		}
+		Ok(())
+	}
+}
+
+struct Delay(Rc<RefCell<Model>>);
+
+impl DelayNs for Delay {
+	async fn delay_ns(&mut self, ns: u32) {
+		self.0.borrow_mut().now_ms += ns.div_ceil(1_000_000);
+	}
+}
+
+fn simulated() -> (Rc<RefCell<Model>>, Lsm6ds3<Bus, Int1, Power>, Delay) {
+	let model = Model::new();
+	let imu = Lsm6ds3::new(Bus(model.clone()), Int1(model.clone()), Power(model.clone()));
+	(model.clone(), imu, Delay(model))
+}
+
+fn timed() -> ImuConfig {
+	ImuConfig {
+		timestamps: FifoTimestamps::Fine,
+		..ImuConfig::DEFAULT
+	}
+}
+
+#[test]
+fn init_writes_config_after_boot() {
+	for config in [ImuConfig::DEFAULT, timed(), ImuConfig { odr: Odr::Hz104, ..timed() }] {
+		let (model, mut imu, mut delay) = simulated();
+		block_on(imu.init(config, &mut delay)).unwrap();
+
+		let model = model.borrow();
+		let expected: Vec<(u8, u8)> = lsm6ds3::init_sequence(&config).iter().map(|&[reg, value]| (reg, value)).collect();
+		assert_eq!(model.writes, expected);
+		assert!(model.now_ms - model.powered_at.unwrap() >= 300, "configured before the rail settled");
+
+		assert_eq!(model.regs[CTRL1_XL as usize], config.ctrl1_xl());
+		assert_eq!(model.regs[CTRL2_G as usize], config.ctrl2_g());
+		assert_eq!(model.regs[FIFO_CTRL5 as usize], config.fifo_ctrl5());
+		assert_eq!(model.watermark_words(), config.watermark_words() as usize);
+		assert_eq!(model.timestamps(), config.timestamps != FifoTimestamps::Off);
+	}
+}
+
+#[test]
+fn init_sequence_enables_timestamps_only_when_asked() {
+	let plain = lsm6ds3::init_sequence(&ImuConfig::DEFAULT);
+	let timed = lsm6ds3::init_sequence(&timed());
+	assert_eq!(plain.len(), 8);
+	assert_eq!(timed.len(), 12);
+	// Counter runs and gets reset
+	assert!(timed.contains(&[0x19, 0b0010_0100]));
+	assert!(timed.contains(&[0x42, 0xAA]));
+	assert!(!plain.iter().any(|[reg, _]| *reg == 0x19 || *reg == 0x42));
+	// Sensors start last, once the FIFO is set up
+	assert_eq!(plain[plain.len() - 1][0], CTRL2_G);
+	assert_eq!(timed[timed.len() - 1][0], CTRL2_G);
+}
+
+#[test]
+fn reinit_power_cycles() {
+	let (model, mut imu, mut delay) = simulated();
+	block_on(imu.init(ImuConfig::DEFAULT, &mut delay)).unwrap();
+	block_on(imu.wait_data_ready()).unwrap();
+	assert!(!model.borrow().fifo.is_empty());
+
+	block_on(imu.init(timed(), &mut delay)).unwrap();
+	let model = model.borrow();
+	assert!(model.fifo.is_empty());
+	assert!(model.timestamps());
+}
+
+#[test]
+fn fifo_status_parsing() {
+	let status = FifoStatus::from_registers([0xF6, 0b1000_0000, 0, 0]);
+	assert!(!status.below_watermark);
+	assert_eq!(status.unread_bytes(), 246 * 2);
+	assert_eq!(status.pattern, 0);
+
+	let status = FifoStatus::from_registers([0x71, 0b1000_0001, 0x05, 0b1111_1100]);
+	assert_eq!(status.unread_bytes(), 369 * 2);
+	assert_eq!(status.pattern, 5);
+
+	let status = FifoStatus::from_registers([0x00, 0b0001_0000, 0x02, 0b01]);
+	assert_eq!(status.pattern, 0x102);
+	assert!(status.below_watermark);
+	assert!(status.fifo_empty);
+	assert!(!status.overrun);
+	assert_eq!(status.unread_bytes(), 0);
+
+	let status = FifoStatus::from_registers([0x00, 0b1110_1000, 0, 0]);
+	assert!(status.overrun);
+	assert!(status.fifo_full_smart);
+	assert!(!status.fifo_empty);
+	assert_eq!(status.unread_bytes(), 2048 * 2);
+
+	// Flags don't leak into the level
+	let status = FifoStatus::from_registers([0xFF, 0b1111_0111, 0, 0]);
+	assert_eq!(status.unread_bytes(), 0x7FF * 2);
+}
+
+#[test]
+fn fifo_status_from_model() {
+	let (model, mut imu, mut delay) = simulated();
+	block_on(imu.init(timed(), &mut delay)).unwrap();
+
+	let status = block_on(imu.fifo_status()).unwrap();
+	assert!(status.below_watermark);
+	assert_eq!(status.unread_bytes(), 0);
+
+	for _ in 0..200 {
+		model.borrow_mut().push_set();
+	}
+	let status = block_on(imu.fifo_status()).unwrap();
+	assert!(!status.below_watermark);
+	assert_eq!(status.unread_bytes(), 200 * 18);
+}
+
+#[test]
+fn reads_exactly_one_watermark() {
+	for config in [ImuConfig::DEFAULT, timed()] {
+		let (model, mut imu, mut delay) = simulated();
+		block_on(imu.init(config, &mut delay)).unwrap();
+
+		for batch in 0..5 {
+			block_on(imu.wait_data_ready()).unwrap();
+			let batch_samples = block_on(imu.read_batch()).unwrap();
+			assert_eq!(batch_samples.as_bytes().len(), config.watermark_words() as usize * 2);
+			assert_eq!(batch_samples.len() * config.sample_len(), batch_samples.as_bytes().len());
+			assert_eq!(batch_samples.skipped_words, 0);
+
+			// Every set starts where the previous batch stopped
+			let sets = batch_samples.len();
+			for (i, sample) in batch_samples.samples().enumerate() {
+				let n = (batch * sets + i) as i16;
+				assert_eq!(sample, ImuSample { gyro: [n, n + 1, n + 2], accel: [-n, -n - 1, -n - 2], ..Default::default() });
+			}
+			match batch_samples.timestamps() {
+				Some(ts) => assert!(ts.eq((0..sets).map(|i| (batch * sets + i) as u32 * 3))),
+				None => assert_eq!(config.timestamps, FifoTimestamps::Off),
+			}
+		}
+		assert!(model.borrow().fifo.is_empty());
+	}
+}
+
+#[test]
+fn late_readout_takes_everything() {
+	let (model, mut imu, mut delay) = simulated();
+	block_on(imu.init(ImuConfig::DEFAULT, &mut delay)).unwrap();
+
+	// Some came in before the wait started
+	for _ in 0..20 {
+		model.borrow_mut().push_set();
+	}
+	block_on(imu.wait_data_ready()).unwrap();
+	assert_eq!(block_on(imu.read_batch()).unwrap().len(), 41);
+
+	for _ in 0..300 {
+		model.borrow_mut().push_set();
+	}
+	assert_eq!(block_on(imu.read_batch()).unwrap().len(), 300);
+
+	// Nothing new yet
+	assert!(block_on(imu.read_batch()).unwrap().is_empty());
+}
+
+#[test]
+fn unpowered_imu_does_not_answer() {
+	let (_, mut imu, mut delay) = simulated();
+	assert_eq!(block_on(imu.fifo_status()), Err(Error::I2c(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address))));
+
+	block_on(imu.init(ImuConfig::DEFAULT, &mut delay)).unwrap();
+	assert!(block_on(imu.fifo_status()).is_ok());
+
+	block_on(imu.power_off()).unwrap();
+	assert!(block_on(imu.read_batch()).is_err());
+
+	let (bus, _, _) = imu.release();
+	assert!(!bus.0.borrow().powered);
+}
+
+#[test]
+fn overrun_resets_fifo_and_continues() {
+	for config in [ImuConfig::DEFAULT, timed()] {
+		let (model, mut imu, mut delay) = simulated();
+		block_on(imu.init(config, &mut delay)).unwrap();
+		block_on(imu.wait_data_ready()).unwrap();
+		block_on(imu.read_batch()).unwrap();
+
+		// Readout came way too late, the FIFO wrapped mid sample set
+		for _ in 0..1000 {
+			model.borrow_mut().push_set();
+		}
+		assert!(block_on(imu.fifo_status()).unwrap().overrun);
+		model.borrow_mut().writes.clear();
+		assert_eq!(block_on(imu.read_batch()), Err(Error::Overrun));
+
+		// Went through bypass and back to the same continuous mode
+		let bypass = config.fifo_ctrl5() & !0b111;
+		assert_eq!(model.borrow().writes, [(FIFO_CTRL5, bypass), (FIFO_CTRL5, config.fifo_ctrl5())]);
+		let status = block_on(imu.fifo_status()).unwrap();
+		assert!(!status.overrun);
+		assert!(status.fifo_empty);
+
+		// Recording goes on with whole sample sets
+		let resumed_at = model.borrow().sets as i16;
+		block_on(imu.wait_data_ready()).unwrap();
+		let samples = block_on(imu.read_batch()).unwrap();
+		assert_eq!(samples.as_bytes().len(), config.watermark_words() as usize * 2);
+		for (i, sample) in samples.samples().enumerate() {
+			let n = resumed_at + i as i16;
+			assert_eq!(sample.gyro[0], n);
+			assert_eq!(sample.accel[0], -n);
+		}
+	}
+}
+
+#[test]
+fn leaves_partial_sets_for_later() {
+	for config in [ImuConfig::DEFAULT, timed()] {
+		let (model, mut imu, mut delay) = simulated();
+		block_on(imu.init(config, &mut delay)).unwrap();
+		let set_words = config.sample_len() / 2;
+
+		// Readout while the IMU is halfway through writing the 11th set
+		for _ in 0..10 {
+			model.borrow_mut().push_set();
+		}
+		model.borrow_mut().push_words(4);
+		let batch = block_on(imu.read_batch()).unwrap();
+		assert_eq!(batch.len(), 10);
+		assert_eq!(model.borrow().fifo.len(), 4 * 2);
+
+		// The rest of it arrives, reading picks up at gyro X
+		model.borrow_mut().push_words(set_words - 4);
+		model.borrow_mut().push_set();
+		let batch = block_on(imu.read_batch()).unwrap();
+		assert_eq!(batch.len(), 2);
+		assert_eq!(batch.skipped_words, 0);
+		assert_eq!(batch.samples().map(|s| s.gyro[0]).collect::<Vec<_>>(), [10, 11]);
+	}
+}
+
+#[test]
+fn realigns_on_fifo_pattern() {
+	for config in [ImuConfig::DEFAULT, timed()] {
+		let (model, mut imu, mut delay) = simulated();
+		block_on(imu.init(config, &mut delay)).unwrap();
+		let set_words = config.sample_len() / 2;
+
+		// Someone else read a few words, the FIFO is out of step
+		for _ in 0..20 {
+			model.borrow_mut().push_set();
+		}
+		{
+			let mut model = model.borrow_mut();
+			for _ in 0..2 * 2 {
+				model.read_reg(FIFO_DATA_OUT_L);
+			}
+		}
+		assert_eq!(block_on(imu.fifo_status()).unwrap().pattern, 2);
+
+		let batch = block_on(imu.read_batch()).unwrap();
+		assert_eq!(batch.skipped_words as usize, set_words - 2);
+		assert_eq!(batch.len(), 19);
+		for (i, sample) in batch.samples().enumerate() {
+			let n = i as i16 + 1;
+			assert_eq!(sample, ImuSample { gyro: [n, n + 1, n + 2], accel: [-n, -n - 1, -n - 2], ..Default::default() });
+		}
+		assert!(model.borrow().fifo.is_empty());
+		assert_eq!(block_on(imu.fifo_status()).unwrap().pattern, 0);
+	}
+}
+
+#[test]
+fn too_little_to_realign() {
+	let (model, mut imu, mut delay) = simulated();
+	block_on(imu.init(ImuConfig::DEFAULT, &mut delay)).unwrap();
+	model.borrow_mut().push_words(5);
+	model.borrow_mut().read_reg(FIFO_DATA_OUT_L);
+	model.borrow_mut().read_reg(FIFO_DATA_OUT_L);
+
+	// Only the tail of a set and no whole one yet, nothing is read
+	let batch = block_on(imu.read_batch()).unwrap();
+	assert!(batch.is_empty());
+	assert_eq!(batch.skipped_words, 0);
+	assert_eq!(model.borrow().fifo.len(), 4 * 2);
+}
+
+#[test]
+fn temperature_is_stored_with_every_sample() {
+	for config in [ImuConfig { temperature: true, ..ImuConfig::DEFAULT }, ImuConfig { temperature: true, ..timed() }] {
+		let (model, mut imu, mut delay) = simulated();
+		block_on(imu.init(config, &mut delay)).unwrap();
+		// 30.5°C at 256 LSB per °C above 25°C
+		model.borrow_mut().regs[OUT_TEMP_L as usize..][..2].copy_from_slice(&1408_i16.to_le_bytes());
+
+		// Out of step, so the skipped words have to go too
+		for _ in 0..20 {
+			model.borrow_mut().push_set();
+		}
+		model.borrow_mut().read_reg(FIFO_DATA_OUT_L);
+		model.borrow_mut().read_reg(FIFO_DATA_OUT_L);
+		let batch = block_on(imu.read_batch()).unwrap();
+		assert_eq!(batch.len(), 19);
+		assert_eq!(batch.as_bytes().len(), 19 * config.sample_len());
+		for (i, sample) in batch.samples().enumerate() {
+			let n = i as i16 + 1;
+			assert_eq!(sample, ImuSample { gyro: [n, n + 1, n + 2], accel: [-n, -n - 1, -n - 2], mag: None, temperature: Some(1408) });
+		}
+		if let Some(ts) = batch.timestamps() {
+			assert!(ts.eq((1..20).map(|n| n * 3)));
+		}
+		let info = imu.info();
+		assert_eq!(1408.0 * info.temp_c_per_lsb + info.temp_zero_c, 30.5);
+	}
+}
+
+#[test]
+fn temperature_readout_fits_the_buffer() {
+	let (model, mut imu, mut delay) = simulated();
+	let config = ImuConfig { temperature: true, ..timed() };
+	block_on(imu.init(config, &mut delay)).unwrap();
+
+	// A FIFO worth of sets grows by a ninth once the temperature is added
+	for _ in 0..lsm6ds3::FIFO_BUFSIZE / config.fifo_set_len() {
+		model.borrow_mut().push_set();
+	}
+	let batch = block_on(imu.read_batch()).unwrap();
+	assert_eq!(batch.len(), lsm6ds3::FIFO_BUFSIZE / config.sample_len());
+	let left = lsm6ds3::FIFO_BUFSIZE / config.fifo_set_len() - batch.len();
+	assert_eq!(model.borrow().fifo.len(), left * config.fifo_set_len());
+
+	let batch = block_on(imu.read_batch()).unwrap();
+	assert_eq!(batch.len(), left);
+	assert_eq!(batch.samples().next().unwrap().gyro[0], (lsm6ds3::FIFO_BUFSIZE / config.sample_len()) as i16);
+}
+
+#[test]
+fn refuses_other_chips() {
+	let (model, mut imu, mut delay) = simulated();
+	// Plain LSM6DS3, different register map
+	model.borrow_mut().who_am_i = 0x69;
+	assert_eq!(block_on(imu.init(ImuConfig::DEFAULT, &mut delay)), Err(Error::WrongDevice(0x69)));
+	assert!(model.borrow().writes.is_empty());
+}
@@ -0,0 +1,41 @@
+use std::collections::HashSet;
+use traccam_common::gyro_format::orientation::Orientation;
+
+fn cross([a0, a1, a2]: [i32; 3], [b0, b1, b2]: [i32; 3]) -> [i32; 3] {
+	[a1 * b2 - a2 * b1, a2 * b0 - a0 * b2, a0 * b1 - a1 * b0]
+}
+
+#[test]
+fn there_are_24_mountings() {
+	let all: Vec<_> = Orientation::all().collect();
+	assert_eq!(all.len(), 24);
+	assert_eq!(all[0], Orientation::IDENTITY);
+	assert_eq!(all.iter().collect::<HashSet<_>>().len(), 24);
+
+	for orientation in all {
+		assert_eq!(Orientation::parse(orientation.as_str().as_bytes()), Some(orientation));
+		// A rotation keeps the axes right handed
+		let [x, y, z] = [[1, 0, 0], [0, 1, 0], [0, 0, 1]].map(|v| orientation.rotate(v));
+		assert_eq!(cross(x, y), z, "{orientation}");
+	}
+}
+
+#[test]
+fn rejects_mirrors_and_garbage() {
+	for text in ["XYz", "xyz", "YXZ", "XXZ", "XY", "XYZX", "ABC", ""] {
+		assert_eq!(Orientation::parse(text.as_bytes()), None, "{text}");
+	}
+	for text in ["XYZ", "xyZ", "yXZ", "ZXY", "zYX"] {
+		assert!(Orientation::parse(text.as_bytes()).is_some(), "{text}");
+	}
+}
+
+#[test]
+fn rotates_imu_into_camera_axes() {
+	let v = [1.0, 2.0, 3.0];
+	assert_eq!(Orientation::IDENTITY.rotate(v), v);
+	// Camera X is IMU -Y, camera Y is IMU X
+	assert_eq!(Orientation::parse(b"yXZ").unwrap().rotate(v), [-2.0, 1.0, 3.0]);
+	assert_eq!(Orientation::parse(b"ZXY").unwrap().rotate(v), [3.0, 1.0, 2.0]);
+	assert_eq!(Orientation::parse(b"xyZ").unwrap().rotate([1, -2, 3]), [-1, 2, 3]);
+}
@@ -0,0 +1,271 @@
+//! Recordings cut short by pulling the power at random points, what is left on the card and
+//! what a scan of the raw image gets back
+
+use embassy_futures::block_on;
+use embedded_hal_async::delay::DelayNs;
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx, Mode, TimeSource, Timestamp, VolumeIdx, VolumeManager};
+use std::cell::Cell;
+use std::io::{self, Read};
+use std::rc::Rc;
+use traccam_common::gyro_format::binary::{BinGyroHeader, BlockEncoder, BlockHeader, Reader, SensorConfig};
+use traccam_common::gyro_format::recover::{Found, Scanner};
+use traccam_common::imu::lsm6ds3::ImuConfig;
+use traccam_common::sd_storage::{FlushPolicy, MemoryDevice, OutOfRange, Storage};
+use zerocopy::IntoBytes;
+
+const SENSOR: SensorConfig = ImuConfig::DEFAULT.sensor_config();
+const BLOCKS: usize = 400;
+const CUTS: u64 = 8;
+
+struct Clock;
+
+impl TimeSource for Clock {
+	fn get_timestamp(&self) -> Timestamp {
+		Timestamp::from_calendar(2026, 10, 17, 12, 0, 0).unwrap()
+	}
+}
+
+struct NoDelay;
+
+impl DelayNs for NoDelay {
+	async fn delay_ns(&mut self, _ns: u32) {}
+}
+
+/// A card that stops taking writes after a set number of blocks, like one losing power
+#[derive(Clone)]
+struct Card {
+	memory: Rc<MemoryDevice>,
+	written: Rc<Cell<usize>>,
+	cut_at: usize,
+}
+
+impl Card {
+	fn new(cut_at: usize) -> Self {
+		Self { memory: Rc::new(MemoryDevice::fat32(64 << 20)), written: Rc::new(Cell::new(0)), cut_at }
+	}
+}
+
+impl BlockDevice for Card {
+	type Error = OutOfRange;
+
+	fn read(&self, blocks: &mut [Block], start_block_idx: BlockIdx) -> Result<(), OutOfRange> {
+		self.memory.read(blocks, start_block_idx)
+	}
+
+	fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), OutOfRange> {
+		let landed = (self.cut_at - self.written.get()).min(blocks.len());
+		self.memory.write(&blocks[..landed], start_block_idx)?;
+		self.written.set(self.written.get() + landed);
+		match landed == blocks.len() {
+			true => Ok(()),
+			false => Err(OutOfRange(start_block_idx.0 + landed as u32)),
+		}
+	}
+
+	fn num_blocks(&self) -> Result<BlockCount, OutOfRange> {
+		self.memory.num_blocks()
+	}
+}
+
+/// The raw card, as `dd` would dump it. Blank blocks are left out, there is nothing to find in
+/// them and scanning them all takes long in debug builds.
+struct Image<'a> {
+	memory: &'a MemoryDevice,
+	block: u32,
+	pending: Vec<u8>,
+}
+
+impl Read for Image<'_> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		while self.pending.is_empty() {
+			if self.block == self.memory.num_blocks().unwrap().0 {
+				return Ok(0);
+			}
+			let block = self.memory.read_block(self.block);
+			if block.iter().any(|&b| b != 0) {
+				self.pending = block.to_vec();
+			}
+			self.block += 1;
+		}
+		let len = buf.len().min(self.pending.len());
+		buf[..len].copy_from_slice(&self.pending[..len]);
+		self.pending.drain(..len);
+		Ok(len)
+	}
+}
+
+struct Rng(u64);
+
+impl Rng {
+	fn next(&mut self) -> u64 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		self.0
+	}
+
+	fn below(&mut self, n: u64) -> u64 {
+		self.next() % n
+	}
+}
+
+/// Blocks like the IMU delivers them, a varying number of samples every few ms
+fn blocks(rng: &mut Rng, start: u64) -> Vec<(BlockHeader, Vec<u8>)> {
+	let mut encoder = BlockEncoder::new(&SENSOR);
+	let mut timestamp = start;
+	(0..BLOCKS)
+		.map(|_| {
+			let samples = 20 + rng.below(40) as usize;
+			let payload: Vec<u8> = (0..samples * SENSOR.sample_len()).map(|_| rng.next() as u8).collect();
+			timestamp += samples as u64 * 602;
+			(encoder.frame(timestamp, &payload), payload)
+		})
+		.collect()
+}
+
+/// Records `blocks` like the firmware does until the card fails, returns how many made it
+/// through `LogFile::write`. Only cuts after the log header are of interest, the card must
+/// take the writes up to there.
+fn record(card: &Card, blocks: &[(BlockHeader, Vec<u8>)], flush: FlushPolicy, preallocate: Option<u32>) -> usize {
+	let storage = block_on(Storage::mount(card.clone(), Clock, &mut NoDelay)).unwrap();
+	let (_, mut file) = storage.create_log(None, flush).unwrap();
+	if let Some(len) = preallocate {
+		file.preallocate(len).unwrap();
+	}
+	file.write(BinGyroHeader::new(SENSOR).with_segment(1, 0).as_bytes()).unwrap();
+	file.flush().unwrap();
+	for (written, (header, payload)) in blocks.iter().enumerate() {
+		let result = file.write(header.as_bytes()).and_then(|_| file.write(payload));
+		if result.and_then(|_| file.tick(header.timestamp())).is_err() {
+			return written;
+		}
+	}
+	let _ = file.close();
+	blocks.len()
+}
+
+/// Blocks of the log file as the filesystem has it
+fn read_log(memory: &MemoryDevice) -> Vec<(BlockHeader, Vec<u8>)> {
+	let volume_mgr = VolumeManager::new(memory.clone(), Clock);
+	let volume = volume_mgr.open_volume(VolumeIdx(0)).unwrap();
+	let root = volume.open_root_dir().unwrap();
+	let file = root.open_file_in_dir("LOG00001.BIN", Mode::ReadOnly).unwrap();
+	let mut content = vec![0; file.length() as usize];
+	let mut read = 0;
+	while read < content.len() {
+		read += file.read(&mut content[read..]).unwrap();
+	}
+	// Only damage at the end, where the power went
+	let reader = Reader::new(content.as_slice()).unwrap();
+	reader.map_while(Result::ok).map(|block| (block.header, block.payload)).collect()
+}
+
+/// Blocks the scan finds for the log
+fn recover(memory: &MemoryDevice) -> Vec<(BlockHeader, Vec<u8>)> {
+	let mut logs = 0;
+	let mut blocks = vec![];
+	for found in Scanner::new(Image { memory, block: 0, pending: vec![] }) {
+		match found.unwrap() {
+			Found::Log { header, .. } => {
+				assert_eq!(header, BinGyroHeader::new(SENSOR).with_segment(1, 0));
+				logs += 1;
+			}
+			Found::Block { header, payload, .. } => blocks.push((header, payload)),
+		}
+	}
+	assert_eq!(logs, 1);
+	blocks
+}
+
+/// A random point to cut the power at, somewhere after the log header
+fn random_cut(rng: &mut Rng, blocks: &[(BlockHeader, Vec<u8>)], flush: FlushPolicy, preallocate: Option<u32>) -> Card {
+	let card = Card::new(usize::MAX);
+	assert_eq!(record(&card, &[], flush, preallocate), 0);
+	let start = card.written.get() as u64;
+	let card = Card::new(usize::MAX);
+	assert_eq!(record(&card, blocks, flush, preallocate), blocks.len());
+	let end = card.written.get() as u64;
+	Card::new((start + rng.below(end - start)) as usize)
+}
+
+fn is_prefix(found: &[(BlockHeader, Vec<u8>)], blocks: &[(BlockHeader, Vec<u8>)]) -> bool {
+	found.len() <= blocks.len() && found == &blocks[..found.len()]
+}
+
+#[test]
+fn timed_flush_keeps_all_but_the_last_moments() {
+	let mut rng = Rng(0x5EED_0001);
+	let blocks = blocks(&mut rng, 0);
+	let flush = FlushPolicy::EveryMicros(250_000);
+	for _ in 0..CUTS {
+		let card = random_cut(&mut rng, &blocks, flush, None);
+		let written = record(&card, &blocks, flush, None);
+
+		let in_file = read_log(&card.memory);
+		assert!(is_prefix(&in_file, &blocks));
+		// Up to two flush intervals may be missing, if the power went during a flush
+		let last = written.checked_sub(1).map_or(0, |last| blocks[last].0.timestamp());
+		let durable = blocks[..written].iter().filter(|(h, _)| h.timestamp() + 500_000 + 60 * 602 <= last).count();
+		assert!(in_file.len() >= durable, "{} of {written} blocks in the file", in_file.len());
+
+		// The rest is still on the card
+		let recovered = recover(&card.memory);
+		assert!(is_prefix(&recovered, &blocks));
+		assert!(recovered.len() >= in_file.len().max(written.saturating_sub(2)));
+	}
+}
+
+#[test]
+fn preallocated_log_keeps_what_reached_the_card() {
+	let mut rng = Rng(0x5EED_0002);
+	let blocks = blocks(&mut rng, 0);
+	let preallocate = Some(256 * 1024);
+	for _ in 0..CUTS {
+		let card = random_cut(&mut rng, &blocks, FlushPolicy::OnClose, preallocate);
+		let written = record(&card, &blocks, FlushPolicy::OnClose, preallocate);
+
+		// Never flushed, the preallocated length covers it anyway
+		let in_file = read_log(&card.memory);
+		assert!(is_prefix(&in_file, &blocks));
+		assert!(in_file.len() >= written.saturating_sub(2), "{} of {written} blocks in the file", in_file.len());
+
+		let recovered = recover(&card.memory);
+		assert!(is_prefix(&recovered, &blocks));
+		assert!(recovered.len() >= in_file.len());
+	}
+}
+
+#[test]
+fn scan_skips_stale_blocks_of_an_older_recording() {
+	let mut rng = Rng(0x5EED_0003);
+	// From an earlier boot, the clock had been running for a while
+	let old = blocks(&mut rng, 3_600_000_000);
+	let new = blocks(&mut rng, 0);
+	let header = BinGyroHeader::new(SENSOR).with_segment(1, 0);
+	let log = |blocks: &[(BlockHeader, Vec<u8>)]| {
+		let mut log = header.as_bytes().to_vec();
+		for (header, payload) in blocks {
+			log.extend_from_slice(header.as_bytes());
+			log.extend_from_slice(payload);
+		}
+		log
+	};
+
+	// A shorter recording over a longer one, the old block numbers go on but their times don't
+	let mut image = vec![0xFF; 4096];
+	let start = image.len();
+	image.extend_from_slice(&log(&old));
+	let new_log = log(&new[..100]);
+	image[start..start + new_log.len()].copy_from_slice(&new_log);
+
+	let found: Vec<_> = Scanner::new(image.as_slice()).collect::<io::Result<_>>().unwrap();
+	assert_eq!(found[0], Found::Log { offset: start as u64, header });
+	let recovered: Vec<_> = found[1..]
+		.iter()
+		.map(|found| match found {
+			Found::Block { header, payload, .. } => (header.clone(), payload.clone()),
+			Found::Log { .. } => panic!("Only one log on the card"),
+		})
+		.collect();
+	assert_eq!(recovered, new[..100]);
+}
@@ -0,0 +1,440 @@
+//! The storage layer on FAT32 images in RAM, read back through `embedded_sdmmc` itself
+
+use embassy_futures::block_on;
+use embedded_hal_async::delay::DelayNs;
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx, Mode, TimeSource, Timestamp, VolumeIdx, VolumeManager};
+use chrono::NaiveDate;
+use std::cell::Cell;
+use std::rc::Rc;
+use traccam_common::sd_storage::{
+	Error, FlushPolicy, LogName, MemoryDevice, OutOfRange, Rollover, Storage, MOUNT_ATTEMPTS,
+};
+
+struct Clock;
+
+impl TimeSource for Clock {
+	fn get_timestamp(&self) -> Timestamp {
+		Timestamp::from_calendar(2026, 10, 17, 12, 0, 0).unwrap()
+	}
+}
+
+/// Adds up the time waited instead of waiting
+#[derive(Default)]
+struct Delay {
+	waited_ms: u32,
+}
+
+impl DelayNs for Delay {
+	async fn delay_ns(&mut self, ns: u32) {
+		self.waited_ms += ns / 1_000_000;
+	}
+}
+
+/// A card that can be looked at while the storage layer holds it
+#[derive(Clone)]
+struct Card {
+	memory: Rc<MemoryDevice>,
+	/// Size requests to fail before the card answers, like a card still powering up
+	absent: Rc<Cell<u32>>,
+	writes: Rc<Cell<usize>>,
+}
+
+impl Card {
+	fn new(memory: MemoryDevice) -> Self {
+		Self { memory: Rc::new(memory), absent: Rc::default(), writes: Rc::default() }
+	}
+
+	/// What a power cut right now would leave on the card
+	fn snapshot(&self) -> MemoryDevice {
+		(*self.memory).clone()
+	}
+}
+
+impl BlockDevice for Card {
+	type Error = OutOfRange;
+
+	fn read(&self, blocks: &mut [Block], start_block_idx: BlockIdx) -> Result<(), OutOfRange> {
+		self.memory.read(blocks, start_block_idx)
+	}
+
+	fn write(&self, blocks: &[Block], start_block_idx: BlockIdx) -> Result<(), OutOfRange> {
+		self.writes.set(self.writes.get() + blocks.len());
+		self.memory.write(blocks, start_block_idx)
+	}
+
+	fn num_blocks(&self) -> Result<BlockCount, OutOfRange> {
+		match self.absent.get() {
+			0 => self.memory.num_blocks(),
+			absent => {
+				self.absent.set(absent - 1);
+				Err(OutOfRange(0))
+			}
+		}
+	}
+}
+
+fn mount<D: BlockDevice>(device: D) -> Storage<D, Clock> {
+	block_on(Storage::mount(device, Clock, &mut Delay::default())).unwrap()
+}
+
+/// A file's content as `embedded_sdmmc` reads it, None if there is no such file
+fn read(card: impl BlockDevice, path: &str) -> Option<Vec<u8>> {
+	let volume_mgr = VolumeManager::new(card, Clock);
+	let volume = volume_mgr.open_volume(VolumeIdx(0)).unwrap();
+	let mut dir = volume.open_root_dir().unwrap();
+	let (dirs, name) = path.rsplit_once('/').unwrap_or(("", path));
+	for part in dirs.split('/').filter(|p| !p.is_empty()) {
+		dir.change_dir(part).ok()?;
+	}
+	let file = dir.open_file_in_dir(name, Mode::ReadOnly).ok()?;
+	let mut content = vec![0; file.length() as usize];
+	let mut read = 0;
+	while read < content.len() {
+		read += file.read(&mut content[read..]).unwrap();
+	}
+	Some(content)
+}
+
+/// Bytes that differ from block to block
+fn data(len: usize) -> Vec<u8> {
+	(0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+#[test]
+fn writes_read_back() {
+	let card = Card::new(MemoryDevice::fat32(64 << 20));
+	let storage = mount(card.clone());
+	assert_eq!(storage.card_size(), 64 << 20);
+
+	let data = data(300_000);
+	let mut file = storage.create(None, "LOG.BIN", FlushPolicy::OnClose).unwrap();
+	let mut rest = &data[..];
+	// Odd sizes, block sized and larger than a block
+	for size in [1, 41, 511, 512, 513, 2000, 12, 4096].iter().cycle() {
+		let (chunk, tail) = rest.split_at((*size).min(rest.len()));
+		file.write(chunk).unwrap();
+		rest = tail;
+		if rest.is_empty() {
+			break;
+		}
+	}
+	assert_eq!(file.len(), data.len() as u32);
+	file.close().unwrap();
+	storage.unmount().unwrap();
+
+	assert_eq!(read(card, "LOG.BIN").unwrap(), data);
+}
+
+#[test]
+fn small_writes_reach_the_card_as_blocks() {
+	// 32 KiB clusters like a real card, so cluster allocation hardly adds writes
+	let card = Card::new(MemoryDevice::fat32(4 << 30));
+	let storage = mount(card.clone());
+	let mut file = storage.create(None, "LOG.BIN", FlushPolicy::OnClose).unwrap();
+	let before = card.writes.get();
+	for chunk in data(1_000_000).chunks(41) {
+		file.write(chunk).unwrap();
+	}
+	let blocks = 1_000_000 / 512;
+	let written = card.writes.get() - before;
+	// Every 41 bytes would mean 24000 writes
+	assert!(written < blocks + blocks / 8, "{written} block writes for {blocks} blocks");
+	drop(file);
+	storage.unmount().unwrap();
+	assert_eq!(read(card, "LOG.BIN").unwrap(), data(1_000_000));
+}
+
+#[test]
+fn never_overwrites() {
+	let card = Card::new(MemoryDevice::fat32(64 << 20));
+	let storage = mount(card.clone());
+	let mut file = storage.create(None, "LOG.BIN", FlushPolicy::OnClose).unwrap();
+	file.write(b"first").unwrap();
+	file.close().unwrap();
+	assert!(matches!(storage.create(None, "LOG.BIN", FlushPolicy::OnClose), Err(Error::Exists)));
+	storage.unmount().unwrap();
+	assert_eq!(read(card, "LOG.BIN").unwrap(), b"first");
+}
+
+#[test]
+fn files_in_directories() {
+	let card = Card::new(MemoryDevice::fat32(64 << 20));
+	let storage = mount(card.clone());
+	for (dir, name, content) in [("20261017", "A.BIN", &b"a"[..]), ("20261017", "B.BIN", b"b"), ("20261018", "A.BIN", b"c")] {
+		let mut file = storage.create(Some(dir), name, FlushPolicy::OnClose).unwrap();
+		file.write(content).unwrap();
+		file.close().unwrap();
+	}
+	storage.unmount().unwrap();
+	assert_eq!(read(card.clone(), "20261017/A.BIN").unwrap(), b"a");
+	assert_eq!(read(card.clone(), "20261017/B.BIN").unwrap(), b"b");
+	assert_eq!(read(card.clone(), "20261018/A.BIN").unwrap(), b"c");
+	assert_eq!(read(card, "A.BIN"), None);
+}
+
+#[test]
+fn flush_policy_decides_what_survives_a_power_cut() {
+	let card = Card::new(MemoryDevice::fat32(64 << 20));
+	let storage = mount(card.clone());
+	let data = data(10_000);
+
+	let mut file = storage.create(None, "CLOSE.BIN", FlushPolicy::OnClose).unwrap();
+	file.write(&data).unwrap();
+	assert_eq!(read(card.snapshot(), "CLOSE.BIN").unwrap(), b"");
+	file.close().unwrap();
+	assert_eq!(read(card.snapshot(), "CLOSE.BIN").unwrap(), data);
+
+	let mut file = storage.create(None, "FLUSH.BIN", FlushPolicy::EveryBytes(4096)).unwrap();
+	for chunk in data[..4000].chunks(1000) {
+		file.write(chunk).unwrap();
+	}
+	assert_eq!(read(card.snapshot(), "FLUSH.BIN").unwrap(), b"");
+	// Past 4096 bytes on the card, the partial block goes out along with the entry
+	for chunk in data[4000..7000].chunks(1000) {
+		file.write(chunk).unwrap();
+	}
+	assert_eq!(read(card.snapshot(), "FLUSH.BIN").unwrap(), data[..5000]);
+	file.write(&data[7000..]).unwrap();
+	file.flush().unwrap();
+	assert_eq!(read(card.snapshot(), "FLUSH.BIN").unwrap(), data);
+	file.write(&data).unwrap();
+	file.close().unwrap();
+	storage.unmount().unwrap();
+	assert_eq!(read(card, "FLUSH.BIN").unwrap(), [&data[..], &data[..]].concat());
+}
+
+#[test]
+fn timed_flush() {
+	let card = Card::new(MemoryDevice::fat32(64 << 20));
+	let storage = mount(card.clone());
+	let data = data(6000);
+	let mut file = storage.create(None, "TIMED.BIN", FlushPolicy::EveryMicros(1_000_000)).unwrap();
+	file.write(&data[..3000]).unwrap();
+	file.tick(5_000_000).unwrap();
+	file.tick(5_999_999).unwrap();
+	assert_eq!(read(card.snapshot(), "TIMED.BIN").unwrap(), b"");
+	file.tick(6_000_000).unwrap();
+	assert_eq!(read(card.snapshot(), "TIMED.BIN").unwrap(), data[..3000]);
+	file.write(&data[3000..]).unwrap();
+	file.tick(6_500_000).unwrap();
+	assert_eq!(read(card.snapshot(), "TIMED.BIN").unwrap(), data[..3000]);
+	file.tick(7_000_000).unwrap();
+	assert_eq!(read(card.snapshot(), "TIMED.BIN").unwrap(), data);
+
+	// Other policies don't care about the time
+	let mut file = storage.create(None, "CLOSE.BIN", FlushPolicy::OnClose).unwrap();
+	file.write(&data).unwrap();
+	file.tick(0).unwrap();
+	file.tick(u64::MAX).unwrap();
+	assert_eq!(read(card.snapshot(), "CLOSE.BIN").unwrap(), b"");
+}
+
+#[test]
+fn preallocated_file_holds_data_before_any_flush() {
+	let card = Card::new(MemoryDevice::fat32(64 << 20));
+	let storage = mount(card.clone());
+	let data = data(10_000);
+	let mut file = storage.create(None, "PREALLOC.BIN", FlushPolicy::OnClose).unwrap();
+	file.preallocate(8192).unwrap();
+	assert!(file.is_empty());
+	file.write(&data[..3000]).unwrap();
+
+	// Whole blocks are on the card and in the file, the rest is still zeros
+	let on_card = read(card.snapshot(), "PREALLOC.BIN").unwrap();
+	assert_eq!(on_card.len(), 8192);
+	assert_eq!(on_card[..2560], data[..2560]);
+	assert!(on_card[2560..].iter().all(|&b| b == 0));
+
+	file.overwrite(0, b"HEAD").unwrap();
+	file.write(&data[3000..]).unwrap();
+	assert!(file.preallocate(8192).is_err());
+	file.close().unwrap();
+	storage.unmount().unwrap();
+	assert_eq!(read(card, "PREALLOC.BIN").unwrap(), [&b"HEAD"[..], &data[4..]].concat());
+}
+
+#[test]
+fn preallocated_file_keeps_its_length() {
+	let card = Card::new(MemoryDevice::fat32(64 << 20));
+	let storage = mount(card.clone());
+	let mut file = storage.create(None, "SHORT.BIN", FlushPolicy::OnClose).unwrap();
+	file.preallocate(4096).unwrap();
+	file.write(&data(1000)).unwrap();
+	assert_eq!(file.len(), 1000);
+	file.close().unwrap();
+	storage.unmount().unwrap();
+	let content = read(card, "SHORT.BIN").unwrap();
+	assert_eq!(content[..1000], data(1000));
+	assert_eq!(content[1000..], [0; 3096]);
+}
+
+#[test]
+fn header_filled_in_later() {
+	let card = Card::new(MemoryDevice::fat32(64 << 20));
+	let storage = mount(card.clone());
+	let mut file = storage.create(None, "LOG.BIN", FlushPolicy::OnClose).unwrap();
+	file.write(&[0; 16]).unwrap();
+	file.write(&data(1000)).unwrap();
+	file.overwrite(4, b"HEAD").unwrap();
+	file.write(b"tail").unwrap();
+	assert!(file.overwrite(1018, b"too far").is_err());
+	file.close().unwrap();
+	storage.unmount().unwrap();
+
+	let content = read(card, "LOG.BIN").unwrap();
+	assert_eq!(&content[..8], b"\0\0\0\0HEAD");
+	assert_eq!(content[16..1016], data(1000));
+	assert_eq!(&content[1016..], b"tail");
+}
+
+#[test]
+fn retries_a_card_that_is_slow_to_start() {
+	let card = Card::new(MemoryDevice::fat32(64 << 20));
+	card.absent.set(MOUNT_ATTEMPTS - 1);
+	let mut delay = Delay::default();
+	assert!(block_on(Storage::mount(card.clone(), Clock, &mut delay)).is_ok());
+	assert!(delay.waited_ms > 0);
+
+	card.absent.set(MOUNT_ATTEMPTS);
+	assert!(matches!(block_on(Storage::mount(card, Clock, &mut delay)), Err(Error::NoCard(_))));
+}
+
+#[test]
+fn refuses_unformatted_cards() {
+	let result = block_on(Storage::mount(MemoryDevice::new(64 << 20), Clock, &mut Delay::default()));
+	assert!(matches!(result, Err(Error::NoFilesystem)));
+}
+
+/// A card that has seen some use: logs in the root and in date folders, with gaps, and other
+/// files and folders with names close to a log's
+fn used_card() -> Card {
+	let card = Card::new(MemoryDevice::fat32(64 << 20));
+	let storage = mount(card.clone());
+	let mut files = vec![];
+	files.extend((1..=150).filter(|i| i % 7 != 0).map(|i| (None, format!("LOG{i:05}.BIN"))));
+	files.extend((151..=200).map(|i| (Some("20261015"), format!("LOG{i:05}.BIN"))));
+	files.push((Some("20261016"), "LOG00420.BIN".into()));
+	for name in ["LOG-17.BIN", "LOG00999.CSV", "LOG0999A.BIN", "LOG9999.BIN", "README.TXT"] {
+		files.push((None, name.into()));
+	}
+	// Only date folders count
+	files.push((Some("DCIM"), "LOG05000.BIN".into()));
+	files.push((Some("2026101"), "LOG05001.BIN".into()));
+	for (dir, name) in files {
+		let mut file = storage.create(dir, &name, FlushPolicy::OnClose).unwrap();
+		file.write(name.as_bytes()).unwrap();
+		file.close().unwrap();
+	}
+	storage.unmount().unwrap();
+	card
+}
+
+#[test]
+fn log_names() {
+	let date = NaiveDate::from_ymd_opt(2026, 10, 17);
+	assert_eq!(LogName { date: None, index: 1 }.to_string(), "LOG00001.BIN");
+	assert_eq!(LogName { date, index: 99_999 }.to_string(), "20261017/LOG99999.BIN");
+}
+
+#[test]
+fn logs_continue_the_numbering() {
+	let card = used_card();
+	let storage = mount(card.clone());
+	let date = NaiveDate::from_ymd_opt(2026, 10, 17);
+
+	let (name, mut file) = storage.create_log(None, FlushPolicy::OnClose).unwrap();
+	assert_eq!(name, LogName { date: None, index: 421 });
+	file.write(b"first").unwrap();
+	file.close().unwrap();
+	let (name, mut file) = storage.create_log(date, FlushPolicy::OnClose).unwrap();
+	assert_eq!(name.to_string(), "20261017/LOG00422.BIN");
+	file.write(b"second").unwrap();
+	file.close().unwrap();
+	storage.unmount().unwrap();
+
+	assert_eq!(read(card.clone(), "LOG00421.BIN").unwrap(), b"first");
+	assert_eq!(read(card.clone(), "20261017/LOG00422.BIN").unwrap(), b"second");
+	// Everything that was there is untouched
+	assert_eq!(read(card.clone(), "LOG00150.BIN").unwrap(), b"LOG00150.BIN");
+	assert_eq!(read(card.clone(), "20261015/LOG00151.BIN").unwrap(), b"LOG00151.BIN");
+	assert_eq!(read(card, "DCIM/LOG05000.BIN").unwrap(), b"LOG05000.BIN");
+}
+
+#[test]
+fn first_log_on_a_fresh_card() {
+	let card = Card::new(MemoryDevice::fat32(64 << 20));
+	let storage = mount(card);
+	let date = NaiveDate::from_ymd_opt(2026, 10, 17);
+	assert_eq!(storage.create_log(date, FlushPolicy::OnClose).unwrap().0, LogName { date, index: 1 });
+	assert_eq!(storage.create_log(date, FlushPolicy::OnClose).unwrap().0, LogName { date, index: 2 });
+}
+
+#[test]
+fn skips_names_taken_by_something_else() {
+	let card = used_card();
+	{
+		let volume_mgr = VolumeManager::new(card.clone(), Clock);
+		let volume = volume_mgr.open_volume(VolumeIdx(0)).unwrap();
+		let root = volume.open_root_dir().unwrap();
+		root.make_dir_in_dir("LOG00421.BIN").unwrap();
+	}
+	let storage = mount(card);
+	assert_eq!(storage.create_log(None, FlushPolicy::OnClose).unwrap().0.index, 422);
+}
+
+#[test]
+fn runs_out_of_numbers() {
+	let card = Card::new(MemoryDevice::fat32(64 << 20));
+	let storage = mount(card);
+	storage.create(None, "LOG99999.BIN", FlushPolicy::OnClose).unwrap().close().unwrap();
+	assert!(matches!(storage.create_log(None, FlushPolicy::OnClose), Err(Error::Exists)));
+}
+
+#[test]
+fn rollover_limits() {
+	let rollover = Rollover::new(10_000);
+	assert!(!rollover.is_due(9_000, 1_000, u64::MAX));
+	assert!(rollover.is_due(9_001, 1_000, 0));
+	// A write larger than the limit still has to go somewhere
+	assert!(!rollover.is_due(0, 20_000, 0));
+
+	let rollover = rollover.with_max_duration(60_000_000);
+	assert!(!rollover.is_due(100, 100, 59_999_999));
+	assert!(rollover.is_due(100, 100, 60_000_000));
+	assert!(!rollover.is_due(0, 100, 60_000_000));
+
+	assert!(!Rollover::FAT32_LIMIT.is_due(u32::MAX - 512, 512, u64::MAX));
+	assert!(Rollover::FAT32_LIMIT.is_due(u32::MAX - 512, 513, 0));
+}
+
+#[test]
+fn recording_rolls_over_into_numbered_logs() {
+	let card = Card::new(MemoryDevice::fat32(64 << 20));
+	let storage = mount(card.clone());
+	let rollover = Rollover::new(10_000).with_max_duration(1_000_000);
+	let date = NaiveDate::from_ymd_opt(2026, 10, 17);
+	let recording = data(50_000);
+
+	// 700 bytes every 10ms, the size limit is reached before the duration
+	let (mut name, mut file) = storage.create_log(date, FlushPolicy::OnClose).unwrap();
+	let mut started = 0;
+	let mut names = vec![];
+	for (i, chunk) in recording.chunks(700).enumerate() {
+		let now = i as u64 * 10_000;
+		if rollover.is_due(file.len(), chunk.len(), now - started) {
+			file.close().unwrap();
+			names.push(name);
+			(name, file) = storage.create_log(date, FlushPolicy::OnClose).unwrap();
+			started = now;
+		}
+		file.write(chunk).unwrap();
+	}
+	file.close().unwrap();
+	names.push(name);
+	storage.unmount().unwrap();
+
+	assert_eq!(names.iter().map(|n| n.index).collect::<Vec<_>>(), [1, 2, 3, 4, 5, 6]);
+	let logs: Vec<_> = names.iter().map(|n| read(card.clone(), &n.to_string()).unwrap()).collect();
+	assert!(logs.iter().all(|log| log.len() <= 10_000));
+	assert_eq!(logs.concat(), recording);
+}
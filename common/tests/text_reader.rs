@@ -0,0 +1,133 @@
+use traccam_common::gyro_format::orientation::Orientation;
+use traccam_common::gyro_format::text::{
+	self, get_header_string, row_string, Channels, Error, Reader, Record, Row, RowScale, GSCALE,
+};
+use traccam_common::imu::ImuSample;
+use traccam_common::imu::lsm6ds3::ImuConfig;
+
+fn read(data: &str) -> Result<Vec<Record>, Error> {
+	Reader::new(data.as_bytes())?.collect()
+}
+
+#[test]
+fn write_read_round_trip() {
+	let info = ImuConfig::DEFAULT.info();
+	let scale = RowScale::new(info.gyro_dps_per_lsb, info.accel_g_per_lsb);
+	let samples = [
+		ImuSample { gyro: [-265, -5, 200], accel: [-5906, 1764, 13596], ..Default::default() },
+		ImuSample { gyro: [i16::MAX, i16::MIN, 0], accel: [1, -1, 16393], ..Default::default() },
+	];
+	let mounting = Orientation::parse(b"zYX").unwrap();
+	let mut log = get_header_string(mounting, Channels::NONE).to_string();
+	let header_len = log.len();
+	log += &row_string(&scale.row(0, &samples[0]), Channels::NONE);
+	log += &row_string(&Row::lost(602), Channels::NONE);
+	log += &row_string(&scale.row(1205, &samples[1]), Channels::NONE);
+
+	let reader = Reader::new(log.as_bytes()).unwrap();
+	assert_eq!(reader.offset(), header_len as u64);
+	let header = reader.header().clone();
+	assert_eq!(header.version.as_deref(), Some("1.3"));
+	assert_eq!(header.id.as_deref(), Some("traccam_v1"));
+	assert_eq!(header.orientation, mounting);
+	assert_eq!((header.tscale, header.gscale, header.ascale), (text::TSCALE, GSCALE, text::ASCALE));
+	assert_eq!(header.mscale, None);
+
+	let records: Vec<Record> = reader.collect::<Result<_, _>>().unwrap();
+	assert_eq!(records.len(), 3);
+	assert!(records[1].is_lost());
+	assert_eq!(records[1].t, 602.0);
+	for (record, (t, sample)) in [&records[0], &records[2]].into_iter().zip([(0, samples[0]), (1205, samples[1])]) {
+		let values = scale.values(&sample).map(|nanos| nanos as f64 / 1e9);
+		assert_eq!(record.t, t as f64);
+		assert_eq!(record.gyro, values[..3]);
+		assert_eq!(record.accel, values[3..]);
+		assert!(!record.is_lost());
+	}
+}
+
+#[test]
+fn mag_and_temperature_round_trip() {
+	let info = ImuConfig::DEFAULT.info();
+	let scale = RowScale::new(info.gyro_dps_per_lsb, info.accel_g_per_lsb).with_mag(0.15).with_temperature(1.0 / 256.0, 25.0);
+	let sample = ImuSample { gyro: [1, 2, 3], accel: [4, 5, 6], mag: Some([-7, 8, 900]), temperature: Some(128) };
+	let mut log = get_header_string(Orientation::IDENTITY, scale.channels()).to_string();
+	log += &row_string(&scale.row(0, &sample), scale.channels());
+	log += &row_string(&Row::lost(602), scale.channels());
+
+	let reader = Reader::new(log.as_bytes()).unwrap();
+	assert_eq!(reader.header().mscale, Some(text::MSCALE));
+	let records: Vec<Record> = reader.collect::<Result<_, _>>().unwrap();
+	assert_eq!(records[0].mag, Some([-1.05, 1.2, 135.0]));
+	assert_eq!(records[0].temperature, Some(25.5));
+	assert!(records[1].is_lost());
+	assert!(records[1].mag.unwrap().iter().all(|v| v.is_nan()));
+	assert!(records[1].temperature.unwrap().is_nan());
+}
+
+#[test]
+fn quaternion_round_trip() {
+	let info = ImuConfig::DEFAULT.info();
+	let scale = RowScale::new(info.gyro_dps_per_lsb, info.accel_g_per_lsb);
+	let channels = Channels { quaternion: true, ..Channels::NONE };
+	let mut log = get_header_string(Orientation::IDENTITY, channels).to_string();
+	let row = Row { quaternion: Some([600_000_000, 0, -800_000_000, 1]), ..scale.row(0, &ImuSample::default()) };
+	log += &row_string(&row, channels);
+	log += &row_string(&Row::lost(602), channels);
+
+	let records: Vec<Record> = Reader::new(log.as_bytes()).unwrap().collect::<Result<_, _>>().unwrap();
+	assert_eq!(records[0].quaternion, Some([0.6, 0.0, -0.8, 0.000000001]));
+	assert_eq!(records[0].mag, None);
+	assert!(records[1].quaternion.unwrap().iter().all(|v| v.is_nan()));
+}
+
+#[test]
+fn optional_columns() {
+	let log = "GYROFLOW IMU LOG\nversion,1.3\ntscale,0.001\ngscale,1\nascale,1\nmscale,0.5\n\
+		t,ax,ay,az,gx,gy,gz,mx,my,mz,temp,extra\n10, 1,2,3, 4,5,6, 7,8,9, 31.5,x\n";
+	let reader = Reader::new(log.as_bytes()).unwrap();
+	assert_eq!(reader.header().mscale, Some(0.5));
+	assert_eq!(reader.header().orientation, Orientation::IDENTITY);
+	assert_eq!(reader.header().get("extra"), None);
+	let records: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+	assert_eq!(
+		records,
+		[Record { t: 10.0, gyro: [4.0, 5.0, 6.0], accel: [1.0, 2.0, 3.0], mag: Some([7.0, 8.0, 9.0]), temperature: Some(31.5), quaternion: None }]
+	);
+}
+
+#[test]
+fn refuses_bad_headers() {
+	let header = |lines: &str| format!("GYROFLOW IMU LOG,\n{lines}t,gx,gy,gz,ax,ay,az\n");
+	let bad_header = |log: &str| matches!(Reader::new(log.as_bytes()), Err(Error::BadHeader { .. }));
+
+	assert!(matches!(Reader::new(&b"t,gx\n1,2\n"[..]), Err(Error::BadMagic)));
+	assert!(matches!(Reader::new(&b""[..]), Err(Error::BadMagic)));
+	assert!(Reader::new(header("tscale,1\ngscale,1\nascale,1\n").as_bytes()).is_ok());
+	assert!(bad_header(&header("tscale,1\nascale,1\n")));
+	assert!(bad_header(&header("tscale,1\ngscale,fast\nascale,1\n")));
+	assert!(bad_header(&header("tscale,1\ngscale,1\nascale,1\norientation,XYz\n")));
+	assert!(bad_header(&header("tscale,1\ngscale,1\nascale,1\njunk\n")));
+	assert!(bad_header("GYROFLOW IMU LOG\ntscale,1\ngscale,1\nascale,1\nt,gx,gy,gz,ax,ay\n"));
+	assert!(bad_header("GYROFLOW IMU LOG\ntscale,1\ngscale,1\nascale,1\nt,gx,gy,gz,ax,ay,az,mx,my,mz\n"));
+	assert!(bad_header("GYROFLOW IMU LOG\ntscale,1\ngscale,1\nascale,1\nmscale,1\nt,gx,gy,gz,ax,ay,az,mx\n"));
+	assert!(bad_header("GYROFLOW IMU LOG\ntscale,1\ngscale,1\nascale,1\nt,gx,gy,gz,ax,ay,az,qw,qx,qy\n"));
+	assert!(bad_header("GYROFLOW IMU LOG\ntscale,1\ngscale,1\nascale,1\n"));
+}
+
+#[test]
+fn reports_bad_and_cut_rows() {
+	let header = "GYROFLOW IMU LOG\ntscale,1\ngscale,1\nascale,1\nt,gx,gy,gz,ax,ay,az\n";
+	let results: Vec<_> = Reader::new(format!("{header}1,1,1,1,1,1,1\n\n2,1,x,1,1,1,1\n3,1,1,1,1,1,1\n4,1,1").as_bytes())
+		.unwrap()
+		.collect();
+	assert_eq!(results.len(), 4);
+	assert!(results[0].is_ok());
+	assert!(matches!(results[1], Err(Error::BadRow { line: 8, .. })));
+	assert_eq!(results[2].as_ref().unwrap().t, 3.0);
+	let offset = (header.len() + "1,1,1,1,1,1,1\n\n2,1,x,1,1,1,1\n3,1,1,1,1,1,1\n".len()) as u64;
+	assert!(matches!(results[3], Err(Error::Truncated { line: 10, offset: o }) if o == offset));
+
+	// A complete last row without newline is fine
+	assert_eq!(read(&format!("{header}1,1,1,1,1,1,1")).unwrap().len(), 1);
+}
@@ -0,0 +1,92 @@
+use traccam_common::gyro_format::text::{row_string, Channels, Row, RowScale, ROW_MAX_LEN};
+use traccam_common::imu::{icm42688, lsm6ds3, ImuSample, SensorInfo};
+
+fn scale(info: SensorInfo) -> RowScale {
+	RowScale::new(info.gyro_dps_per_lsb, info.accel_g_per_lsb)
+}
+
+#[test]
+fn first_rows_of_legacy_log() {
+	// Start of detrac/LOG.CSV and what detrac converts it to
+	let raw = [
+		0xf7, 0xfe, 0xfb, 0xff, 0xc8, 0x00, 0xee, 0xe8, 0xe4, 0x06, 0x1c, 0x35,
+		0x22, 0x19, 0xd8, 0xee, 0x10, 0xed, 0xa1, 0xe4, 0xc2, 0x07, 0x43, 0x3c,
+	];
+	let scale = scale(lsm6ds3::ImuConfig::DEFAULT.info());
+	let rows: Vec<_> = raw
+		.as_chunks::<{ ImuSample::LEN }>()
+		.0
+		.iter()
+		.zip([0, 602])
+		.map(|(bytes, t)| row_string(&scale.row(t, &ImuSample::from_le_bytes(bytes)), Channels::NONE))
+		.collect();
+	assert_eq!(rows[0], "0,-2.31875, -0.04375, 1.75, -0.360266, 0.107604, 0.829356\n");
+	assert_eq!(rows[1], "602,56.2975, -38.43, -42.42, -0.427427, 0.121146, 0.941047\n");
+}
+
+#[test]
+fn values_are_exact_decimals() {
+	let scale = scale(lsm6ds3::ImuConfig::DEFAULT.info());
+	for raw in [i16::MIN, -1000, -1, 0, 1, 7, 1000, i16::MAX] {
+		let sample = ImuSample { gyro: [raw; 3], accel: [raw; 3], ..Default::default() };
+		let line = row_string(&scale.row(0, &sample), Channels::NONE);
+		let values: Vec<f64> = line.trim_end().split(',').skip(1).map(|v| v.trim().parse().unwrap()).collect();
+		// 8.75 m°/s and 0.061 mg per LSB, without float rounding on the way
+		assert_eq!(values[0], (raw as i64 * 8_750_000) as f64 / 1e9, "{line}");
+		assert_eq!(values[3], (raw as i64 * 61_000) as f64 / 1e9, "{line}");
+	}
+	let zero = row_string(&scale.row(1, &ImuSample::default()), Channels::NONE);
+	assert_eq!(zero, "1,0, 0, 0, 0, 0, 0\n");
+}
+
+#[test]
+fn sensitivities_round_to_nano_units() {
+	let config = icm42688::ImuConfig::DEFAULT;
+	let scale = scale(config.info());
+	let row = scale.row(0, &ImuSample { gyro: [1, 131, 0], accel: [1, 16384, 0], ..Default::default() });
+	// 1/131 °/s is 7633587.8 n°/s, 1/16384 g is 61035.2 ng
+	assert_eq!(row.values, Some([7_633_588, 1_000_000_028, 0, 61_035, 999_997_440, 0]));
+	assert_eq!(row_string(&row, Channels::NONE), "0,0.007633588, 1.000000028, 0, 0.000061035, 0.99999744, 0\n");
+}
+
+#[test]
+fn lost_samples_are_nan() {
+	assert_eq!(row_string(&Row::lost(1204), Channels::NONE), "1204,NaN, NaN, NaN, NaN, NaN, NaN\n");
+}
+
+#[test]
+fn optional_columns_follow_accel() {
+	let info = lsm6ds3::ImuConfig::DEFAULT.info();
+	let scale = scale(info).with_mag(0.15).with_temperature(1.0 / 256.0, 25.0);
+	let all = Channels { mag: true, temperature: true, quaternion: false };
+	assert_eq!(scale.channels(), all);
+
+	let sample = ImuSample { gyro: [0; 3], accel: [0; 3], mag: Some([1, -200, 0]), temperature: Some(-64) };
+	let row = scale.row(5, &sample);
+	assert_eq!(row_string(&row, all), "5,0, 0, 0, 0, 0, 0, 0.15, -30, 0, 24.75\n");
+	assert_eq!(row_string(&row, Channels { temperature: true, ..Channels::NONE }), "5,0, 0, 0, 0, 0, 0, 24.75\n");
+	assert_eq!(row_string(&row, Channels::NONE), "5,0, 0, 0, 0, 0, 0\n");
+
+	// Missing readings keep the columns in line
+	let row = scale.row(6, &ImuSample::default());
+	assert_eq!(row_string(&row, all), "6,0, 0, 0, 0, 0, 0, NaN, NaN, NaN, NaN\n");
+	assert_eq!(row_string(&Row::lost(7), all), "7,NaN, NaN, NaN, NaN, NaN, NaN, NaN, NaN, NaN, NaN\n");
+
+	// Orientation comes last
+	let row = Row { quaternion: Some([1_000_000_000, 0, -250_000_000, 5]), ..scale.row(8, &sample) };
+	let quaternion = Channels { quaternion: true, ..Channels::NONE };
+	assert_eq!(row_string(&row, quaternion), "8,0, 0, 0, 0, 0, 0, 1, 0, -0.25, 0.000000005\n");
+	assert_eq!(row_string(&Row::lost(9), quaternion), "9,NaN, NaN, NaN, NaN, NaN, NaN, NaN, NaN, NaN, NaN\n");
+}
+
+#[test]
+fn longest_row_fits() {
+	let row = Row {
+		micros: u64::MAX,
+		values: Some([i64::MIN; 6]),
+		mag: Some([i64::MIN; 3]),
+		temperature: Some(i64::MIN),
+		quaternion: Some([i64::MIN; 4]),
+	};
+	assert!(row_string(&row, Channels { mag: true, temperature: true, quaternion: true }).len() <= ROW_MAX_LEN);
+}
@@ -0,0 +1,150 @@
+use traccam_common::gyro_format::binary::{BinGyroHeader, BlockEncoder, Reader};
+use traccam_common::imu::lsm6ds3::ImuConfig;
+use traccam_common::timing::{ClockFit, CounterUnwrapper, WindowedFit};
+use zerocopy::IntoBytes;
+
+const NOMINAL_PERIOD: f64 = 1_000_000.0 / 1660.0;
+
+/// Simulates the firmware: an IMU whose period drifts linearly from `start_period` to
+/// `end_period`, read out in batches shortly after every watermark interrupt.
+/// Returns the true capture time of every sample and the logged file.
+fn record(start_period: f64, end_period: f64, samples: usize) -> (Vec<f64>, Vec<u8>) {
+	let mut t = 5_000_000.0; // Recording starts a while after boot
+	let truth: Vec<f64> = (0..samples)
+		.map(|i| {
+			t += start_period + (end_period - start_period) * i as f64 / samples as f64;
+			t
+		})
+		.collect();
+
+	let mut rng = 0x2545_F491_u32;
+	let mut jitter = move || {
+		rng ^= rng << 13;
+		rng ^= rng >> 17;
+		rng ^= rng << 5;
+		(rng % 800) as f64 // Up to 0.8ms until the readout task runs
+	};
+
+	let sensor = ImuConfig::DEFAULT.sensor_config();
+	let mut log = BinGyroHeader::new(sensor).as_bytes().to_vec();
+	let mut encoder = BlockEncoder::new(&sensor);
+	let mut read = 0;
+	while read < samples {
+		let watermark = (read + 250).min(samples - 1);
+		let read_at = truth[watermark] + jitter();
+		let available = truth.partition_point(|&s| s <= read_at);
+		let payload = vec![0_u8; (available - read) * 12];
+		log.extend_from_slice(encoder.frame(read_at as u64, &payload).as_bytes());
+		log.extend_from_slice(&payload);
+		read = available;
+	}
+	(truth, log)
+}
+
+fn batches(log: &[u8]) -> Vec<(u64, u64)> {
+	let mut total = 0;
+	Reader::new(log)
+		.unwrap()
+		.map(|block| {
+			let block = block.unwrap();
+			total += block.header.sample_count() as u64;
+			(total - 1, block.header.timestamp())
+		})
+		.collect()
+}
+
+fn fit(log: &[u8]) -> ClockFit {
+	let mut fit = ClockFit::new();
+	for (samples, ts) in batches(log) {
+		fit.add(samples, ts);
+	}
+	fit
+}
+
+#[test]
+fn recovers_off_nominal_rate() {
+	// 3% fast, and a minute long
+	let period = NOMINAL_PERIOD / 1.03;
+	let (truth, log) = record(period, period, 100_000);
+
+	let clock = fit(&log).solve(NOMINAL_PERIOD).unwrap();
+	assert!((clock.period - period).abs() / period < 1e-5, "{} vs {period}", clock.period);
+
+	let worst = truth.iter().enumerate().map(|(i, &t)| (clock.time_of(i as u64) - t).abs()).fold(0.0, f64::max);
+	// Bounded by the readout latency, not growing with the length of the recording
+	assert!(worst < 800.0, "worst error {worst}µs");
+}
+
+#[test]
+fn tracks_drifting_rate() {
+	// Warming up over ten minutes slows the oscillator by 1%
+	let (truth, log) = record(NOMINAL_PERIOD, NOMINAL_PERIOD * 1.01, 1_000_000);
+
+	let clock = fit(&log).solve(NOMINAL_PERIOD).unwrap();
+	let mean_period = (truth[truth.len() - 1] - truth[0]) / (truth.len() - 1) as f64;
+	assert!((clock.period - mean_period).abs() / mean_period < 1e-3);
+
+	// The nominal rate would be off by 3 seconds at the end
+	let nominal_error = (truth[0] + NOMINAL_PERIOD * (truth.len() - 1) as f64 - truth[truth.len() - 1]).abs();
+	assert!(nominal_error > 2_000_000.0);
+	// A single fit only gets the average right
+	let worst = truth.iter().enumerate().map(|(i, &t)| (clock.time_of(i as u64) - t).abs()).fold(0.0, f64::max);
+	assert!(worst > 100_000.0, "worst error {worst}µs");
+
+	// Fitting around every batch follows the drift
+	let batches = batches(&log);
+	let mut start = 0;
+	let mut worst: f64 = 0.0;
+	for ((last, _), clock) in batches.iter().zip(WindowedFit::new(&batches, 40, NOMINAL_PERIOD)) {
+		for i in start..=*last {
+			worst = worst.max((clock.time_of(i) - truth[i as usize]).abs());
+		}
+		start = last + 1;
+	}
+	assert!(worst < 800.0, "worst error {worst}µs");
+}
+
+#[test]
+fn single_batch_uses_nominal_period() {
+	let mut fit = ClockFit::new();
+	assert!(fit.solve(NOMINAL_PERIOD).is_none());
+
+	fit.add(249, 1_000_000);
+	let clock = fit.solve(NOMINAL_PERIOD).unwrap();
+	assert_eq!(clock.period, NOMINAL_PERIOD);
+	assert!((clock.time_of(249) - 1_000_000.0).abs() < 1e-6);
+}
+
+#[test]
+fn unwraps_24_bit_counter() {
+	let mut counter = CounterUnwrapper::new(24);
+	assert_eq!(counter.next(0xFF_FFF0), 0xFF_FFF0);
+	assert_eq!(counter.next(0x00_0010), 0x100_0010);
+	assert_eq!(counter.next(0x00_0020), 0x100_0020);
+
+	// Two and a bit wraps without readings
+	let elapsed = 2 * (1 << 24) + 0x100;
+	assert_eq!(counter.resume(0x00_0120, elapsed), 0x300_0120);
+	// A reading slightly earlier than the estimate still lands in the right wrap
+	assert_eq!(counter.resume(0x00_0100, (1 << 24) - 0x40), 0x400_0100);
+}
+
+#[test]
+fn calibrates_hw_timestamps() {
+	// The IMU timer shares the oscillator of the ODR, both are 2% slow
+	let tick = 25.0 * 1.02;
+	let ticks_per_sample = 24_u64;
+	let hw: Vec<u64> = (0..50_000).map(|i| 1000 + i * ticks_per_sample).collect();
+	let truth: Vec<f64> = hw.iter().map(|&t| 3_000_000.0 + t as f64 * tick).collect();
+
+	let mut fit = ClockFit::new();
+	for last in (99..hw.len()).step_by(100) {
+		// Read out up to half a millisecond after the last sample
+		fit.add(hw[last], (truth[last] + (last % 7) as f64 * 70.0) as u64);
+	}
+	let clock = fit.solve(25.0).unwrap();
+	assert!((clock.period - tick).abs() < 1e-3, "{}", clock.period);
+
+	let worst = hw.iter().zip(&truth).map(|(&h, &t)| (clock.time_of(h) - t).abs()).fold(0.0, f64::max);
+	assert!(worst < 500.0, "worst error {worst}µs");
+}
@@ -0,0 +1,73 @@
+use traccam_common::gyro_format::orientation::Orientation;
+use traccam_common::gyro_format::text::{self, Channels, ASCALE, GSCALE, MSCALE, TSCALE};
+use traccam_common::imu::ImuSample;
+use traccam_common::imu::lsm6ds3::{AccelScale, GyroScale, ImuConfig};
+
+fn close(a: f64, b: f64) -> bool {
+	(a - b).abs() < 1e-8 * b.abs().max(1.0)
+}
+
+#[test]
+fn fifo_data_sets_decode_gyro_first() {
+	// Data set 1 is the gyro, data set 2 the accel, each X, Y, Z little endian
+	let fifo = [0x01, 0x00, 0x02, 0x00, 0xFF, 0xFF, 0x10, 0x27, 0xF0, 0xD8, 0x00, 0x40];
+	let sample = ImuSample::from_le_bytes(&fifo);
+	assert_eq!(sample.gyro, [1, 2, -1]);
+	assert_eq!(sample.accel, [10000, -10000, 16384]);
+	assert_eq!(sample.to_le_bytes(), fifo);
+}
+
+#[test]
+fn known_raw_values_scale_to_dps_and_g() {
+	let sample = ImuSample { gyro: [1000, -1000, 0], accel: [16393, -8197, 1000], ..Default::default() };
+
+	let sensor = ImuConfig::DEFAULT.sensor_config();
+	let [gx, gy, gz, ax, ay, az] = sensor.scale(sample);
+	// 250 °/s: 8.75 m°/s per LSB, 2 g: 0.061 mg per LSB
+	assert!(close(gx, 8.75) && close(gy, -8.75) && gz == 0.0);
+	assert!(close(ax, 0.999973) && close(ay, -0.500017) && close(az, 0.061));
+
+	let config = ImuConfig { accel: AccelScale::G16, gyro: GyroScale::Dps2000, ..ImuConfig::DEFAULT };
+	let [gx, _, _, ax, _, az] = config.sensor_config().scale(sample);
+	assert!(close(gx, 70.0));
+	assert!(close(ax, 7.999784) && close(az, 0.488));
+}
+
+#[test]
+fn header_declares_output_units() {
+	let header = text::get_header_string(Orientation::IDENTITY, Channels::NONE);
+	assert!(header.contains(&format!("\ntscale,{TSCALE}\n")));
+	assert!(header.contains(&format!("\ngscale,{GSCALE}\n")));
+	assert!(header.contains(&format!("\nascale,{ASCALE:?}\n")));
+
+	// Rows in µs, °/s and g come out in s, rad/s and g
+	assert!(close(1_000_000.0 * TSCALE, 1.0));
+	assert!(close(180.0 * GSCALE, std::f64::consts::PI));
+	assert_eq!(ASCALE, 1.0);
+
+	// So a 250 °/s gyro at 1000 LSB reads 0.1527 rad/s in Gyroflow
+	let [gx, ..] = ImuConfig::DEFAULT.sensor_config().scale(ImuSample { gyro: [1000, 0, 0], accel: [0; 3], ..Default::default() });
+	assert!(close(gx * GSCALE, 8.75_f64.to_radians()));
+}
+
+#[test]
+fn header_lists_present_channels() {
+	let plain = text::get_header_string(Orientation::IDENTITY, Channels::NONE);
+	assert!(plain.ends_with("\nascale,1.0\nt,gx,gy,gz,ax,ay,az,\n"));
+	assert!(!plain.contains("mscale"));
+
+	let temperature = text::get_header_string(Orientation::IDENTITY, Channels { temperature: true, ..Channels::NONE });
+	assert!(temperature.ends_with("\nascale,1.0\nt,gx,gy,gz,ax,ay,az,temp,\n"));
+
+	let all = text::get_header_string(Orientation::IDENTITY, Channels { mag: true, temperature: true, quaternion: true });
+	assert!(all.ends_with(&format!("\nascale,1.0\nmscale,{MSCALE:?}\nt,gx,gy,gz,ax,ay,az,mx,my,mz,temp,qw,qx,qy,qz,\n")));
+	assert_eq!(all.len(), text::HEADER_LEN);
+}
+
+#[test]
+fn header_names_firmware_and_mounting() {
+	let header = text::get_header_string(Orientation::parse(b"yXZ").unwrap(), Channels::NONE);
+	assert!(header.contains("\nid,traccam_v1\n"));
+	assert!(header.contains("\norientation,yXZ\n"));
+	assert!(header.len() < text::HEADER_LEN);
+}
@@ -0,0 +1,96 @@
+use chrono::{NaiveDate, NaiveDateTime, NaiveTime};
+use core::cell::Cell;
+use embedded_sdmmc::{TimeSource, Timestamp};
+use traccam_common::utc::{parse_nmea_utc, UtcAnchor, UtcClock, UNKNOWN_TIME};
+use traccam_common::DisplayState;
+
+fn utc(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32, micro: u32) -> NaiveDateTime {
+	NaiveDate::from_ymd_opt(y, mo, d).unwrap().and_hms_micro_opt(h, mi, s, micro).unwrap()
+}
+
+#[test]
+fn parses_zda() {
+	assert_eq!(
+		parse_nmea_utc("$GPZDA,201530.00,04,07,2002,00,00*60\r\n"),
+		Some(utc(2002, 7, 4, 20, 15, 30, 0))
+	);
+	assert_eq!(
+		parse_nmea_utc("$GNZDA,083559.25,17,10,2026,,"),
+		Some(utc(2026, 10, 17, 8, 35, 59, 250_000))
+	);
+}
+
+#[test]
+fn parses_rmc() {
+	assert_eq!(
+		parse_nmea_utc("$GPRMC,225446.33,A,4916.45,N,12311.12,W,000.5,054.7,191194,020.3,E*61"),
+		None, // Checksum is over the whole body, this one was tampered with
+	);
+	assert_eq!(
+		parse_nmea_utc("$GPRMC,225446,A,4916.45,N,12311.12,W,000.5,054.7,191194,020.3,E*68"),
+		Some(utc(2094, 11, 19, 22, 54, 46, 0))
+	);
+	// No fix yet, the receiver's clock can't be trusted
+	assert_eq!(parse_nmea_utc("$GPRMC,225446,V,,,,,,,191194,,"), None);
+}
+
+#[test]
+fn rejects_other_sentences() {
+	assert_eq!(parse_nmea_utc("$GPGGA,123519,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*47"), None);
+	assert_eq!(parse_nmea_utc("GPZDA,201530.00,04,07,2002,00,00"), None);
+	assert_eq!(parse_nmea_utc("$GPZDA,2015,04,07,2002,00,00"), None);
+	assert_eq!(parse_nmea_utc("$GPZDA,201530.00,32,07,2002,00,00"), None);
+	assert_eq!(parse_nmea_utc("$GPZDA,20ä530,04,07,2002,00,00"), None);
+	assert_eq!(parse_nmea_utc("$"), None);
+}
+
+#[test]
+fn anchor_follows_monotonic_clock() {
+	let anchor = UtcAnchor::new(utc(2026, 10, 17, 12, 0, 0, 0), 5_000_000);
+	let noon = 1_792_238_400_000_000;
+	assert_eq!(anchor.unix_micros_at(5_000_000), noon);
+	assert_eq!(anchor.unix_micros_at(6_500_000), noon + 1_500_000);
+	assert_eq!(anchor.unix_micros_at(0), noon - 5_000_000);
+	assert_eq!(anchor.utc_at(6_500_000), Some(utc(2026, 10, 17, 12, 0, 1, 500_000)));
+}
+
+#[test]
+fn clock_runs_from_the_last_anchor() {
+	let anchor = Cell::new(None);
+	let ticks = Cell::new(0);
+	let clock = UtcClock::new(|| anchor.get(), || ticks.get());
+	let fat = |t: Timestamp| t.serialize_to_fat();
+
+	// Nothing received yet
+	assert_eq!(clock.now(), None);
+	assert_eq!(fat(clock.get_timestamp()), fat(UNKNOWN_TIME));
+	assert_eq!(fat(UNKNOWN_TIME), fat(Timestamp::from_calendar(1980, 1, 1, 0, 0, 0).unwrap()));
+
+	anchor.set(Some(UtcAnchor::new(utc(2026, 10, 17, 23, 59, 58, 0), 1_000_000)));
+	ticks.set(4_500_000);
+	assert_eq!(clock.now(), Some(utc(2026, 10, 18, 0, 0, 1, 500_000)));
+	assert_eq!(fat(clock.get_timestamp()), fat(Timestamp::from_calendar(2026, 10, 18, 0, 0, 1).unwrap()));
+
+	// A newer anchor takes over
+	anchor.set(Some(UtcAnchor::new(utc(2026, 10, 18, 6, 30, 0, 0), 4_000_000)));
+	assert_eq!(fat(clock.get_timestamp()), fat(Timestamp::from_calendar(2026, 10, 18, 6, 30, 0).unwrap()));
+}
+
+#[test]
+fn clock_marks_times_fat_cannot_store() {
+	for year in [1979, 2108] {
+		let anchor = UtcAnchor::new(utc(year, 6, 1, 12, 0, 0, 0), 0);
+		let clock = UtcClock::new(|| Some(anchor), || 0);
+		assert!(clock.now().is_some());
+		assert_eq!(clock.get_timestamp().serialize_to_fat(), UNKNOWN_TIME.serialize_to_fat());
+	}
+}
+
+#[test]
+fn display_state_utc_needs_a_date() {
+	let mut state = DisplayState::default();
+	state.update_utc_time(NaiveTime::from_hms_opt(8, 35, 59).unwrap());
+	assert_eq!(state.utc(), None);
+	state.update_date(NaiveDate::from_ymd_opt(2026, 10, 17).unwrap());
+	assert_eq!(state.utc(), Some(utc(2026, 10, 17, 8, 35, 59, 0)));
+}
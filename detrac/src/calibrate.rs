@@ -0,0 +1,312 @@
+use crate::recording::Row;
+use traccam_common::gyro_format::binary::SensorConfig;
+use traccam_common::imu::calibration::{Calibration, STILL_GYRO_SPREAD_DPS};
+
+/// Stretch of samples checked for motion at once, still segments are made of these
+const WINDOW_MICROS: f64 = 500_000.0;
+/// Temperature range the still segments have to cover to fit the bias against it
+const MIN_TEMPERATURE_SPAN_C: f64 = 2.0;
+
+/// A part of the recording where the rig didn't move
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StillSegment {
+    /// First sample, in µs since the start of the recording
+    pub start: f64,
+    /// Last sample
+    pub end: f64,
+    pub samples: u64,
+    /// Mean gyro in n°/s
+    pub gyro: [f64; 3],
+    /// Mean accel in ng
+    pub accel: [f64; 3],
+    /// Mean die temperature in °C, if recorded
+    pub temperature: Option<f64>,
+}
+
+/// Sums over consecutive samples
+#[derive(Debug, Clone, Copy)]
+struct Sums {
+    start: f64,
+    end: f64,
+    samples: u64,
+    gyro: [f64; 3],
+    accel: [f64; 3],
+    temperature: f64,
+    temperatures: u64,
+    gyro_min: [i64; 3],
+    gyro_max: [i64; 3],
+}
+
+impl Sums {
+    fn new(start: f64) -> Self {
+        Self {
+            start,
+            end: start,
+            samples: 0,
+            gyro: [0.0; 3],
+            accel: [0.0; 3],
+            temperature: 0.0,
+            temperatures: 0,
+            gyro_min: [i64::MAX; 3],
+            gyro_max: [i64::MIN; 3],
+        }
+    }
+
+    fn add(&mut self, micros: f64, values: &[i64; 6], temperature: Option<i64>) {
+        for axis in 0..3 {
+            self.gyro[axis] += values[axis] as f64;
+            self.accel[axis] += values[axis + 3] as f64;
+            self.gyro_min[axis] = self.gyro_min[axis].min(values[axis]);
+            self.gyro_max[axis] = self.gyro_max[axis].max(values[axis]);
+        }
+        if let Some(temperature) = temperature {
+            self.temperature += temperature as f64 / 1e9;
+            self.temperatures += 1;
+        }
+        self.end = micros;
+        self.samples += 1;
+    }
+
+    fn merge(&mut self, other: &Sums) {
+        for axis in 0..3 {
+            self.gyro[axis] += other.gyro[axis];
+            self.accel[axis] += other.accel[axis];
+        }
+        self.temperature += other.temperature;
+        self.temperatures += other.temperatures;
+        self.end = other.end;
+        self.samples += other.samples;
+    }
+
+    fn is_still(&self) -> bool {
+        let spread = STILL_GYRO_SPREAD_DPS * 1e9;
+        self.samples > 0 && (0..3).all(|axis| (self.gyro_max[axis] - self.gyro_min[axis]) as f64 <= spread)
+    }
+
+    fn segment(&self) -> StillSegment {
+        let n = self.samples as f64;
+        StillSegment {
+            start: self.start,
+            end: self.end,
+            samples: self.samples,
+            gyro: self.gyro.map(|sum| sum / n),
+            accel: self.accel.map(|sum| sum / n),
+            // Only when every sample had it, a mean over part of the segment would be off
+            temperature: (self.temperatures == self.samples).then(|| self.temperature / n),
+        }
+    }
+}
+
+/// Finds still segments in a stream of rows: runs of whole windows in which no gyro axis
+/// moved more than [`STILL_GYRO_SPREAD_DPS`]. Lost samples end a segment.
+#[derive(Debug, Default)]
+pub struct StillFinder {
+    window: Option<Sums>,
+    segment: Option<Sums>,
+    segments: Vec<StillSegment>,
+}
+
+impl StillFinder {
+    pub fn add(&mut self, row: &Row) {
+        let Some(values) = &row.values else {
+            self.close_window(false);
+            return;
+        };
+        if self.window.is_some_and(|w| row.micros - w.start >= WINDOW_MICROS) {
+            self.close_window(true);
+        }
+        self.window.get_or_insert_with(|| Sums::new(row.micros)).add(row.micros, values, row.temperature);
+    }
+
+    /// Still segments in recording order
+    pub fn finish(mut self) -> Vec<StillSegment> {
+        // The last window is cut short, it doesn't say much
+        self.close_window(false);
+        self.segments
+    }
+
+    fn close_window(&mut self, complete: bool) {
+        match self.window.take() {
+            Some(window) if complete && window.is_still() => match &mut self.segment {
+                Some(segment) => segment.merge(&window),
+                None => self.segment = Some(window),
+            },
+            _ => {
+                if let Some(segment) = self.segment.take() {
+                    self.segments.push(segment.segment());
+                }
+            }
+        }
+    }
+}
+
+/// Offsets to subtract from the recorded samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bias {
+    /// Gyro zero-rate offset in n°/s at `reference_c`
+    pub gyro: [f64; 3],
+    /// How much the gyro offset changes per °C, if it was fitted against temperature
+    pub gyro_per_c: Option<[f64; 3]>,
+    pub reference_c: f64,
+    /// Accel offset in ng
+    pub accel: [f64; 3],
+}
+
+impl Bias {
+    /// The offsets the logger measured at the start of recording
+    pub fn from_header(calibration: &Calibration, sensor: &SensorConfig) -> Self {
+        let gyro_nanos = sensor.gyro_dps_per_lsb() * 1e9;
+        let accel_nanos = sensor.accel_g_per_lsb() * 1e9;
+        Self {
+            gyro: calibration.gyro_bias.map(|b| b as f64 * gyro_nanos),
+            gyro_per_c: None,
+            reference_c: 0.0,
+            accel: calibration.accel_offset.map(|o| o as f64 * accel_nanos),
+        }
+    }
+
+    /// Averages still segments, weighted by their length. With `temperature` the gyro offset
+    /// is fitted as a straight line over die temperature, if the segments cover enough of a
+    /// range for that.
+    pub fn estimate(segments: &[StillSegment], temperature: bool) -> Option<Self> {
+        let total = segments.iter().map(|s| s.samples as f64).sum::<f64>();
+        if total == 0.0 {
+            return None;
+        }
+        let mean = |value: &dyn Fn(&StillSegment) -> f64| segments.iter().map(|s| value(s) * s.samples as f64).sum::<f64>() / total;
+        let accel = [0, 1, 2].map(|axis| {
+            mean(&|s| {
+                let norm = s.accel.iter().map(|a| a * a).sum::<f64>().sqrt();
+                s.accel[axis] - s.accel[axis] / norm * 1e9
+            })
+        });
+        let gyro = [0, 1, 2].map(|axis| mean(&|s| s.gyro[axis]));
+
+        let temperatures: Option<Vec<f64>> = segments.iter().map(|s| s.temperature).collect();
+        let (gyro, gyro_per_c, reference_c) = match temperatures {
+            Some(temperatures) if temperature => {
+                let reference_c = mean(&|s| s.temperature.unwrap());
+                let lowest = temperatures.iter().copied().fold(f64::INFINITY, f64::min);
+                let highest = temperatures.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                match highest - lowest >= MIN_TEMPERATURE_SPAN_C {
+                    true => {
+                        let variance = mean(&|s| (s.temperature.unwrap() - reference_c).powi(2));
+                        let slope = [0, 1, 2].map(|axis| {
+                            mean(&|s| (s.temperature.unwrap() - reference_c) * (s.gyro[axis] - gyro[axis])) / variance
+                        });
+                        (gyro, Some(slope), reference_c)
+                    }
+                    false => (gyro, None, reference_c),
+                }
+            }
+            _ => (gyro, None, 0.0),
+        };
+        Some(Self { gyro, gyro_per_c, reference_c, accel })
+    }
+
+    /// Gyro offset in n°/s at a die temperature in °C
+    pub fn gyro_at(&self, temperature_c: Option<f64>) -> [f64; 3] {
+        match (self.gyro_per_c, temperature_c) {
+            (Some(slope), Some(t)) => [0, 1, 2].map(|axis| self.gyro[axis] + slope[axis] * (t - self.reference_c)),
+            _ => self.gyro,
+        }
+    }
+
+    pub fn apply(&self, row: &mut Row) {
+        let gyro = self.gyro_at(row.temperature.map(|t| t as f64 / 1e9));
+        if let Some(values) = &mut row.values {
+            for axis in 0..3 {
+                values[axis] -= gyro[axis].round() as i64;
+                values[axis + 3] -= self.accel[axis].round() as i64;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 1 kHz, in µs
+    const PERIOD: f64 = 1000.0;
+    const GYRO_BIAS: [f64; 3] = [1.5e9, -0.7e9, 0.2e9];
+
+    fn still(i: usize, gyro: [f64; 3], temperature: Option<f64>) -> Row {
+        // Noise well within the still threshold, averaging out over every two samples
+        let noise = if i.is_multiple_of(2) { 3e8 } else { -3e8 };
+        let [gx, gy, gz] = gyro.map(|g| (g + noise) as i64);
+        Row {
+            micros: i as f64 * PERIOD,
+            values: Some([gx, gy, gz, 0, 0, 1_004_000_000]),
+            mag: None,
+            temperature: temperature.map(|t| (t * 1e9) as i64),
+        }
+    }
+
+    fn assert_close(actual: [f64; 3], expected: [f64; 3], tolerance: f64) {
+        for axis in 0..3 {
+            assert!((actual[axis] - expected[axis]).abs() <= tolerance, "{actual:?} vs {expected:?}");
+        }
+    }
+
+    #[test]
+    fn still_parts_around_motion() {
+        let mut finder = StillFinder::default();
+        for i in 0..8000 {
+            let row = match i {
+                // Panning at up to 50°/s from 3 to 5 s
+                3000..5000 => {
+                    let rate = (50e9 * (i as f64 / 300.0).sin()) as i64;
+                    Row { values: Some([rate, 0, rate / 2, 0, 0, 1_000_000_000]), ..still(i, GYRO_BIAS, None) }
+                }
+                _ => still(i, GYRO_BIAS, None),
+            };
+            finder.add(&row);
+        }
+
+        let segments = finder.finish();
+        let spans: Vec<_> = segments.iter().map(|s| (s.start, s.end, s.samples)).collect();
+        // The window cut short at the end doesn't count
+        assert_eq!(spans, [(0.0, 2_999_000.0, 3000), (5_000_000.0, 7_499_000.0, 2500)]);
+        assert_eq!(segments[0].temperature, None);
+
+        let bias = Bias::estimate(&segments, true).unwrap();
+        assert_close(bias.gyro, GYRO_BIAS, 1.0);
+        assert_eq!(bias.gyro_per_c, None);
+        // Only the part along gravity shows without turning the camera
+        assert_close(bias.accel, [0.0, 0.0, 4_000_000.0], 1.0);
+        assert_eq!(Bias::estimate(&[], false), None);
+    }
+
+    #[test]
+    fn gyro_bias_follows_temperature() {
+        // Warming up from 25 to 35 °C, the bias drifts linearly with it
+        let per_c = [5e7, -2e7, 1e7];
+        let mut finder = StillFinder::default();
+        for i in 0..20_000 {
+            let temperature = 25.0 + 10.0 * i as f64 / 20_000.0;
+            let gyro = [0, 1, 2].map(|axis| GYRO_BIAS[axis] + per_c[axis] * (temperature - 30.0));
+            let row = still(i, gyro, Some(temperature));
+            // A lost sample every 2 s splits the recording into segments
+            match i % 2000 {
+                1999 => finder.add(&Row { values: None, ..row }),
+                _ => finder.add(&row),
+            }
+        }
+
+        let segments = finder.finish();
+        assert_eq!(segments.len(), 10);
+        assert!(segments.iter().all(|s| s.samples == 1500 && s.temperature.is_some()));
+
+        let bias = Bias::estimate(&segments, true).unwrap();
+        assert_close(bias.gyro_per_c.unwrap(), per_c, 1e4);
+        assert_close(bias.gyro_at(Some(30.0)), GYRO_BIAS, 1e5);
+        assert_close(bias.gyro_at(Some(26.0)), [0, 1, 2].map(|axis| GYRO_BIAS[axis] - 4.0 * per_c[axis]), 1e5);
+
+        // Without temperature, or too little of a range for a slope, it is a plain mean
+        let plain = Bias::estimate(&segments, false).unwrap();
+        assert_eq!((plain.gyro_per_c, plain.gyro), (None, bias.gyro));
+        let narrow = Bias::estimate(&segments[..2], true).unwrap();
+        assert_eq!(narrow.gyro_per_c, None);
+    }
+}
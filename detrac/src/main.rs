@@ -1,22 +1,707 @@
-use std::fs;
-use traccam_common::gyro_format;
-
-fn main() {
-    let p = "LOG.CSV";
-    let data = fs::read(p).unwrap();
-    let headerlen = gyro_format::text::HEADER_LEN;
-    print!("{}", gyro_format::text::get_header_string());
-    for (i, array_window) in data[headerlen..].chunks(12).enumerate() {
-        if array_window.len() < 12 {
-            break
-        }
-        let gx = i16::from_le_bytes([array_window[0], array_window[1]]) as f64 * 0.061 / 1000.0;
-        let gy = i16::from_le_bytes([array_window[2], array_window[3]]) as f64 * 0.061 / 1000.0;
-        let gz = i16::from_le_bytes([array_window[4], array_window[5]]) as f64 * 0.061 / 1000.0;
-        let ax = i16::from_le_bytes([array_window[6], array_window[7]]) as f64 * 8.75 / 1000.0;
-        let ay = i16::from_le_bytes([array_window[8], array_window[9]]) as f64 * 8.75 / 1000.0;
-        let az = i16::from_le_bytes([array_window[10], array_window[11]]) as f64 * 8.75 / 1000.0;
-        let ts = (i as f64 * 602.4096386) as u64;
-        println!("{ts},{gx}, {gy}, {gz}, {ax}, {ay}, {az}");
+mod calibrate;
+mod recording;
+mod timeline;
+mod tracker;
+
+use clap::error::ErrorKind;
+use calibrate::{Bias, StillFinder, StillSegment};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use recording::{LoadError, Recording, Row};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use traccam_common::fusion::{Fusion, Madgwick, Mahony, Quaternion};
+use traccam_common::gyro_format::binary::{BinGyroHeader, BlockHeader};
+use traccam_common::gyro_format::orientation::Orientation;
+use traccam_common::gyro_format::recover::{Found, Scanner};
+use traccam_common::gyro_format::text;
+use tracker::Tracker;
+use zerocopy::IntoBytes;
+
+const EXIT_CODES: &str = "\
+Exit codes:
+  0  success
+  1  I/O error
+  2  bad arguments
+  3  unreadable: unknown format or bad header
+  4  damaged: truncated file or corrupt blocks, everything intact was still converted";
+
+/// Converts traccam IMU logs to Gyroflow CSV
+#[derive(Parser)]
+#[command(version, after_help = EXIT_CODES)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Converts a log to Gyroflow CSV
+    Convert {
+        input: PathBuf,
+        #[command(flatten)]
+        output: Output,
+    },
+    /// Prints header, duration, sample count and gaps of logs
+    Info {
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+    /// Checks logs for damage, lost samples are only reported
+    Validate {
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+    /// Finds still parts of logs and estimates gyro and accel offsets from them
+    Calibrate {
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+        /// Fit the gyro offset against die temperature, if the log has it
+        #[arg(long)]
+        temperature: bool,
+    },
+    /// Converts part of a log to Gyroflow CSV, timestamps start at 0 again
+    Trim {
+        input: PathBuf,
+        /// Start, in seconds since the first sample
+        #[arg(long, value_name = "SECONDS")]
+        from: Option<f64>,
+        /// End, in seconds since the first sample
+        #[arg(long, value_name = "SECONDS")]
+        to: Option<f64>,
+        #[command(flatten)]
+        output: Output,
+    },
+    /// Estimates the camera orientation at every sample from gyro and accel
+    Orientation {
+        input: PathBuf,
+        /// Where to write the CSV, stdout if not given
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// What to write for every sample
+        #[arg(long, value_enum, default_value_t = AngleFormat::Quaternion)]
+        format: AngleFormat,
+        /// How to fuse gyro and accel
+        #[arg(long, value_enum, default_value_t = Filter::Madgwick)]
+        filter: Filter,
+        /// Offsets to subtract from gyro and accel before fusing them
+        #[arg(long, value_enum, default_value_t = BiasSource::None)]
+        bias: BiasSource,
+    },
+    /// Rebuilds logs from an image of a card, for recordings that were never closed
+    Recover {
+        /// Dump of the card or partition, as `dd` makes it
+        image: PathBuf,
+        /// Folder to write the logs to
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+}
+
+#[derive(Args)]
+struct Output {
+    /// Where to write the CSV, stdout if not given
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Rotate the samples into camera axes instead of declaring the mounting in the header
+    #[arg(long)]
+    rotate: bool,
+    /// Offsets to subtract from gyro and accel
+    #[arg(long, value_enum, default_value_t = BiasSource::None)]
+    bias: BiasSource,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BiasSource {
+    /// Keep the samples as recorded
+    None,
+    /// Measured by the logger at the start of recording
+    Header,
+    /// Estimated from the still parts of the log, like `calibrate` does
+    Still,
+    /// Like `still`, with the gyro offset following die temperature
+    Temperature,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AngleFormat {
+    /// t,qw,qx,qy,qz with t in seconds, lost samples left out
+    Quaternion,
+    /// t,roll,pitch,yaw with the angles in degrees, lost samples left out
+    Euler,
+    /// Gyroflow CSV in camera axes with qw,qx,qy,qz columns added
+    Gyroflow,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Filter {
+    /// Madgwick's gradient descent filter
+    Madgwick,
+    /// Mahony's complementary filter, learns the gyro offset as it goes
+    Mahony,
+}
+
+impl Filter {
+    fn constructor(self) -> fn(Quaternion) -> Box<dyn Fusion> {
+        match self {
+            Filter::Madgwick => |start| Box::new(Madgwick::new(Madgwick::DEFAULT_BETA, start)),
+            Filter::Mahony => |start| Box::new(Mahony::new(Mahony::DEFAULT_KP, Mahony::DEFAULT_KI, start)),
+        }
+    }
+}
+
+/// How a file turned out, worst of all files decides the exit code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    Ok,
+    Damaged,
+    Unreadable,
+    Io,
+}
+
+impl From<Status> for ExitCode {
+    fn from(status: Status) -> Self {
+        ExitCode::from(match status {
+            Status::Ok => 0,
+            Status::Io => 1,
+            Status::Unreadable => 3,
+            Status::Damaged => 4,
+        })
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let status = match cli.command {
+        Command::Convert { input, output } => export(&input, &output, None, None, None),
+        Command::Info { inputs } => inputs.iter().map(|p| info(p)).max().unwrap_or(Status::Ok),
+        Command::Validate { inputs } => inputs.iter().map(|p| validate(p)).max().unwrap_or(Status::Ok),
+        Command::Calibrate { inputs, temperature } => {
+            inputs.iter().map(|p| calibrate(p, temperature)).max().unwrap_or(Status::Ok)
+        }
+        Command::Trim { input, from, to, output } => {
+            if let (Some(from), Some(to)) = (from, to)
+                && from >= to
+            {
+                Cli::command().error(ErrorKind::ArgumentConflict, "--from must be before --to").exit();
+            }
+            export(&input, &output, from, to, None)
+        }
+        Command::Orientation { input, output, format, filter, bias } => {
+            // The camera's orientation, not the sensor's
+            let output = Output { output, rotate: true, bias };
+            export(&input, &output, None, None, Some((filter, format)))
+        }
+        Command::Recover { image, output } => recover(&image, &output),
+    };
+    status.into()
+}
+
+/// Loads `path`, with the segments it goes on in if `session`
+fn open(path: &Path, session: bool) -> Result<Recording, Status> {
+    let recording = match session {
+        true => Recording::open_session(path),
+        false => Recording::open(path),
+    };
+    let recording = recording.map_err(|e| {
+        eprintln!("{}: {e}", path.display());
+        match e {
+            LoadError::Io(_) => Status::Io,
+            _ => Status::Unreadable,
+        }
+    })?;
+    for joined in &recording.joined {
+        eprintln!("{}: goes on in {}", path.display(), joined.display());
+    }
+    Ok(recording)
+}
+
+/// Status after reporting all problems of `recording`
+fn report(path: &Path, recording: &Recording) -> Status {
+    for problem in &recording.problems {
+        eprintln!("{}: {problem}", path.display());
+    }
+    match recording.problems.iter().any(|p| p.is_damage()) {
+        true => Status::Damaged,
+        false => Status::Ok,
+    }
+}
+
+/// Writes the samples between `from` and `to` seconds as Gyroflow CSV, or the orientation
+/// `filter` finds at each of them
+fn export(
+    input: &Path,
+    output: &Output,
+    from: Option<f64>,
+    to: Option<f64>,
+    orientation: Option<(Filter, AngleFormat)>,
+) -> Status {
+    let recording = match open(input, true) {
+        Ok(recording) => recording,
+        Err(status) => return status,
+    };
+    let status = report(input, &recording);
+    match recording.start_utc() {
+        Some(utc) => eprintln!("{}: recording starts at {utc}", input.display()),
+        None => eprintln!("{}: recording start time unknown", input.display()),
+    }
+
+    let bias = bias(input, &recording, output.bias);
+
+    let mut out: BufWriter<Box<dyn Write>> = match &output.output {
+        Some(path) => match File::create(path) {
+            Ok(file) => BufWriter::new(Box::new(file)),
+            Err(e) => {
+                eprintln!("{}: {e}", path.display());
+                return Status::Io;
+            }
+        },
+        None => BufWriter::new(Box::new(io::stdout().lock())),
+    };
+    let from = from.map_or(0.0, |s| s * 1e6);
+    let to = to.map_or(f64::INFINITY, |s| s * 1e6);
+    let (declared, rotation) = match output.rotate {
+        true => (Orientation::IDENTITY, recording.orientation),
+        false => (recording.orientation, Orientation::IDENTITY),
+    };
+    let mut tracker = orientation.map(|(filter, _)| Tracker::new(filter.constructor()));
+    let format = orientation.map(|(_, format)| format);
+    let channels = text::Channels { quaternion: format.is_some(), ..recording.channels };
+    let header = match format {
+        Some(AngleFormat::Quaternion) => "t,qw,qx,qy,qz\n",
+        Some(AngleFormat::Euler) => "t,roll,pitch,yaw\n",
+        Some(AngleFormat::Gyroflow) | None => &text::get_header_string(declared, channels),
+    };
+    let written = out.write_all(header.as_bytes()).and_then(|_| {
+        recording.rows(|mut row| match row.micros >= from && row.micros < to {
+            true => {
+                if let Some(bias) = &bias {
+                    bias.apply(&mut row);
+                }
+                row.values = row.values.map(|[gx, gy, gz, ax, ay, az]| {
+                    let [gx, gy, gz] = rotation.rotate([gx, gy, gz]);
+                    let [ax, ay, az] = rotation.rotate([ax, ay, az]);
+                    [gx, gy, gz, ax, ay, az]
+                });
+                row.mag = row.mag.map(|mag| rotation.rotate(mag));
+                let q = tracker.as_mut().and_then(|tracker| tracker.add(&row));
+                let seconds = (row.micros - from) / 1e6;
+                match (format, q) {
+                    (Some(AngleFormat::Quaternion), Some(q)) => {
+                        writeln!(out, "{seconds:.6},{:.9},{:.9},{:.9},{:.9}", q.w, q.x, q.y, q.z)
+                    }
+                    (Some(AngleFormat::Euler), Some(q)) => {
+                        let [roll, pitch, yaw] = q.euler().map(f64::to_degrees);
+                        writeln!(out, "{seconds:.6},{roll:.6},{pitch:.6},{yaw:.6}")
+                    }
+                    (Some(AngleFormat::Quaternion | AngleFormat::Euler), None) => Ok(()),
+                    (Some(AngleFormat::Gyroflow) | None, q) => {
+                        let row = text::Row {
+                            micros: (row.micros - from) as u64,
+                            values: row.values,
+                            mag: row.mag,
+                            temperature: row.temperature,
+                            quaternion: q.map(|q| [q.w, q.x, q.y, q.z].map(|v| (v * 1e9).round() as i64)),
+                        };
+                        out.write_all(text::row_string(&row, channels).as_bytes())
+                    }
+                }
+            }
+            false => Ok(()),
+        })
+    });
+    if let Err(e) = written.and_then(|_| out.flush()) {
+        eprintln!("{}: {e}", output.output.as_ref().map_or("stdout".into(), |p| p.display().to_string()));
+        return Status::Io;
+    }
+    status
+}
+
+/// Offsets to take off the samples on export, None if there are none to be had
+fn bias(path: &Path, recording: &Recording, source: BiasSource) -> Option<Bias> {
+    let bias = match source {
+        BiasSource::None => return None,
+        BiasSource::Header => recording
+            .calibration()
+            .zip(recording.sensor())
+            .map(|(calibration, sensor)| Bias::from_header(&calibration, &sensor)),
+        BiasSource::Still | BiasSource::Temperature => {
+            Bias::estimate(&still_segments(recording), source == BiasSource::Temperature)
+        }
+    };
+    match &bias {
+        Some(bias) => eprintln!("{}: subtracting {}", path.display(), describe(bias)),
+        None => eprintln!("{}: no offsets to subtract, exporting as recorded", path.display()),
+    }
+    bias
+}
+
+fn still_segments(recording: &Recording) -> Vec<StillSegment> {
+    let mut finder = StillFinder::default();
+    recording
+        .rows(|row| {
+            finder.add(&row);
+            Ok(())
+        })
+        .unwrap();
+    finder.finish()
+}
+
+fn describe(bias: &Bias) -> String {
+    let values = |values: [f64; 3]| values.map(|v| format!("{:.4}", v / 1e9)).join(", ");
+    let gyro = match bias.gyro_per_c {
+        Some(slope) => format!("{} °/s at {:.1} °C, {} °/s per °C", values(bias.gyro), bias.reference_c, values(slope)),
+        None => format!("{} °/s", values(bias.gyro)),
+    };
+    format!("gyro {gyro}, accel {} g", values(bias.accel))
+}
+
+fn calibrate(path: &Path, temperature: bool) -> Status {
+    let recording = match open(path, true) {
+        Ok(recording) => recording,
+        Err(status) => return status,
+    };
+    let status = report(path, &recording);
+    let segments = still_segments(&recording);
+
+    println!("{}", path.display());
+    for segment in &segments {
+        println!(
+            "  still:     {:.3} - {:.3} s, {} samples{}",
+            segment.start / 1e6,
+            segment.end / 1e6,
+            segment.samples,
+            match segment.temperature {
+                Some(t) => format!(", {t:.1} °C"),
+                None => String::new(),
+            }
+        );
+    }
+    if let Some((calibration, sensor)) = recording.calibration().zip(recording.sensor()) {
+        let bias = Bias::from_header(&calibration, &sensor);
+        println!("  header:    {} from {} samples", describe(&bias), calibration.samples);
+    }
+    match Bias::estimate(&segments, temperature) {
+        Some(bias) => println!("  estimate:  {}", describe(&bias)),
+        None => println!("  estimate:  none, the rig never stood still"),
+    }
+    status
+}
+
+fn info(path: &Path) -> Status {
+    let recording = match open(path, false) {
+        Ok(recording) => recording,
+        Err(status) => return status,
+    };
+    let (mut samples, mut filled, mut last) = (0_u64, 0_u64, None);
+    recording
+        .rows(|row: Row| {
+            match row.values {
+                Some(_) => samples += 1,
+                None => filled += 1,
+            }
+            last = Some(row.micros);
+            Ok(())
+        })
+        .unwrap();
+    // Up to the end of the last sample
+    let rows = samples + filled;
+    let duration = match last {
+        Some(micros) if rows > 1 => micros * rows as f64 / (rows - 1) as f64 / 1e6,
+        _ => 0.0,
+    };
+
+    println!("{}", path.display());
+    println!("  format:    {}", recording.format());
+    match recording.sensor() {
+        Some(sensor) => println!(
+            "  sensor:    {} Hz, ±{} °/s, ±{} g{}",
+            sensor.odr_millihertz() as f64 / 1000.0,
+            sensor.gyro_range_dps(),
+            sensor.accel_range_g(),
+            match sensor.hw_timestamp_ns() {
+                Some(ns) => format!(", hardware timestamps ({ns} ns)"),
+                None => String::new(),
+            }
+        ),
+        None => println!("  sensor:    unknown"),
+    }
+    println!("  mounting:  {}", recording.orientation);
+    if let Some((session, segment, continues)) = recording.segment()
+        && (segment > 0 || continues)
+    {
+        let next = if continues { ", goes on in the next" } else { ", the last" };
+        println!("  segment:   {segment} of recording {session}{next}");
+    }
+    if let Some((calibration, sensor)) = recording.calibration().zip(recording.sensor()) {
+        let bias = Bias::from_header(&calibration, &sensor);
+        println!("  offsets:   {} from {} samples", describe(&bias), calibration.samples);
+    }
+    match recording.channels {
+        text::Channels { mag: false, temperature: false, .. } => {}
+        text::Channels { mag, temperature, .. } => println!(
+            "  extra:     {}",
+            [(mag, "magnetometer"), (temperature, "temperature")]
+                .iter()
+                .filter_map(|&(present, name)| present.then_some(name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+    match recording.start_utc() {
+        Some(utc) => println!("  start:     {utc}"),
+        None => println!("  start:     unknown"),
+    }
+    println!("  duration:  {duration:.3} s");
+    match recording.blocks() {
+        Some(blocks) => println!("  samples:   {samples} in {blocks} blocks"),
+        None => println!("  samples:   {samples}"),
+    }
+    println!("  lost:      about {filled} samples");
+    for problem in &recording.problems {
+        println!("  problem:   {problem}");
+    }
+
+    match recording.problems.iter().any(|p| p.is_damage()) {
+        true => Status::Damaged,
+        false => Status::Ok,
+    }
+}
+
+fn validate(path: &Path) -> Status {
+    let status = match open(path, false) {
+        Ok(recording) => report(path, &recording),
+        Err(status) => status,
+    };
+    match status {
+        Status::Ok => println!("{}: ok", path.display()),
+        Status::Damaged => println!("{}: damaged", path.display()),
+        Status::Unreadable => println!("{}: unreadable", path.display()),
+        Status::Io => println!("{}: failed", path.display()),
+    }
+    status
+}
+
+/// A log `recover` is writing out
+struct Recovered {
+    path: PathBuf,
+    out: BufWriter<File>,
+    timescale: u64,
+    blocks: u64,
+    missing: u64,
+    /// Sequence number and timestamp of the first and the last block
+    first: (u32, u64),
+    last: (u32, u64),
+}
+
+impl Recovered {
+    fn create(dir: &Path, offset: u64, header: &BinGyroHeader, first: (u32, u64)) -> Result<Self, Status> {
+        let name = format!("recovered_{}_{}_{offset}.bin", header.session_id(), header.segment());
+        let path = dir.join(name);
+        // Never over a log recovered before
+        let created = File::create_new(&path).map(BufWriter::new).and_then(|mut out| {
+            out.write_all(header.as_bytes())?;
+            Ok(out)
+        });
+        match created {
+            Ok(out) => Ok(Self { path, out, timescale: header.timescale(), blocks: 0, missing: 0, first, last: first }),
+            Err(e) => {
+                eprintln!("{}: {e}", path.display());
+                Err(Status::Io)
+            }
+        }
+    }
+
+    fn add(&mut self, header: &BlockHeader, payload: &[u8]) -> Result<(), Status> {
+        let block = (header.sequence(), header.timestamp());
+        self.missing += (block.0 - self.last.0).saturating_sub(1) as u64;
+        self.blocks += 1;
+        self.last = block;
+        self.out.write_all(header.as_bytes()).and_then(|_| self.out.write_all(payload)).map_err(|e| {
+            eprintln!("{}: {e}", self.path.display());
+            Status::Io
+        })
+    }
+
+    fn finish(mut self) -> Result<Status, Status> {
+        if let Err(e) = self.out.flush() {
+            eprintln!("{}: {e}", self.path.display());
+            return Err(Status::Io);
+        }
+        let seconds = (self.last.1 - self.first.1) as f64 / self.timescale as f64;
+        print!("{}: {} blocks, {seconds:.1} s", self.path.display(), self.blocks);
+        match self.missing {
+            0 => println!(),
+            missing => println!(", {missing} blocks missing"),
+        }
+        Ok(match self.missing {
+            0 => Status::Ok,
+            _ => Status::Damaged,
+        })
+    }
+}
+
+/// Writes every log found in `image` that has blocks to `dir`, whether the card still lists it
+/// or not
+fn recover(image: &Path, dir: &Path) -> Status {
+    match recover_logs(image, dir) {
+        Ok(Some(status)) | Err(status) => status,
+        Ok(None) => {
+            eprintln!("{}: no logs found", image.display());
+            Status::Unreadable
+        }
+    }
+}
+
+/// Worst status of the logs written, None if there were none
+fn recover_logs(image: &Path, dir: &Path) -> Result<Option<Status>, Status> {
+    let file = File::open(image).map_err(|e| {
+        eprintln!("{}: {e}", image.display());
+        Status::Io
+    })?;
+    let mut scanner = Scanner::new(file);
+    let mut log = None;
+    let mut current: Option<Recovered> = None;
+    let mut status = None;
+    while let Some(found) = scanner.next() {
+        let found = found.map_err(|e| {
+            eprintln!("{}: {e} after {} bytes", image.display(), scanner.offset());
+            Status::Io
+        })?;
+        match found {
+            Found::Log { offset, header } => {
+                if let Some(recovered) = current.take() {
+                    status = status.max(Some(recovered.finish()?));
+                }
+                log = Some((offset, header));
+            }
+            Found::Block { header, payload, .. } => {
+                let recovered = match &mut current {
+                    Some(recovered) => recovered,
+                    None => {
+                        // Blocks only ever follow a log header
+                        let (offset, log) = log.as_ref().unwrap();
+                        let first = (header.sequence(), header.timestamp());
+                        current.insert(Recovered::create(dir, *offset, log, first)?)
+                    }
+                };
+                recovered.add(&header, &payload)?;
+            }
+        }
+    }
+    if let Some(recovered) = current {
+        status = status.max(Some(recovered.finish()?));
+    }
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use traccam_common::gyro_format::binary::{BlockEncoder, SensorConfig};
+    use traccam_common::imu::lsm6ds3::ImuConfig;
+
+    const SENSOR: SensorConfig = ImuConfig::DEFAULT.sensor_config();
+
+    /// An empty directory of its own for each test
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("detrac-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A log of `header` holding the blocks in `keep` out of `blocks` recorded
+    fn log(header: &BinGyroHeader, blocks: u32, keep: impl Fn(u32) -> bool) -> Vec<u8> {
+        let mut encoder = BlockEncoder::new(&SENSOR);
+        let mut log = header.as_bytes().to_vec();
+        for i in 0..blocks {
+            let payload: Vec<u8> = (0..30 * SENSOR.sample_len()).map(|b| (b as u32 + i) as u8).collect();
+            let header = encoder.frame(18_060 * (i as u64 + 1), &payload);
+            if keep(i) {
+                log.extend_from_slice(header.as_bytes());
+                log.extend_from_slice(&payload);
+            }
+        }
+        log
+    }
+
+    #[test]
+    fn recover_writes_every_log_found() {
+        let dir = temp_dir("recover");
+        let first = BinGyroHeader::new(SENSOR).with_segment(7, 0).with_next_segment();
+        let second = BinGyroHeader::new(SENSOR).with_segment(7, 1);
+        let intact = log(&first, 5, |_| true);
+        let damaged = log(&second, 5, |i| i != 2);
+        // Blank space before, between and after, like free clusters on a card
+        let mut image = vec![0; 4096];
+        image.extend_from_slice(&intact);
+        let gap = image.len();
+        image.resize(gap + 4096, 0xFF);
+        image.extend_from_slice(&damaged);
+        // The header of a log that got no further
+        image.extend_from_slice(BinGyroHeader::new(SENSOR).with_segment(8, 0).as_bytes());
+        image.resize(image.len() + 4096, 0);
+        let image_path = dir.join("card.img");
+        fs::write(&image_path, &image).unwrap();
+
+        let out = temp_dir("recover-out");
+        assert_eq!(recover_logs(&image_path, &out), Ok(Some(Status::Damaged)));
+        let mut files: Vec<_> = fs::read_dir(&out)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        let second_at = gap + 4096;
+        assert_eq!(files, ["recovered_7_0_4096.bin".to_string(), format!("recovered_7_1_{second_at}.bin")]);
+        assert_eq!(fs::read(out.join("recovered_7_0_4096.bin")).unwrap(), intact);
+        assert_eq!(fs::read(out.join(format!("recovered_7_1_{second_at}.bin"))).unwrap(), damaged);
+
+        // Recovering again keeps what is there
+        assert_eq!(recover_logs(&image_path, &out), Err(Status::Io));
+        assert_eq!(fs::read(out.join("recovered_7_0_4096.bin")).unwrap(), intact);
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(out).unwrap();
+    }
+
+    #[test]
+    fn recover_counts_missing_blocks() {
+        let dir = temp_dir("recover-missing");
+        let header = BinGyroHeader::new(SENSOR).with_segment(1, 0);
+        let image_path = dir.join("card.img");
+
+        fs::write(&image_path, log(&header, 8, |_| true)).unwrap();
+        let out = dir.join("intact");
+        fs::create_dir(&out).unwrap();
+        assert_eq!(recover_logs(&image_path, &out), Ok(Some(Status::Ok)));
+
+        // Two runs of lost blocks, the last block made it
+        let mut recovered = Recovered::create(&dir, 2, &header, (0, 0)).unwrap();
+        let mut encoder = BlockEncoder::new(&SENSOR);
+        let payload = vec![0; SENSOR.sample_len()];
+        for i in 0..10 {
+            let block = encoder.frame(1000 * (i + 1), &payload);
+            if ![3, 4, 7].contains(&i) {
+                recovered.add(&block, &payload).unwrap();
+            }
+        }
+        assert_eq!(recovered.missing, 3);
+        assert_eq!(recovered.finish(), Ok(Status::Damaged));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn recover_without_logs_is_unreadable() {
+        let dir = temp_dir("recover-blank");
+        let image_path = dir.join("card.img");
+        fs::write(&image_path, vec![0; 64 * 1024]).unwrap();
+        assert_eq!(recover_logs(&image_path, &dir), Ok(None));
+        assert_eq!(recover(&image_path, &dir), Status::Unreadable);
+        assert_eq!(recover(&dir.join("missing.img"), &dir), Status::Io);
+        fs::remove_dir_all(dir).unwrap();
     }
 }
@@ -0,0 +1,639 @@
+use crate::timeline::Timeline;
+use chrono::{DateTime, Utc};
+use std::{fmt, fs, io};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use traccam_common::gyro_format::binary::{self, BinGyroHeader, Block, Compression, GapTracker, SensorConfig};
+use traccam_common::gyro_format::orientation::Orientation;
+use traccam_common::gyro_format::text::{self, Channels, RowScale};
+use traccam_common::imu::ImuSample;
+use traccam_common::imu::calibration::Calibration;
+use traccam_common::imu::lsm6ds3::ImuConfig;
+
+const LEGACY_SAMPLE_PERIOD: f64 = 602.4096386;
+/// Bytes after a Gyroflow header looked at to tell text from raw samples
+const SNIFF_LEN: usize = 256;
+
+/// A log read off the card, with everything that went wrong recording or storing it
+pub struct Recording {
+    source: Source,
+    /// How the IMU was mounted in the camera
+    pub orientation: Orientation,
+    /// Recorded besides gyro and accel
+    pub channels: Channels,
+    /// In file order
+    pub problems: Vec<Problem>,
+    /// Later segments of the recording that were joined on, in order
+    pub joined: Vec<PathBuf>,
+}
+
+enum Source {
+    Binary {
+        header: BinGyroHeader,
+        /// Runs of consecutive blocks, each gets its own clock
+        segments: Vec<Vec<Block>>,
+    },
+    /// Text header followed by raw samples, written by the first firmware
+    Legacy { data: Vec<u8>, header_len: usize },
+    /// Gyroflow CSV, converted to our units on loading
+    Csv { rows: Vec<Row> },
+}
+
+/// Data that is missing or damaged, the rest of the log is still usable
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// Blocks that never made it to the card
+    Gap { before_block: u32, blocks: u32, samples: u64 },
+    /// The IMU restarted its FIFO
+    Overrun { block: u32, lost_samples: u32 },
+    Corrupt { offset: u64, skipped: u64 },
+    Truncated { offset: u64 },
+    /// A segment file the recording went on in wasn't found
+    MissingSegment { segment: u16 },
+    /// Found in a joined segment rather than the file opened
+    InSegment { path: PathBuf, problem: Box<Problem> },
+}
+
+impl Problem {
+    /// Whether the file itself is damaged, rather than the recording having dropped data
+    pub fn is_damage(&self) -> bool {
+        match self {
+            Problem::Corrupt { .. } | Problem::Truncated { .. } | Problem::MissingSegment { .. } => true,
+            Problem::InSegment { problem, .. } => problem.is_damage(),
+            Problem::Gap { .. } | Problem::Overrun { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Gap { before_block, blocks, samples } => {
+                write!(f, "{blocks} blocks missing before block {before_block}, about {samples} samples")
+            }
+            Problem::Overrun { block, lost_samples } => {
+                write!(f, "IMU FIFO overrun at block {block}, about {lost_samples} samples lost")
+            }
+            Problem::Corrupt { offset, skipped } => write!(f, "corrupt block at byte {offset}, skipped {skipped} bytes"),
+            Problem::Truncated { offset } => write!(f, "file truncated at byte {offset}"),
+            Problem::MissingSegment { segment } => write!(f, "segment {segment} of the recording not found"),
+            Problem::InSegment { path, problem } => write!(f, "in {}: {problem}", path.display()),
+        }
+    }
+}
+
+/// Why a file can't be read at all
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    UnknownFormat,
+    /// Binary log with a header we can't use
+    Binary(binary::Error),
+    /// Gyroflow log with a header or rows we can't use
+    Text(text::Error),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "{e}"),
+            LoadError::UnknownFormat => write!(f, "neither a binary log nor a Gyroflow IMU log"),
+            LoadError::Binary(e) => write!(f, "{e}"),
+            LoadError::Text(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+/// One exported sample
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Row {
+    /// Since the first sample
+    pub micros: f64,
+    /// Gyro in n°/s and accel in ng, None where a lost sample was filled in
+    pub values: Option<[i64; 6]>,
+    /// Magnetometer in nµT, if the log has it
+    pub mag: Option<[i64; 3]>,
+    /// Die temperature in n°C, if the log has it
+    pub temperature: Option<i64>,
+}
+
+impl Row {
+    fn lost(micros: f64) -> Self {
+        Self { micros, values: None, mag: None, temperature: None }
+    }
+
+    fn sample(micros: f64, scale: &RowScale, sample: &ImuSample) -> Self {
+        let text::Row { values, mag, temperature, .. } = scale.row(0, sample);
+        Self { micros, values, mag, temperature }
+    }
+}
+
+impl Recording {
+    pub fn open(path: &Path) -> Result<Self, LoadError> {
+        Self::from_bytes(fs::read(path).map_err(LoadError::Io)?)
+    }
+
+    /// Like [`Self::open`], but joins on the segments a binary log goes on in. They are looked
+    /// for next to it by session ID, later segments alone export from there on.
+    pub fn open_session(path: &Path) -> Result<Self, LoadError> {
+        let data = fs::read(path).map_err(LoadError::Io)?;
+        let header = match binary::Reader::new(data.as_slice()) {
+            Ok(reader) if reader.header().continues() => reader.header().clone(),
+            _ => return Self::from_bytes(data),
+        };
+
+        let mut parts = vec![];
+        let mut missing = vec![];
+        let mut next = header.segment() + 1;
+        let mut continues = true;
+        for (segment, path, header) in find_segments(path, &header).map_err(LoadError::Io)? {
+            missing.extend(next..segment);
+            next = segment + 1;
+            continues = header.continues();
+            parts.push((fs::read(&path).map_err(LoadError::Io)?, path));
+            if !continues {
+                break;
+            }
+        }
+        // The last segment found still expected another one
+        if continues {
+            missing.push(next);
+        }
+
+        let mut recording = Self::from_binary(&data, &parts)?;
+        recording.problems.extend(missing.into_iter().map(|segment| Problem::MissingSegment { segment }));
+        Ok(recording)
+    }
+
+    /// Picks the decoder by the first bytes, anything else is refused rather than guessed at
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, LoadError> {
+        if binary::is_binary(&data) {
+            return Self::from_binary(&data, &[]);
+        }
+        let reader = match text::Reader::new(data.as_slice()) {
+            Ok(reader) => reader,
+            Err(text::Error::BadMagic) => return Err(LoadError::UnknownFormat),
+            Err(text::Error::Io(e)) => return Err(LoadError::Io(e)),
+            Err(e) => return Err(LoadError::Text(e)),
+        };
+        let header_len = reader.offset() as usize;
+        if is_text(&data[header_len..]) {
+            Self::from_csv(reader)
+        } else {
+            let header = reader.header().clone();
+            Self::from_legacy(data, &header, header_len)
+        }
+    }
+
+    fn from_legacy(data: Vec<u8>, header: &text::Header, header_len: usize) -> Result<Self, LoadError> {
+        // The first firmware wrote the samples in exactly the column order, without timestamps
+        if header.columns != ["t", "gx", "gy", "gz", "ax", "ay", "az"] {
+            return Err(LoadError::Text(text::Error::BadHeader {
+                line: data[..header_len].iter().filter(|&&b| b == b'\n').count(),
+                reason: format!("raw samples with unexpected columns {}", header.columns.join(",")),
+            }));
+        }
+        let mut problems = vec![];
+        let samples = data.len() - header_len;
+        if !samples.is_multiple_of(ImuSample::LEN) {
+            let offset = (data.len() - samples % ImuSample::LEN) as u64;
+            problems.push(Problem::Truncated { offset });
+        }
+        Ok(Self {
+            source: Source::Legacy { data, header_len },
+            orientation: header.orientation,
+            channels: Channels::NONE,
+            problems,
+            joined: vec![],
+        })
+    }
+
+    fn from_csv(reader: text::Reader<&[u8]>) -> Result<Self, LoadError> {
+        let header = reader.header().clone();
+        // Gyroflow reads t * tscale as seconds, gyro * gscale as rad/s and accel * ascale as g
+        let to_micros = header.tscale / text::TSCALE;
+        let gyro = header.gscale / text::GSCALE;
+        let accel = header.ascale / text::ASCALE;
+        let mag = header.mscale.unwrap_or(text::MSCALE) / text::MSCALE;
+        let nanos = |value: f64, scale: f64| (value * scale * 1e9).round() as i64;
+        let channels = Channels {
+            mag: header.mscale.is_some(),
+            temperature: header.columns.iter().any(|c| c == text::TEMPERATURE_COLUMN),
+            // Recomputed on export rather than passed through
+            quaternion: false,
+        };
+
+        let mut rows = vec![];
+        let mut problems = vec![];
+        let mut t0 = None;
+        for record in reader {
+            let record = match record {
+                Ok(record) => record,
+                // A write cut short by pulling the card, the rows before it are fine
+                Err(text::Error::Truncated { offset, .. }) => {
+                    problems.push(Problem::Truncated { offset });
+                    break;
+                }
+                Err(text::Error::Io(e)) => return Err(LoadError::Io(e)),
+                Err(e) => return Err(LoadError::Text(e)),
+            };
+            let t = *t0.get_or_insert(record.t);
+            let [gx, gy, gz] = record.gyro.map(|v| nanos(v, gyro));
+            let [ax, ay, az] = record.accel.map(|v| nanos(v, accel));
+            rows.push(Row {
+                micros: (record.t - t) * to_micros,
+                values: (!record.is_lost()).then_some([gx, gy, gz, ax, ay, az]),
+                mag: record.mag.filter(|m| !m.iter().any(|v| v.is_nan())).map(|m| m.map(|v| nanos(v, mag))),
+                temperature: record.temperature.filter(|v| !v.is_nan()).map(|v| nanos(v, 1.0)),
+            });
+        }
+        Ok(Self { source: Source::Csv { rows }, orientation: header.orientation, channels, problems, joined: vec![] })
+    }
+
+    /// `data` followed by the segment files `parts` it goes on in. Block numbers and timestamps
+    /// run on across segments, so they join like one file.
+    fn from_binary(data: &[u8], parts: &[(Vec<u8>, PathBuf)]) -> Result<Self, LoadError> {
+        let reader = binary::Reader::new(data).map_err(LoadError::Binary)?;
+        let header = reader.header().clone();
+        let nominal_period = header.ticks_per_sample();
+
+        let mut problems = vec![];
+        let mut segments: Vec<Vec<Block>> = vec![];
+        let mut gaps = GapTracker::new();
+        let mut readers = vec![(None, reader)];
+        for (data, path) in parts {
+            readers.push((Some(path), binary::Reader::new(data.as_slice()).map_err(LoadError::Binary)?));
+        }
+        let blocks = readers.into_iter().flat_map(|(path, reader)| reader.map(move |block| (path, block)));
+        for (path, block) in blocks {
+            let in_segment = |problem| match path {
+                Some(path) => Problem::InSegment { path: path.clone(), problem: Box::new(problem) },
+                None => problem,
+            };
+            let block = match block {
+                Ok(block) => block,
+                Err(binary::Error::BadBlock { offset, skipped }) => {
+                    problems.push(in_segment(Problem::Corrupt { offset, skipped }));
+                    continue;
+                }
+                // The next segment picks up after the cut
+                Err(binary::Error::Truncated { offset }) => {
+                    problems.push(in_segment(Problem::Truncated { offset }));
+                    continue;
+                }
+                Err(binary::Error::Io(e)) => return Err(LoadError::Io(e)),
+                Err(e) => return Err(LoadError::Binary(e)),
+            };
+            if let Some(gap) = gaps.check(&block.header, nominal_period) {
+                problems.push(Problem::Gap {
+                    before_block: block.header.sequence(),
+                    blocks: gap.blocks,
+                    samples: gap.samples,
+                });
+                segments.push(vec![]);
+            }
+            // The IMU restarted its FIFO, the clock fit can't span that
+            if block.header.lost_samples() > 0 {
+                problems.push(Problem::Overrun {
+                    block: block.header.sequence(),
+                    lost_samples: block.header.lost_samples(),
+                });
+                segments.push(vec![]);
+                continue;
+            }
+            match segments.last_mut() {
+                Some(segment) => segment.push(block),
+                None => segments.push(vec![block]),
+            }
+        }
+        segments.retain(|s| !s.is_empty());
+
+        let orientation = header.orientation();
+        let channels = row_scale(header.sensor()).channels();
+        let joined = parts.iter().map(|(_, path)| path.clone()).collect();
+        Ok(Self { source: Source::Binary { header, segments }, orientation, channels, problems, joined })
+    }
+
+    /// Session ID, segment number and whether the recording goes on, only binary logs have them
+    pub fn segment(&self) -> Option<(u32, u16, bool)> {
+        match &self.source {
+            Source::Binary { header, .. } => Some((header.session_id(), header.segment(), header.continues())),
+            _ => None,
+        }
+    }
+
+    /// Short description of the file format
+    pub fn format(&self) -> String {
+        match &self.source {
+            Source::Binary { header, .. } => match header.compression() {
+                Compression::None => format!("binary v{}", header.version()),
+                Compression::Delta => format!("binary v{}, delta packed", header.version()),
+            },
+            Source::Legacy { .. } => "Gyroflow header with raw samples".into(),
+            Source::Csv { .. } => "Gyroflow CSV".into(),
+        }
+    }
+
+    /// What recorded the samples, unknown for CSV
+    pub fn sensor(&self) -> Option<SensorConfig> {
+        match &self.source {
+            Source::Binary { header, .. } => Some(*header.sensor()),
+            // Old firmware always recorded with the default config
+            Source::Legacy { .. } => Some(ImuConfig::DEFAULT.sensor_config()),
+            Source::Csv { .. } => None,
+        }
+    }
+
+    /// Offsets the logger measured at the start of recording, only newer binary logs have them
+    pub fn calibration(&self) -> Option<Calibration> {
+        match &self.source {
+            Source::Binary { header, .. } => header.calibration(),
+            _ => None,
+        }
+    }
+
+    /// Intact blocks, only binary logs have them
+    pub fn blocks(&self) -> Option<usize> {
+        match &self.source {
+            Source::Binary { segments, .. } => Some(segments.iter().map(Vec::len).sum()),
+            _ => None,
+        }
+    }
+
+    /// When the first sample was taken, if the logger knew the time
+    pub fn start_utc(&self) -> Option<DateTime<Utc>> {
+        let Source::Binary { header, segments } = &self.source else { return None };
+        let first = *Timeline::new(header).segment_times(segments.first()?).first()?;
+        header.unix_micros_at(first).and_then(|us| DateTime::from_timestamp_micros(us as i64))
+    }
+
+    /// Every sample in °/s, g, µT and °C, with lost ones filled in to keep the timeline intact
+    pub fn rows(&self, mut emit: impl FnMut(Row) -> io::Result<()>) -> io::Result<()> {
+        match &self.source {
+            Source::Binary { header, segments } => {
+                let scale = row_scale(header.sensor());
+                let to_micros = 1_000_000.0 / header.timescale() as f64;
+                let mut timeline = Timeline::new(header);
+                let mut start = None;
+                let mut last: Option<(f64, f64)> = None;
+                for segment in segments {
+                    let times = timeline.segment_times(segment);
+                    let Some(&first) = times.first() else { continue };
+                    let t0 = *start.get_or_insert(first);
+
+                    if let Some((last_t, period)) = last {
+                        let mut t = last_t + period;
+                        while t < first - period / 2.0 {
+                            emit(Row::lost((t - t0) * to_micros))?;
+                            t += period;
+                        }
+                    }
+                    let samples = segment.iter().flat_map(|block| block.samples());
+                    for (t, sample) in times.iter().zip(samples) {
+                        emit(Row::sample((t - t0) * to_micros, &scale, &sample))?;
+                    }
+
+                    let period = match times.len() {
+                        1 => timeline.nominal_period(),
+                        n => (times[n - 1] - first) / (n - 1) as f64,
+                    };
+                    last = Some((times[times.len() - 1], period));
+                }
+            }
+            Source::Legacy { data, header_len } => {
+                let scale = row_scale(&ImuConfig::DEFAULT.sensor_config());
+                let (samples, _) = data[*header_len..].as_chunks::<{ ImuSample::LEN }>();
+                for (i, bytes) in samples.iter().enumerate() {
+                    let sample = ImuSample::from_le_bytes(bytes);
+                    emit(Row::sample(i as f64 * LEGACY_SAMPLE_PERIOD, &scale, &sample))?;
+                }
+            }
+            Source::Csv { rows } => rows.iter().try_for_each(|&row| emit(row))?,
+        }
+        Ok(())
+    }
+}
+
+/// Later segments of the recording `header` belongs to, found next to `path`, in order
+fn find_segments(path: &Path, header: &BinGyroHeader) -> io::Result<Vec<(u16, PathBuf, BinGyroHeader)>> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut found = vec![];
+    for entry in fs::read_dir(dir)? {
+        let other = entry?.path();
+        if !other.is_file() || other.file_name() == path.file_name() {
+            continue;
+        }
+        // Only the header is read, anything that isn't a binary log is skipped
+        let Ok(reader) = File::open(&other).map_err(binary::Error::Io).and_then(binary::Reader::new) else {
+            continue;
+        };
+        let segment = reader.header().segment();
+        if reader.header().same_session(header) && segment > header.segment() {
+            found.push((segment, other, reader.header().clone()));
+        }
+    }
+    found.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+    found.dedup_by_key(|(segment, ..)| *segment);
+    Ok(found)
+}
+
+fn row_scale(sensor: &SensorConfig) -> RowScale {
+    let mut scale = RowScale::new(sensor.gyro_dps_per_lsb(), sensor.accel_g_per_lsb());
+    if let Some(ut_per_lsb) = sensor.mag_ut_per_lsb() {
+        scale = scale.with_mag(ut_per_lsb);
+    }
+    if let Some((c_per_lsb, zero_c)) = sensor.temperature_scale() {
+        scale = scale.with_temperature(c_per_lsb, zero_c);
+    }
+    scale
+}
+
+/// Whether what follows a Gyroflow header is CSV rather than raw samples. Raw IMU words turn
+/// into control characters and bytes above ASCII almost immediately.
+fn is_text(body: &[u8]) -> bool {
+    !body.is_empty() && body.iter().take(SNIFF_LEN).all(|b| b.is_ascii_graphic() || b.is_ascii_whitespace())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_dir;
+    use traccam_common::gyro_format::binary::BlockEncoder;
+    use zerocopy::IntoBytes;
+
+    const SENSOR: SensorConfig = ImuConfig::DEFAULT.sensor_config();
+    const BLOCKS: usize = 20;
+    const SAMPLES: usize = 50;
+
+    /// A recording split into segment files, block numbers and times run on across them
+    struct Session {
+        encoder: BlockEncoder,
+        samples: usize,
+    }
+
+    impl Session {
+        fn new() -> Self {
+            Self { encoder: BlockEncoder::new(&SENSOR), samples: 0 }
+        }
+
+        fn segment(&mut self, header: BinGyroHeader) -> Vec<u8> {
+            let period = header.ticks_per_sample();
+            let mut log = header.as_bytes().to_vec();
+            for _ in 0..BLOCKS {
+                self.samples += SAMPLES;
+                let payload = vec![0; SAMPLES * SENSOR.sample_len()];
+                let block = self.encoder.frame((self.samples as f64 * period) as u64, &payload);
+                log.extend_from_slice(block.as_bytes());
+                log.extend_from_slice(&payload);
+            }
+            log
+        }
+    }
+
+    fn header(session: u32, segment: u16) -> BinGyroHeader {
+        BinGyroHeader::new(SENSOR).with_segment(session, segment)
+    }
+
+    /// Samples and filled in lost ones
+    fn rows(recording: &Recording) -> (usize, usize) {
+        let (mut samples, mut lost) = (0, 0);
+        recording
+            .rows(|row| {
+                match row.values {
+                    Some(_) => samples += 1,
+                    None => lost += 1,
+                }
+                Ok(())
+            })
+            .unwrap();
+        (samples, lost)
+    }
+
+    #[test]
+    fn complete_session_joins_like_one_file() {
+        let dir = temp_dir("segments-complete");
+        let mut session = Session::new();
+        for segment in 0..3 {
+            let header = match segment {
+                2 => header(5, segment),
+                _ => header(5, segment).with_next_segment(),
+            };
+            fs::write(dir.join(format!("LOG0000{segment}.BIN")), session.segment(header)).unwrap();
+        }
+        // Started right after and named next, but a recording of its own
+        fs::write(dir.join("LOG00003.BIN"), Session::new().segment(header(6, 3))).unwrap();
+
+        let recording = Recording::open_session(&dir.join("LOG00000.BIN")).unwrap();
+        assert_eq!(recording.problems, []);
+        assert_eq!(recording.joined, [dir.join("LOG00001.BIN"), dir.join("LOG00002.BIN")]);
+        assert_eq!(rows(&recording), (3 * BLOCKS * SAMPLES, 0));
+
+        // A later segment alone goes on from there
+        let recording = Recording::open_session(&dir.join("LOG00001.BIN")).unwrap();
+        assert_eq!(recording.joined, [dir.join("LOG00002.BIN")]);
+        assert_eq!(rows(&recording), (2 * BLOCKS * SAMPLES, 0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn missing_segments_are_reported_and_filled_in() {
+        let dir = temp_dir("segments-missing");
+        let mut session = Session::new();
+        let segments: Vec<Vec<u8>> = (0..4)
+            .map(|segment| session.segment(header(9, segment).with_next_segment()))
+            .collect();
+        // Segment 1 is lost, and the card was pulled before segment 4 got its header
+        fs::write(dir.join("LOG00010.BIN"), &segments[0]).unwrap();
+        fs::write(dir.join("LOG00012.BIN"), &segments[2]).unwrap();
+        fs::write(dir.join("LOG00013.BIN"), &segments[3]).unwrap();
+        // A stale copy of segment 2 that sorts after the real one
+        let mut stale = segments[2].clone();
+        stale.truncate(BinGyroHeader::LEN);
+        fs::write(dir.join("LOG00012~1.BIN"), stale).unwrap();
+        // Not a log at all
+        fs::write(dir.join("NOTES.TXT"), "lens cap on").unwrap();
+        // Another recording in the same folder
+        fs::write(dir.join("LOG00011.BIN"), Session::new().segment(header(10, 1))).unwrap();
+
+        let recording = Recording::open_session(&dir.join("LOG00010.BIN")).unwrap();
+        assert_eq!(recording.joined, [dir.join("LOG00012.BIN"), dir.join("LOG00013.BIN")]);
+        let missing: Vec<_> = recording
+            .problems
+            .iter()
+            .filter(|problem| matches!(problem, Problem::MissingSegment { .. }))
+            .collect();
+        assert_eq!(missing, [&Problem::MissingSegment { segment: 1 }, &Problem::MissingSegment { segment: 4 }]);
+        assert!(recording.problems.contains(&Problem::Gap {
+            before_block: 2 * BLOCKS as u32,
+            blocks: BLOCKS as u32,
+            samples: (BLOCKS * SAMPLES) as u64,
+        }));
+        // The lost segment is filled in to keep the timeline
+        assert_eq!(rows(&recording), (3 * BLOCKS * SAMPLES, BLOCKS * SAMPLES));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    const GYROFLOW_HEADER: &str = "GYROFLOW IMU LOG,\nversion,1.3\nid,REVISION\norientation,XYZ\ntscale,0.000001\n\
+        gscale,0.0174532925\nascale,1.0\nt,gx,gy,gz,ax,ay,az,\n";
+
+    #[test]
+    fn binary_logs_are_told_by_their_magic() {
+        let mut log = Session::new().segment(header(1, 0));
+        let recording = Recording::from_bytes(log.clone()).unwrap();
+        assert!(matches!(recording.source, Source::Binary { .. }));
+        assert_eq!(recording.blocks(), Some(BLOCKS));
+
+        // The magic alone isn't a usable header
+        log.truncate(binary::MAGIC.len() + 4);
+        assert!(matches!(Recording::from_bytes(log), Err(LoadError::Binary(_))));
+    }
+
+    #[test]
+    fn gyroflow_header_is_followed_by_csv_or_raw_samples() {
+        // Start of LOG.CSV, written by the first firmware
+        let mut legacy = GYROFLOW_HEADER.as_bytes().to_vec();
+        legacy.extend_from_slice(&[
+            0xf7, 0xfe, 0xfb, 0xff, 0xc8, 0x00, 0xee, 0xe8, 0xe4, 0x06, 0x1c, 0x35,
+            0x22, 0x19, 0xd8, 0xee, 0x10, 0xed, 0xa1, 0xe4, 0xc2, 0x07, 0x43, 0x3c,
+        ]);
+        let recording = Recording::from_bytes(legacy).unwrap();
+        assert!(matches!(recording.source, Source::Legacy { header_len, .. } if header_len == GYROFLOW_HEADER.len()));
+        assert_eq!(recording.problems, []);
+
+        let csv = format!("{GYROFLOW_HEADER}0,-2.31875, -0.04375, 1.75, -0.360266, 0.107604, 0.829356\n");
+        let recording = Recording::from_bytes(csv.into_bytes()).unwrap();
+        let Source::Csv { rows } = &recording.source else { panic!("not read as CSV") };
+        assert_eq!(rows.len(), 1);
+    }
+
+    #[test]
+    fn unknown_and_broken_files_are_refused() {
+        for data in [&b""[..], b"t,gx,gy,gz,ax,ay,az\n0,0,0,0,0,0,1\n", &[0xf7, 0xfe, 0xfb, 0xff, 0xc8, 0x00]] {
+            assert!(matches!(Recording::from_bytes(data.to_vec()), Err(LoadError::UnknownFormat)));
+        }
+
+        // The magic, then something that isn't a header
+        let mut garbage = b"GYROFLOW IMU LOG\n".to_vec();
+        garbage.extend_from_slice(&[0xf7, 0xfe, 0xfb, 0xff, 0xc8, 0x0a]);
+        let error = Recording::from_bytes(garbage).err().unwrap();
+        assert!(matches!(error, LoadError::Text(text::Error::BadHeader { line: 2, .. })), "{error}");
+
+        // Raw samples in a layout the first firmware never wrote
+        let raw = GYROFLOW_HEADER.replace("t,gx,gy,gz,ax,ay,az", "t,ax,ay,az,gx,gy,gz") + "\u{1}\u{2}";
+        assert!(matches!(Recording::from_bytes(raw.into_bytes()), Err(LoadError::Text(text::Error::BadHeader { .. }))));
+    }
+
+    #[test]
+    fn text_is_sniffed_from_the_first_bytes() {
+        assert!(is_text(b"0,1,2,3,4,5,6\r\n"));
+        assert!(!is_text(b""));
+        assert!(!is_text(&[b'0', b',', 0xf7, 0xfe]));
+        // Only the start is looked at
+        let mut late = vec![b'0'; SNIFF_LEN];
+        late.push(0xff);
+        assert!(is_text(&late));
+    }
+}
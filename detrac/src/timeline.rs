@@ -0,0 +1,209 @@
+use traccam_common::gyro_format::binary::{BinGyroHeader, Block};
+use traccam_common::timing::{CounterUnwrapper, WindowedFit};
+
+/// Batches on either side that are fitted for the sample clock, about 6s at 1.66kHz
+const CLOCK_FIT_RADIUS: usize = 40;
+/// Width of the LSM6DS3 timestamp counter
+const HW_TIMESTAMP_BITS: u32 = 24;
+
+/// Reconstructs when every sample was captured, in timescale ticks of the MCU clock.
+///
+/// Without hardware timestamps the sample clock is fitted to the batch stamps. With them,
+/// the IMU ticks give the exact spacing and the batch stamps only calibrate their length.
+pub struct Timeline {
+    nominal_period: f64,
+    hw: Option<HwTicks>,
+}
+
+struct HwTicks {
+    counter: CounterUnwrapper,
+    /// Nominal length of one IMU tick in timescale ticks
+    nominal_tick: f64,
+    last_batch: Option<u64>,
+}
+
+impl Timeline {
+    pub fn new(header: &BinGyroHeader) -> Self {
+        let hw = header.sensor().hw_timestamp_ns().map(|lsb| HwTicks {
+            counter: CounterUnwrapper::new(HW_TIMESTAMP_BITS),
+            nominal_tick: lsb.get() as f64 * header.timescale() as f64 / 1e9,
+            last_batch: None,
+        });
+        Self {
+            nominal_period: header.ticks_per_sample(),
+            hw,
+        }
+    }
+
+    pub fn nominal_period(&self) -> f64 {
+        self.nominal_period
+    }
+
+    /// Times of all samples in a run of consecutive blocks, feed segments in file order
+    pub fn segment_times(&mut self, segment: &[Block]) -> Vec<f64> {
+        match &mut self.hw {
+            None => fit_sample_clock(segment, self.nominal_period),
+            Some(hw) => hw.sample_times(segment, self.nominal_period),
+        }
+    }
+}
+
+fn fit_sample_clock(segment: &[Block], nominal_period: f64) -> Vec<f64> {
+    let mut total = 0;
+    let batches: Vec<(u64, u64)> = segment
+        .iter()
+        .filter(|block| block.header.sample_count() > 0)
+        .map(|block| {
+            total += block.header.sample_count() as u64;
+            (total - 1, block.header.timestamp())
+        })
+        .collect();
+
+    let mut times = Vec::with_capacity(total as usize);
+    let mut first = 0;
+    for ((last, _), clock) in batches.iter().zip(WindowedFit::new(&batches, CLOCK_FIT_RADIUS, nominal_period)) {
+        times.extend((first..=*last).map(|i| clock.time_of(i)));
+        first = last + 1;
+    }
+    times
+}
+
+impl HwTicks {
+    fn sample_times(&mut self, segment: &[Block], nominal_period: f64) -> Vec<f64> {
+        let mut ticks = vec![];
+        let mut batches = vec![];
+        for (i, block) in segment.iter().enumerate() {
+            let Some(raw) = block.hw_timestamps() else { continue };
+            for (j, raw) in raw.enumerate() {
+                let tick = match self.last_batch {
+                    // Resolve counter wraps across the gap with the MCU clock
+                    Some(last) if i == 0 && j == 0 => {
+                        let first_sample = block.header.timestamp() as f64 - (block.header.sample_count() as f64 - 1.0) * nominal_period;
+                        let elapsed = (first_sample - last as f64).max(0.0) / self.nominal_tick;
+                        self.counter.resume(raw, elapsed as u64)
+                    }
+                    _ => self.counter.next(raw),
+                };
+                ticks.push(tick);
+            }
+            if let Some(&last) = ticks.last() && block.header.sample_count() > 0 {
+                batches.push((last, block.header.timestamp()));
+                self.last_batch = Some(block.header.timestamp());
+            }
+        }
+
+        let mut times = Vec::with_capacity(ticks.len());
+        let mut rest = ticks.as_slice();
+        for ((last, _), clock) in batches.iter().zip(WindowedFit::new(&batches, CLOCK_FIT_RADIUS, self.nominal_tick)) {
+            let end = rest.partition_point(|t| t <= last);
+            times.extend(rest[..end].iter().map(|&t| clock.time_of(t)));
+            rest = &rest[end..];
+        }
+        times
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use traccam_common::gyro_format::binary::{BlockEncoder, Reader, SensorConfig};
+    use traccam_common::imu::lsm6ds3::ImuConfig;
+    use traccam_common::imu::ImuSample;
+    use zerocopy::IntoBytes;
+
+    const SENSOR: SensorConfig = ImuConfig::DEFAULT.sensor_config();
+    /// Nominal 25 µs per tick of the LSM6DS3 timestamp counter
+    const HW_LSB_NS: u32 = 25_000;
+
+    struct Jitter(u32);
+
+    impl Jitter {
+        /// Up to 0.2 ms until the readout task gets to stamp the batch
+        fn next(&mut self) -> f64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            (self.0 % 200) as f64
+        }
+    }
+
+    /// Frames samples captured at `truth` the way the firmware does, a batch every ~250
+    /// samples with an empty overrun block after the first. `hw_ticks` holds the unwrapped IMU
+    /// counter of every sample, if the log records it.
+    fn blocks(sensor: SensorConfig, truth: &[f64], hw_ticks: Option<&[u64]>) -> Vec<Block> {
+        let mut log = BinGyroHeader::new(sensor).as_bytes().to_vec();
+        let mut encoder = BlockEncoder::new(&sensor);
+        let mut jitter = Jitter(0x2545_F491);
+        let mut read = 0;
+        while read < truth.len() {
+            let last = (read + 249).min(truth.len() - 1);
+            let mut payload = vec![];
+            for i in read..=last {
+                payload.extend_from_slice(&[0; ImuSample::LEN]);
+                if let Some(ticks) = hw_ticks {
+                    // The FIFO timestamp data set, only 24 bits of the counter
+                    let [b0, b1, b2, _] = (ticks[i] as u32).to_le_bytes();
+                    payload.extend_from_slice(&[b1, b2, 0, b0, 0, 0]);
+                }
+            }
+            let header = encoder.frame((truth[last] + jitter.next()) as u64, &payload);
+            log.extend_from_slice(header.as_bytes());
+            log.extend_from_slice(&payload);
+            if read == 0 {
+                log.extend_from_slice(encoder.overrun((truth[last] + 300.0) as u64, 1).as_bytes());
+            }
+            read = last + 1;
+        }
+        Reader::new(log.as_slice()).unwrap().map(Result::unwrap).collect()
+    }
+
+    fn worst_error(times: &[f64], truth: &[f64]) -> f64 {
+        assert_eq!(times.len(), truth.len());
+        times.iter().zip(truth).map(|(t, truth)| (t - truth).abs()).fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn fitted_clock_follows_drift() {
+        // Warming up slows the oscillator by 0.1% over a minute, the recording starts after boot
+        let nominal = 1e6 / 1660.0;
+        let samples = 100_000;
+        let mut t = 5_000_000.0;
+        let truth: Vec<f64> = (0..samples)
+            .map(|i| {
+                t += nominal * (1.0 + 0.001 * i as f64 / samples as f64);
+                t
+            })
+            .collect();
+
+        let blocks = blocks(SENSOR, &truth, None);
+        assert!(blocks.iter().any(|block| block.header.sample_count() == 0));
+        let mut timeline = Timeline::new(&BinGyroHeader::new(SENSOR));
+        let times = timeline.segment_times(&blocks);
+        // Well within a sample period, so no sample is placed at its neighbour's time
+        let worst = worst_error(&times, &truth);
+        assert!(worst < 250.0, "worst error {worst}µs");
+    }
+
+    #[test]
+    fn hw_ticks_survive_counter_wraps() {
+        // The IMU oscillator is 2% slow, 24 ticks per sample, the counter wraps soon after the
+        // start and again during the pause between the two segments
+        let sensor = SENSOR.with_hw_timestamps(HW_LSB_NS);
+        let tick = 25.0 * 1.02;
+        let start = (1 << 24) - 20_000;
+        let pause = 500_000_000 / 25;
+        let ticks: Vec<u64> = (0..20_000)
+            .map(|i| start + i * 24)
+            .chain((0..20_000).map(|i| start + pause + i * 24))
+            .collect();
+        let truth: Vec<f64> = ticks.iter().map(|&t| 3_000_000.0 + t as f64 * tick).collect();
+        let (first, second) = ticks.split_at(20_000);
+
+        let mut timeline = Timeline::new(&BinGyroHeader::new(sensor));
+        let mut times = timeline.segment_times(&blocks(sensor, &truth[..20_000], Some(first)));
+        times.extend(timeline.segment_times(&blocks(sensor, &truth[20_000..], Some(second))));
+        assert!(times.windows(2).all(|t| t[0] < t[1]));
+        let worst = worst_error(&times, &truth);
+        assert!(worst < 250.0, "worst error {worst}µs");
+    }
+}
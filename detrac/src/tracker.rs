@@ -0,0 +1,32 @@
+use crate::recording::Row;
+use traccam_common::fusion::{Fusion, Quaternion};
+
+/// Runs a fusion filter over the rows of a recording
+pub struct Tracker {
+    new_filter: fn(Quaternion) -> Box<dyn Fusion>,
+    /// The filter and the time of the last sample it took
+    state: Option<(Box<dyn Fusion>, f64)>,
+}
+
+impl Tracker {
+    /// `new_filter` builds the filter once the first sample tells where down is
+    pub fn new(new_filter: fn(Quaternion) -> Box<dyn Fusion>) -> Self {
+        Self { new_filter, state: None }
+    }
+
+    /// Orientation after `row`, None for lost samples. The first sample after a gap turns the
+    /// filter for the whole gap.
+    pub fn add(&mut self, row: &Row) -> Option<Quaternion> {
+        let [gx, gy, gz, ax, ay, az] = row.values?;
+        let gyro = [gx, gy, gz].map(|v| (v as f64 / 1e9).to_radians());
+        let accel = [ax, ay, az].map(|v| v as f64 / 1e9);
+        match &mut self.state {
+            Some((filter, last)) => {
+                filter.update(gyro, accel, (row.micros - *last) / 1e6);
+                *last = row.micros;
+            }
+            None => self.state = Some(((self.new_filter)(Quaternion::from_gravity(accel)), row.micros)),
+        }
+        self.state.as_ref().map(|(filter, _)| filter.orientation())
+    }
+}
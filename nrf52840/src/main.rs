@@ -4,40 +4,43 @@
 mod imu;
 mod util;
 use embassy_nrf::peripherals;
-use embassy_nrf::rng;
 use crate::util::wait_for_press;
 use embassy_sync::pipe::Pipe;
-use traccam_common::gyro_format::text::get_header_string;
-use crate::imu::SAMPLE_INTERVAL_MICROS;
-use crate::imu::{Imu, ImuRessources};
+use traccam_common::gyro_format::binary::{BinGyroHeader, BlockEncoder, BlockHeader, Compression, SensorConfig};
+use traccam_common::gyro_format::compress;
+use traccam_common::gyro_format::orientation::Orientation;
+use traccam_common::imu::{self as imu_common, ImuDriver};
+use traccam_common::imu::calibration::{Calibration, StillEstimator};
+use traccam_common::imu::lsm6ds3::{self, AccelScale, FifoTimestamps, GyroScale, ImuConfig, Odr};
+use traccam_common::sd_storage::{self, FlushPolicy, LogFile, Rollover, Storage, BLOCK_LEN};
+use crate::imu::Imu;
 use core::fmt::Write;
+use core::mem;
 use core::ops::Add;
-use defmt::info;
+use defmt::{info, warn, Debug2Format, Display2Format};
 use embassy_executor::{InterruptExecutor, Spawner};
 use embassy_futures::yield_now;
 use embassy_nrf::gpio::{Level, Output, OutputDrive, Pull};
 use embassy_nrf::interrupt::InterruptExt;
 use embassy_nrf::interrupt::Priority;
-use embassy_nrf::peripherals::{GPIOTE_CH0, P0_26, GPIOTE_CH1};
+use embassy_nrf::peripherals::{P0_26, GPIOTE_CH1};
 use embassy_nrf::spim::Spim;
+use embassy_nrf::uarte::UarteRx;
 use embassy_nrf::twim::{self};
-use embassy_nrf::{Peri, bind_interrupts, interrupt, spim};
+use embassy_nrf::{Peri, bind_interrupts, interrupt, spim, uarte};
 use embassy_nrf::gpiote::{InputChannel, InputChannelPolarity};
-use embassy_nrf::mode::Async;
-use embassy_nrf::rng::Rng;
+use embassy_sync::blocking_mutex;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Channel;
-use embassy_sync::mutex::Mutex;
 use embassy_sync::signal::Signal;
 use embassy_time::{Delay, Duration};
 use embassy_time::{Instant, Timer};
 use embedded_hal_bus::spi::ExclusiveDevice;
-use embedded_sdmmc::SdCard;
-use embedded_sdmmc::TimeSource;
-use embedded_sdmmc::Timestamp;
-use embedded_sdmmc::VolumeIdx;
-use embedded_sdmmc::VolumeManager;
-use heapless::{format, String, Vec};
+use embedded_sdmmc::{BlockDevice, SdCard, TimeSource};
+use core::cell::Cell;
+use heapless::String;
+use traccam_common::utc::{parse_nmea_utc, UtcAnchor, UtcClock};
+use zerocopy::{FromBytes, IntoBytes};
 use {defmt_rtt as _, panic_probe as _};
 
 static EXECUTOR_RT: InterruptExecutor = InterruptExecutor::new();
@@ -50,7 +53,7 @@ unsafe fn EGU1_SWI1() {
 bind_interrupts!(struct Irqs {
     TWISPI0 => twim::InterruptHandler<peripherals::TWISPI0>;
     TWISPI1 => spim::InterruptHandler<peripherals::TWISPI1>;
-    RNG => rng::InterruptHandler<peripherals::RNG>;
+    UARTE0 => uarte::InterruptHandler<peripherals::UARTE0>;
 });
 
 #[embassy_executor::main]
@@ -70,7 +73,7 @@ async fn main(spawner: Spawner) {
     let rt_spawner = EXECUTOR_RT.start(interrupt::EGU1_SWI1);
 
     // IMU stuff
-    let resources = ImuRessources::new(p.P1_08, p.P0_11, p.GPIOTE_CH0, p.TWISPI0, p.P0_07, p.P0_27);
+    let imu = imu::new(p.P1_08, p.P0_11, p.TWISPI0, p.P0_07, p.P0_27);
 
     // SD stuff
     let cs = Output::new(p.P0_29, Level::High, OutputDrive::Standard);
@@ -82,14 +85,17 @@ async fn main(spawner: Spawner) {
 
     let spi_device = ExclusiveDevice::new(spi_bus, cs, Delay).unwrap();
 
-    // Misc.
-    let rng = Mutex::<CriticalSectionRawMutex, _>::new(Rng::new(p.RNG, Irqs));
+    // Time from a GPS or host, NMEA on D7
+    let mut uart_config = uarte::Config::default();
+    uart_config.baudrate = uarte::Baudrate::BAUD115200;
+    let time_rx = UarteRx::new(p.UARTE0, Irqs, p.P1_12, uart_config);
 
     // Spawn tasks
-    let _ = rt_spawner.spawn(sample_task(p.P0_26, resources)).unwrap();
+    let _ = rt_spawner.spawn(sample_task(p.P0_26, imu)).unwrap();
     let _ = spawner
-        .spawn(do_sd_card(spi_device, rng))
+        .spawn(do_sd_card(spi_device))
         .unwrap();
+    spawner.spawn(do_time_sync(time_rx)).unwrap();
     loop {
         wait_for_press::<GPIOTE_CH1>(&mut btn_center).await;
         TOGGLE_RECORDING.signal(());
@@ -102,89 +108,310 @@ async fn main(spawner: Spawner) {
     }
 }
 
-static SAMPLES: Pipe<CriticalSectionRawMutex, { 1024 * 2 }> = Pipe::new();
+/// Room for two of the largest blocks, a full FIFO that didn't pack, so a late readout after a
+/// slow SD write still fits while the writer is busy with the one before
+const SAMPLES_LEN: usize = 2 * (BlockHeader::LEN + PACKED_LEN);
+static SAMPLES: Pipe<CriticalSectionRawMutex, SAMPLES_LEN> = Pipe::new();
 static COMPLETE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
-static IMU_READY: Signal<CriticalSectionRawMutex, Instant> = Signal::new();
+static IMU_READY: Signal<CriticalSectionRawMutex, (Instant, SensorConfig)> = Signal::new();
+/// Offsets measured at the start of the running recording, goes into the header on closing
+static CALIBRATION: Signal<CriticalSectionRawMutex, Calibration> = Signal::new();
 
 static TOGGLE_RECORDING: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
+/// Last UTC time received over serial
+static UTC_ANCHOR: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Option<UtcAnchor>>> =
+    blocking_mutex::Mutex::new(Cell::new(None));
+
+/// What the IMU records, also written to the log header
+const IMU_CONFIG: <Imu as ImuDriver>::Config = ImuConfig {
+    odr: Odr::Hz1660,
+    accel: AccelScale::G2,
+    gyro: GyroScale::Dps250,
+    // Store the IMU's own timestamp with every sample
+    timestamps: FifoTimestamps::Fine,
+    // Die temperature with every sample, for temperature dependent gyro bias
+    temperature: true,
+};
+
+/// The rig is expected to stand still this long after pressing record, for the gyro bias
+const CALIBRATION_TIME: Duration = Duration::from_millis(500);
+
+/// Largest packed batch, a whole FIFO that didn't pack at all
+const PACKED_LEN: usize = compress::max_packed_len(lsm6ds3::FIFO_BUFSIZE, IMU_CONFIG.sample_len());
+const _: () = assert!(PACKED_LEN >= lsm6ds3::FIFO_BUFSIZE, "a raw FIFO readout must fit a block");
+
+/// A power cut loses at most the last 2 s of a recording from the file, `detrac recover` finds
+/// what made it to the card after that
+const FLUSH_POLICY: FlushPolicy = FlushPolicy::EveryMicros(2_000_000);
+
+/// Zeros written into each new segment before recording, so its clusters are listed and in one
+/// run even if the power goes. Samples pile up meanwhile, at 10 MHz SPI a few MiB is the most
+/// the buffer covers.
+const PREALLOCATE: Option<u32> = None;
+
+/// Segments of an hour or 1 GiB, far from FAT32's 4 GiB limit and not too much to lose to a bad
+/// card
+const ROLLOVER: Rollover = Rollover::new(1 << 30).with_max_duration(60 * 60 * 1_000_000);
+
+/// How the board sits in the camera, lets Gyroflow line up the IMU axes with the image
+const MOUNTING: Orientation = Orientation::IDENTITY;
+
 #[embassy_executor::task]
-async fn sample_task(power_led: Peri<'static, P0_26>, mut resources: ImuRessources) {
+async fn sample_task(power_led: Peri<'static, P0_26>, mut imu: Imu) {
     let mut led = Output::new(power_led, Level::High, OutputDrive::Standard);
 
     loop {
         // Start recording
         TOGGLE_RECORDING.wait().await;
-        let mut imu = Imu::init(resources).await;
-        IMU_READY.signal(Instant::now());
+        imu.init(IMU_CONFIG, &mut Delay).await.unwrap();
+        let info = imu.info();
+        let mut encoder = BlockEncoder::new(&info.sensor_config());
+        // Packed samples take about 40% less card bandwidth and pipe space
+        let mut packed = [0_u8; PACKED_LEN];
+        let mut last_readout = Instant::now();
+        let mut still = Some(StillEstimator::new(&info));
+        let calibrated_at = last_readout + CALIBRATION_TIME;
+        CALIBRATION.reset();
+        IMU_READY.signal((last_readout, info.sensor_config()));
         info!("Started sampling");
-        resources = loop {
-            imu.data_interrupt().await;
+        loop {
+            imu.wait_data_ready().await.unwrap();
 
             led.set_low();
 
-            imu.read_samples(&SAMPLES).await;
+            let captured = Instant::now();
+            let (block, samples) = match imu.read_batch().await {
+                Ok(batch) => {
+                    if let Some(estimator) = &mut still {
+                        batch.samples().for_each(|sample| estimator.add(&sample));
+                        if captured >= calibrated_at {
+                            match estimator.calibration() {
+                                Some(calibration) => CALIBRATION.signal(calibration),
+                                None => warn!("Moved during the first {} ms, recording stays uncalibrated", CALIBRATION_TIME.as_millis()),
+                            }
+                            still = None;
+                        }
+                    }
+                    encoder.pack(captured.as_micros(), batch.as_bytes(), &mut packed)
+                }
+                Err(imu_common::Error::Overrun) => {
+                    // Everything since the last readout went down with the FIFO
+                    let lost = info.samples_in((captured - last_readout).as_micros());
+                    warn!("IMU FIFO overrun, about {} samples lost", lost);
+                    (encoder.overrun(captured.as_micros(), lost), &[][..])
+                }
+                Err(e) => panic!("IMU readout failed: {:?}", e),
+            };
+            last_readout = captured;
+            // Never stall the IMU on a slow card, a dropped block shows up as a sequence gap
+            if SAMPLES.free_capacity() >= block.as_bytes().len() + samples.len() {
+                SAMPLES.write_all(block.as_bytes()).await;
+                SAMPLES.write_all(samples).await;
+            } else {
+                warn!("Sample pipe full, dropped block {}", block.sequence());
+            }
 
             led.set_high();
             if TOGGLE_RECORDING.signaled() {
                 TOGGLE_RECORDING.wait().await;
                 info!("Completed sampling");
                 COMPLETE.signal(());
-                break imu.poweroff();
+                imu.power_off().await.unwrap();
+                break;
             }
-        };
+        }
     }
 }
 
-struct DummyClock;
+/// Listens for NMEA `ZDA`/`RMC` sentences, either wired to the TX of a GPS module or typed in
+/// from a host. Without PPS this is good to a few 100ms, enough to match footage by time of day.
+#[embassy_executor::task]
+async fn do_time_sync(mut rx: UarteRx<'static>) {
+    let mut line: String<96> = String::new();
+    // The time in a sentence refers to when it started being sent
+    let mut sentence_start = Instant::now();
+
+    loop {
+        let mut byte = [0u8; 1];
+        if let Err(e) = rx.read(&mut byte).await {
+            warn!("Time sync UART error: {:?}", e);
+            line.clear();
+            continue;
+        }
 
-impl TimeSource for DummyClock {
-    fn get_timestamp(&self) -> Timestamp {
-        // Returns a dummy date: Jan 1, 2024, 00:00:00
-        Timestamp::from_calendar(2026, 3, 8, 0, 0, 0).unwrap()
+        match byte[0] {
+            b'$' => {
+                sentence_start = Instant::now();
+                line.clear();
+                let _ = line.push('$');
+            }
+            b'\n' => {
+                if let Some(utc) = parse_nmea_utc(line.as_str()) {
+                    let anchor = UtcAnchor::new(utc, sentence_start.as_micros());
+                    if UTC_ANCHOR.lock(|a| a.replace(Some(anchor))).is_none() {
+                        info!("Got UTC time");
+                    }
+                }
+                line.clear();
+            }
+            b => {
+                // Overlong lines are garbage anyway and fail to parse
+                let _ = line.push(b as char);
+            }
+        }
     }
 }
 
 #[embassy_executor::task]
 async fn do_sd_card(
     mut spi_device: ExclusiveDevice<Spim<'static>, Output<'static>, Delay>,
-    rng: Mutex<CriticalSectionRawMutex, Rng<'static, Async>>
 ) {
     loop {
-        IMU_READY.wait().await;
-        let sdcard = SdCard::new(&mut spi_device, Delay);
-
-        let s = sdcard.num_bytes().unwrap();
-        info!("SD card is {} bytes", s);
-
-        let volume_mgr = VolumeManager::new(sdcard, DummyClock);
-        let volume0 = volume_mgr.open_volume(VolumeIdx(0)).unwrap();
-        let root_dir = volume0.open_root_dir().unwrap();
-
-        let random_fname = rng.lock().await.blocking_next_u32();
-        let mut my_file = root_dir
-            .open_file_in_dir(format!(20; "LOG-{}.CSV", random_fname as u8).unwrap().as_str(), embedded_sdmmc::Mode::ReadWriteCreateOrTruncate)
-            .unwrap();
-
+        let (started, sensor) = IMU_READY.wait().await;
+        // File times follow the last UTC time received
+        let clock = UtcClock::new(|| UTC_ANCHOR.lock(|a| a.get()), || Instant::now().as_micros());
+        let storage = match Storage::mount(SdCard::new(&mut spi_device, Delay), clock, &mut Delay).await {
+            Ok(storage) => storage,
+            Err(e) => {
+                warn!("SD card unusable, recording is lost: {}", Debug2Format(&e));
+                discard_recording().await;
+                continue;
+            }
+        };
+        info!("SD card is {} bytes", storage.card_size());
+
+        let anchor = UTC_ANCHOR.lock(|a| a.get());
+        // Into a folder for the day when the time is known, the whole recording stays there
+        let date = anchor.and_then(|a| a.utc_at(started.as_micros())).map(|utc| utc.date());
+        let (session, mut my_file) = match storage.create_log(date, FLUSH_POLICY) {
+            Ok((name, file)) => {
+                info!("Recording to {}", Display2Format(&name));
+                (name.index, file)
+            }
+            Err(e) => {
+                warn!("Can't create a log, recording is lost: {}", Debug2Format(&e));
+                discard_recording().await;
+                continue;
+            }
+        };
 
-        my_file.write(get_header_string().as_bytes()).unwrap(); // TODO: Replace with binary header
-        let mut total = 0;
+        let mut header = BinGyroHeader::new(sensor)
+            .with_orientation(MOUNTING)
+            .with_compression(Compression::Delta)
+            .with_segment(session, 0);
+        match anchor {
+            Some(anchor) => {
+                let started = started.as_micros();
+                header = header.with_gps_start(anchor.unix_micros_at(started) as u64, started);
+            }
+            None => warn!("No UTC time received, log start time unknown"),
+        }
+        let mut result = preallocate(&mut my_file).and_then(|_| my_file.write(header.as_bytes()));
+        let mut calibration = None;
+        let mut segment_started = started.as_micros();
 
-        let mut data = [0_u8;512];
-        loop {
+        let mut block = [0_u8; BlockHeader::LEN];
+        let mut payload = [0_u8; PACKED_LEN];
+        while result.is_ok() {
             if COMPLETE.signaled() && SAMPLES.is_empty() {
                 COMPLETE.wait().await;
                 break
             }
-            let read = SAMPLES.read(&mut data).await;
-            total += read;
-            my_file.write(&data[..read]).unwrap();
+            // Whole blocks only, so every segment can be read on its own
+            receive(&mut block).await;
+            let block_header = BlockHeader::read_from_bytes(&block).unwrap();
+            let data = &mut payload[..block_header.payload_len()];
+            receive(data).await;
+
+            let elapsed = block_header.timestamp().saturating_sub(segment_started);
+            if ROLLOVER.is_due(my_file.len(), block.len() + data.len(), elapsed) {
+                let next = match storage.create_log(date, FLUSH_POLICY) {
+                    Ok((name, file)) => {
+                        info!("Recording goes on in {}", Display2Format(&name));
+                        file
+                    }
+                    Err(e) => {
+                        warn!("Can't create the next segment, recording ends here: {}", Debug2Format(&e));
+                        discard_recording().await;
+                        break;
+                    }
+                };
+                calibration = calibration.or_else(|| CALIBRATION.try_take());
+                finish_segment(&mut my_file, &header.clone().with_next_segment(), calibration.as_ref());
+                if let Err(e) = mem::replace(&mut my_file, next).close() {
+                    warn!("Closing the segment failed: {}", Debug2Format(&e));
+                }
+                header = header.with_segment(session, header.segment() + 1);
+                segment_started = block_header.timestamp();
+                result = preallocate(&mut my_file).and_then(|_| my_file.write(header.as_bytes()));
+            }
+            result = result
+                .and_then(|_| my_file.write(&block))
+                .and_then(|_| my_file.write(data))
+                .and_then(|_| my_file.tick(block_header.timestamp()));
+        }
+        if let Err(e) = result {
+            warn!("SD write failed, recording ends here: {}", Debug2Format(&e));
+            discard_recording().await;
         }
 
-        info!("{}", total);
-        my_file.flush().unwrap();
-        my_file.close().unwrap();
-        root_dir.close().unwrap();
+        info!("{}", my_file.len());
+        calibration = calibration.or_else(|| CALIBRATION.try_take());
+        finish_segment(&mut my_file, &header, calibration.as_ref());
+        let closed = my_file.close();
+        if let Err(e) = closed.and(storage.unmount().map(|_| ())) {
+            warn!("Closing the log failed: {}", Debug2Format(&e));
+        }
         info!("Completed writing");
     }
 }
+
+/// Reserves [`PREALLOCATE`] bytes in a new segment
+fn preallocate<D: BlockDevice, T: TimeSource>(
+    file: &mut LogFile<'_, D, T>,
+) -> Result<(), sd_storage::Error<D::Error>> {
+    match PREALLOCATE {
+        Some(len) => file.preallocate(len),
+        None => Ok(()),
+    }
+}
+
+/// Fills in what is only known by the end of a segment. The offsets are measured a moment into
+/// the recording, long after the first header went out.
+fn finish_segment<D: BlockDevice, T: TimeSource>(
+    file: &mut LogFile<'_, D, T>,
+    header: &BinGyroHeader,
+    calibration: Option<&Calibration>,
+) {
+    if calibration.is_none() && !header.continues() {
+        return;
+    }
+    let header = match calibration {
+        Some(calibration) => header.clone().with_calibration(calibration),
+        None => header.clone(),
+    };
+    if let Err(e) = file.overwrite(0, header.as_bytes()) {
+        warn!("Header update failed, offsets or continuation not recorded: {}", Debug2Format(&e));
+    }
+}
+
+/// Reads exactly enough samples to fill `buf`
+async fn receive(buf: &mut [u8]) {
+    let mut read = 0;
+    while read < buf.len() {
+        read += SAMPLES.read(&mut buf[read..]).await;
+    }
+}
+
+/// Keeps the sample pipe moving until the recording stops, when there is nowhere to write to
+async fn discard_recording() {
+    let mut data = [0_u8; BLOCK_LEN];
+    loop {
+        if COMPLETE.signaled() && SAMPLES.is_empty() {
+            COMPLETE.wait().await;
+            break
+        }
+        SAMPLES.read(&mut data).await;
+    }
+}
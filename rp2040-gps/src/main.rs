@@ -4,6 +4,8 @@
 use embedded_hal_bus::spi::ExclusiveDevice;
 use traccam_common::display::draw_status_display;
 use traccam_common::{DisplayState};
+use traccam_common::sd_storage::Storage;
+use traccam_common::utc::{UtcAnchor, UtcClock};
 use embedded_graphics::prelude::{DrawTarget};
 use embedded_graphics::pixelcolor::BinaryColor;
 use ssd1306::{I2CDisplayInterface, Ssd1306};
@@ -22,14 +24,16 @@ use embassy_rp::peripherals::{DMA_CH0, DMA_CH1, DMA_CH2, I2C1, PIN_2, PIN_3, PIN
 use embassy_rp::spi::Spi;
 use embassy_rp::uart::{BufferedUartRx, BufferedInterruptHandler};
 use embassy_rp::uart;
+use embassy_sync::blocking_mutex;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_time::{Instant, Timer};
 use heapless::{String};
 use nmea::{parse_nmea_sentence, Nmea, SentenceType};
 use embassy_sync::signal::Signal;
-use embedded_sdmmc::{Mode, SdCard, TimeSource, Timestamp, VolumeIdx, VolumeManager};
+use embedded_sdmmc::SdCard;
 use embassy_time::Delay;
 use nmea::sentences::{parse_zda, ZdaData};
+use core::cell::Cell;
 
 bind_interrupts!(struct Irqs {
     UART0_IRQ => BufferedInterruptHandler<UART0>;
@@ -99,6 +103,14 @@ async fn do_nmea_decode(mut uart: BufferedUartRx) {
                             state.local_time = Some(zda.local_date_time().unwrap());
                         }
 
+                        // Only these carry the time of the sentence itself
+                        if matches!(mtype, SentenceType::RMC | SentenceType::ZDA)
+                            && let Some(utc) = state.utc()
+                        {
+                            let anchor = UtcAnchor::new(utc, Instant::now().as_micros());
+                            UTC_ANCHOR.lock(|a| a.set(Some(anchor)));
+                        }
+
                         DISPLAY_SIGNAL.signal(state);
                     }
                     buffer.clear();
@@ -113,14 +125,9 @@ async fn do_nmea_decode(mut uart: BufferedUartRx) {
     }
 }
 
-struct DummyClock;
-
-impl TimeSource for DummyClock {
-    fn get_timestamp(&self) -> Timestamp {
-        // Returns a dummy date: Jan 1, 2024, 00:00:00
-        Timestamp::from_calendar(2024, 1, 1, 0, 0, 0).unwrap()
-    }
-}
+/// Last GPS UTC time and when it was received
+static UTC_ANCHOR: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Option<UtcAnchor>>> =
+    blocking_mutex::Mutex::new(Cell::new(None));
 
 #[embassy_executor::task]
 async fn do_sd_card(
@@ -150,39 +157,20 @@ async fn do_sd_card(
     let spi_device = ExclusiveDevice::new(spi_bus, cs, Delay).unwrap();
 
 
-    let mut sdcard = SdCard::new(spi_device, Delay);
-
-    let s = sdcard.num_bytes().unwrap();
-    info!("SD card is {} bytes", s);
-
-    let mut volume_mgr = VolumeManager::new(sdcard, DummyClock);
-    let mut volume0 = volume_mgr.open_volume(VolumeIdx(0)).unwrap();
-    let mut root_dir = volume0.open_root_dir().unwrap();
-
-    let mut my_file = root_dir.open_file_in_dir("TEST.TXT", Mode::ReadWriteTruncate).unwrap();
-    // let before_size = my_file.length();
-
-    // for _ in 0..10 {
-    //     let start = Instant::now();
-    //     my_file.write(&[b'a'; 8096]).unwrap();
-    //     info!("{}", start.elapsed().as_millis());
-    //     Timer::after_secs(1).await;
-    // }
-    // info!("Before:{} After:{} Correct:{}", before_size, my_file.length(), before_size + 10 * 8096 == my_file.length());
-    // let start = Instant::now();
-    // let mut sum = 0;
-    // while !my_file.is_eof() {
-    //     let mut buffer = [0u8; 32];
-    //     let num_read = my_file.read(&mut buffer).unwrap();
-    //
-    //     // Convert raw bytes to a UTF-8 string so defmt can print it to the terminal
-    //     let text_chunk = core::str::from_utf8(&buffer[..num_read]).unwrap();
-    //     sum += text_chunk.len();
-    // }
-    // info!("{} sum: {}", start.elapsed().as_millis(), sum);
-
-    my_file.close().unwrap();
-    root_dir.close().unwrap();
+    // File times follow the GPS
+    let clock = UtcClock::new(|| UTC_ANCHOR.lock(|a| a.get()), || Instant::now().as_micros());
+    let storage = match Storage::mount(SdCard::new(spi_device, Delay), clock, &mut Delay).await {
+        Ok(storage) => storage,
+        Err(e) => {
+            error!("SD card unusable: {}", Debug2Format(&e));
+            return;
+        }
+    };
+    info!("SD card is {} bytes", storage.card_size());
+
+    if let Err(e) = storage.unmount() {
+        error!("SD card unmount failed: {}", Debug2Format(&e));
+    }
 }
 
 static DISPLAY_SIGNAL: Signal<CriticalSectionRawMutex, DisplayState> = Signal::new();